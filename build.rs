@@ -0,0 +1,55 @@
+// Gravishot
+// Copyright (C) 2024 Tomáš Pecl
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+// Computes GRAVISHOT_BUILD_HASH from the source code and the assets.
+// Clients and Servers with different hashes refuse to play together because their
+// network messages or maps could be different.
+
+use std::path::{Path, PathBuf};
+
+/// FNV-1a, it has to give the same result on every platform and compiler version
+struct Fnv(u64);
+impl Fnv {
+    fn write(&mut self, bytes: &[u8]) {
+        for &b in bytes {
+            self.0 ^= b as u64;
+            self.0 = self.0.wrapping_mul(0x100000001b3);
+        }
+    }
+}
+
+fn collect_files(dir: &Path, files: &mut Vec<PathBuf>) {
+    let Ok(entries) = std::fs::read_dir(dir) else{return};
+    for entry in entries.flatten() {
+        let path = entry.path();
+        if path.is_dir() {
+            collect_files(&path, files);
+        }else{
+            files.push(path);
+        }
+    }
+}
+
+fn main() {
+    let mut files = Vec::new();
+    for dir in ["src", "assets"] {
+        println!("cargo:rerun-if-changed={dir}");
+        collect_files(Path::new(dir), &mut files);
+    }
+    files.sort();
+
+    let mut hash = Fnv(0xcbf29ce484222325);
+    hash.write(env!("CARGO_PKG_VERSION").as_bytes());
+    for file in files {
+        //use / on every platform
+        let name = file.components().map(|c| c.as_os_str().to_string_lossy().into_owned()).collect::<Vec<_>>().join("/");
+        hash.write(name.as_bytes());
+        //git can change line endings on checkout, they should not change the hash
+        let data = std::fs::read(&file).unwrap_or_default();
+        hash.write(&data.into_iter().filter(|&b| b != b'\r').collect::<Vec<_>>());
+    }
+
+    println!("cargo:rustc-env=GRAVISHOT_BUILD_HASH={:016x}", hash.0);
+}
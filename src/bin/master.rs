@@ -0,0 +1,92 @@
+// Gravishot
+// Copyright (C) 2024 Tomáš Pecl
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+// Master server, keeps the list of running game Servers and sends it to Clients.
+// See src/master_protocol.rs for the messages.
+//
+//     cargo run --bin master -- --port 12347
+
+#[path = "../master_protocol.rs"]
+#[allow(dead_code)]     //MasterMessage::list is used only by the game
+mod master_protocol;
+
+use master_protocol::{MasterMessage, ServerInfo, DEFAULT_MASTER_PORT, HEARTBEAT_INTERVAL};
+
+use clap::Parser;
+
+use std::collections::BTreeMap;
+use std::net::{UdpSocket, SocketAddr, Ipv4Addr};
+use std::time::{Duration, Instant};
+
+/// Servers which did not send a heartbeat for this long are forgotten
+const SERVER_TIMEOUT: Duration = Duration::from_secs((3.0 * HEARTBEAT_INTERVAL) as u64);
+/// Protection against running out of memory, heartbeats of new Servers are ignored above this
+const MAX_SERVERS: usize = 1000;
+
+#[derive(Parser, Debug)]
+#[command(version, about = "GraviShot master server - list of running game servers")]
+struct Args {
+    /// Port the master server listens on
+    #[arg(long, default_value_t = DEFAULT_MASTER_PORT)]
+    port: u16,
+}
+
+fn main() {
+    let args = Args::parse();
+
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, args.port)).unwrap_or_else(|e| panic!("could not listen on port {}: {e}", args.port));
+    socket.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+    println!("master server listening on port {}", args.port);
+
+    //game address -> info and the time of the last heartbeat, ordered by address for the pages of List
+    let mut servers: BTreeMap<SocketAddr, (ServerInfo, Instant)> = BTreeMap::new();
+    let mut buf = [0; 2048];
+
+    loop {
+        let received = socket.recv_from(&mut buf);
+
+        let now = Instant::now();
+        servers.retain(|addr, (info, seen)| {
+            let alive = now.duration_since(*seen) < SERVER_TIMEOUT;
+            if !alive {
+                println!("server {} at {addr} timed out", info.name);
+            }
+            alive
+        });
+
+        let Ok((len, from)) = received else{continue};
+        match MasterMessage::decode(&buf[..len]) {
+            Some(MasterMessage::Heartbeat(info)) => {
+                let addr = SocketAddr::new(from.ip(), info.port);
+                if !servers.contains_key(&addr) {
+                    if servers.len() >= MAX_SERVERS {
+                        continue
+                    }
+                    println!("server {} registered at {addr}", info.name);
+                }
+                servers.insert(addr, (info, now));
+            },
+            Some(MasterMessage::List(after)) => {
+                //the answer is never larger than the request, the sender address of UDP can be spoofed
+                let mut page = Vec::new();
+                let mut answer = MasterMessage::Servers(Vec::new()).encode();
+                for (&addr, (info, _)) in servers.iter().filter(|(&addr, _)| after.map_or(true, |after| addr > after)) {
+                    page.push((addr, info.clone()));
+                    let encoded = MasterMessage::Servers(page.clone()).encode();
+                    if encoded.len() > len {
+                        page.pop();
+                        //a Server with a too long info is left out so the pages after it are not lost
+                        if page.is_empty() {continue}else{break}
+                    }
+                    answer = encoded;
+                }
+                if answer.len() <= len {
+                    let _ = socket.send_to(&answer, from);
+                }
+            },
+            Some(MasterMessage::Servers(..)) | None => (),
+        }
+    }
+}
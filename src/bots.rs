@@ -0,0 +1,285 @@
+// Gravishot
+// Copyright (C) 2024 Tomáš Pecl
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+// Bots for load testing a Server. One process runs many of them, each has its own connection.
+//
+// Bots do not simulate the game, they only talk the protocol like a real Client:
+// synchronize the clock with Pings -> Connect -> every frame send random Inputs -> acknowledge summaries.
+// The summaries tell them if their player is alive, dead bots spawn again after RESPAWN_DELAY.
+//
+//     gravishot --connect 1.2.3.4:1234 --bots 32
+
+use crate::networking::{ClientMessage, ServerMessage, ConnectRequest, EntityType};
+use crate::networking::clock::{ClockSync, TARGET_LEAD, SETUP_PING_INTERVAL, RUNNING_PING_INTERVAL};
+use crate::networking::delta::ReceivedSummaries;
+use crate::input::{Input, Buttons, UnackedInputs, ShootSignal, PlayerSpawnSignal};
+use crate::player::Player;
+
+use bevy_gravirollback::prelude::*;
+
+use bevy::prelude::*;
+use bevy_quinnet::client::{QuinnetClient, QuinnetClientPlugin};
+use bevy_quinnet::client::certificate::CertificateVerificationMode;
+use bevy_quinnet::client::connection::{ClientEndpointConfiguration, ConnectionLocalId, ConnectionLostEvent};
+use bevy_quinnet::shared::channels::{ChannelType, ChannelsConfiguration};
+
+use rand::{Rng, SeedableRng};
+use rand::rngs::StdRng;
+
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::time::Duration;
+
+/// Seconds a dead bot waits before it spawns again
+const RESPAWN_DELAY: f32 = 5.0;
+/// Probability that a bot shoots in a frame
+const SHOOT_PROBABILITY: f64 = 0.02;
+/// Range of seconds for which a bot keeps pressing the same buttons
+const BUTTONS_HOLD: std::ops::Range<f32> = 0.5..2.0;
+/// Maximal mouse movement of a bot in one frame
+const MAX_MOUSE_DELTA: i16 = 300;
+
+/// Runs the bots until all of them are disconnected
+pub fn run(args: crate::cli::Args) -> AppExit {
+    let count = args.bots.unwrap_or(1);
+    let addr = args.connect.clone().expect("bots need --connect");
+    let name = args.name.clone().unwrap_or("Bot".to_string());
+    let seed = args.bot_seed.unwrap_or_else(rand::random);
+    let addr = match addr.to_socket_addrs().map(|mut addrs| addrs.next()) {
+        Ok(Some(addr)) => addr,
+        Ok(None) => {
+            eprintln!("unknown server address {addr}");
+            return AppExit::error()
+        },
+        Err(e) => {
+            eprintln!("unknown server address {addr}: {e}");
+            return AppExit::error()
+        },
+    };
+    println!("starting {count} bots connecting to {addr}, seed {seed}");
+
+    App::new()
+    .add_plugins((
+        MinimalPlugins.set(bevy::app::ScheduleRunnerPlugin::run_loop(Duration::from_secs_f64(1.0 / 60.0))),
+        QuinnetClientPlugin::default(),
+    ))
+    .insert_resource(BotConfig { count, addr, name })
+    .insert_resource(BotRng(StdRng::seed_from_u64(seed)))
+    .init_resource::<Bots>()
+    .add_systems(Startup, connect_bots)
+    .add_systems(Update, (lost_connections, run_bots).chain())
+    .run()
+}
+
+#[derive(Resource)]
+pub struct BotConfig {
+    pub count: usize,
+    /// Resolved address of the Server
+    pub addr: SocketAddr,
+    /// Bots are named like this followed by their number
+    pub name: String,
+}
+
+/// All bots share one generator so a seed reproduces the whole run
+#[derive(Resource)]
+pub struct BotRng(StdRng);
+
+pub enum BotState {
+    /// Exchanging Pings to learn the Server clock
+    Syncing,
+    /// Connect was sent
+    Joining,
+    Playing {
+        player: Player,
+        /// Next RollbackID for spawned entities
+        next_id: u64,
+        frame_0_time: Duration,
+        /// The last frame whose Input was sent
+        last_frame: u64,
+        alive: bool,
+        /// Real time of the last spawn signal
+        spawned: f32,
+    },
+    Disconnected,
+}
+
+pub struct Bot {
+    pub connection: ConnectionLocalId,
+    pub name: String,
+    pub state: BotState,
+    clock: ClockSync,
+    summaries: ReceivedSummaries,
+    unacked: UnackedInputs,
+    /// Real time when the next Ping is sent
+    next_ping: f32,
+    buttons: Buttons,
+    /// Real time when the bot presses different buttons
+    next_buttons: f32,
+}
+
+#[derive(Resource, Default)]
+pub struct Bots(pub Vec<Bot>);
+
+fn connect_bots(
+    mut client: ResMut<QuinnetClient>,
+    mut bots: ResMut<Bots>,
+    config: Res<BotConfig>,
+) {
+    for i in 1..=config.count {
+        let connection = client.open_connection(
+            ClientEndpointConfiguration::from_addrs(config.addr, str::parse("0.0.0.0:0").unwrap()),
+            //bots are only used against our own Servers
+            CertificateVerificationMode::SkipVerification,
+            ChannelsConfiguration::from_types(vec![ChannelType::OrderedReliable, ChannelType::UnorderedReliable, ChannelType::Unreliable]).unwrap(),
+        ).unwrap();
+        bots.0.push(Bot {
+            connection,
+            name: format!("{} {i}", config.name),
+            state: BotState::Syncing,
+            clock: ClockSync::default(),
+            summaries: ReceivedSummaries::default(),
+            unacked: UnackedInputs::default(),
+            next_ping: 0.0,
+            buttons: Buttons::none(),
+            next_buttons: 0.0,
+        });
+    }
+}
+
+fn lost_connections(
+    mut events: EventReader<ConnectionLostEvent>,
+    mut bots: ResMut<Bots>,
+) {
+    for event in events.read() {
+        if let Some(bot) = bots.0.iter_mut().find(|bot| bot.connection == event.id) {
+            println!("{} lost connection", bot.name);
+            bot.state = BotState::Disconnected;
+        }
+    }
+}
+
+fn run_bots(
+    mut client: ResMut<QuinnetClient>,
+    mut bots: ResMut<Bots>,
+    mut rng: ResMut<BotRng>,
+    time: Res<Time<Real>>,
+    mut exit: EventWriter<AppExit>,
+) {
+    let now = time.elapsed_secs();
+    let rng = &mut rng.0;
+
+    for bot in bots.0.iter_mut() {
+        let Some(connection) = client.get_connection_mut_by_id(bot.connection) else{continue};
+
+        while let Some((_channel_id, msg)) = connection.try_receive_message::<ServerMessage>() {
+            match msg {
+                ServerMessage::Pong(client_time, server_time) => bot.clock.pong(client_time, server_time),
+                ServerMessage::ConnectionGranted(player, _token, first_id, _map, position_bounds, states) => {
+                    //bots share the process with each other, they all join the same Server
+                    crate::networking::wire::set_position_bounds(position_bounds);
+                    println!("{} joined as player {}", bot.name, player.0);
+                    bot.state = BotState::Playing {
+                        player,
+                        next_id: first_id.0,
+                        frame_0_time: states.frame_0_time,
+                        last_frame: states.last_frame.0,
+                        alive: false,
+                        spawned: f32::NEG_INFINITY,
+                    };
+                },
+                ServerMessage::ConnectionRejected(reason) | ServerMessage::Kicked(reason) => {
+                    println!("{} disconnected: {reason}", bot.name);
+                    bot.state = BotState::Disconnected;
+                },
+                ServerMessage::InputAck(frame) => bot.unacked.ack(frame),
+                ServerMessage::StateSummary(frame, baseline, delta) => {
+                    let base = match baseline {
+                        Some(baseline) => match bot.summaries.get(baseline.0) {
+                            Some(base) => Some(base),
+                            None => continue,
+                        },
+                        None => None,
+                    };
+                    let Ok(snapshot) = delta.apply(base) else{continue};
+                    if let BotState::Playing { player, alive, .. } = &mut bot.state {
+                        *alive = snapshot.states.values().any(|state| state.2 == Some(*player) && state.3 == EntityType::Player && state.4.0);
+                    }
+                    bot.summaries.insert(frame.0, snapshot.states);
+                    connection.try_send_message(ClientMessage::SummaryAck(frame));
+                },
+                _ => (),
+            }
+        }
+
+        if !connection.is_connected() {
+            continue
+        }
+
+        let interval = if bot.clock.is_synced() {RUNNING_PING_INTERVAL}else{SETUP_PING_INTERVAL};
+        if now >= bot.next_ping {
+            bot.next_ping = now + interval;
+            connection.try_send_message(ClientMessage::Ping(crate::networking::clock::now(), bot.clock.rtt_ms as f32));
+        }
+
+        match &mut bot.state {
+            BotState::Syncing => {
+                if bot.clock.is_synced() {
+                    connection.try_send_message(ClientMessage::Connect(ConnectRequest::new(bot.name.clone())));
+                    bot.state = BotState::Joining;
+                }
+            },
+            BotState::Playing { next_id, frame_0_time, last_frame, alive, spawned, .. } => {
+                //the same frame the game Client would be in, a little bit ahead of the Server
+                let elapsed = bot.clock.server_time().saturating_sub(*frame_0_time).as_millis() as u64;
+                let frame = elapsed / crate::gravity::PHYSICS_TIMESTEP_MS + TARGET_LEAD as u64;
+                if frame <= *last_frame {
+                    continue
+                }
+
+                if now >= bot.next_buttons {
+                    bot.next_buttons = now + rng.gen_range(BUTTONS_HOLD);
+                    bot.buttons = Buttons::none();
+                    for button in [Buttons::W, Buttons::S, Buttons::A, Buttons::D, Buttons::Q, Buttons::E, Buttons::Space] {
+                        if rng.gen_bool(0.3) {
+                            bot.buttons.set(button);
+                        }
+                    }
+                }
+
+                for frame in (*last_frame + 1)..=frame {
+                    let mut input = Input {
+                        buttons: bot.buttons,
+                        ..default()
+                    };
+                    input.mouse.deltas.push((rng.gen_range(-MAX_MOUSE_DELTA..=MAX_MOUSE_DELTA), rng.gen_range(-MAX_MOUSE_DELTA..=MAX_MOUSE_DELTA)));
+                    if !*alive && now - *spawned > RESPAWN_DELAY {
+                        *spawned = now;
+                        input.signals.spawn = Some(PlayerSpawnSignal {
+                            body: RollbackID(*next_id),
+                            gun: RollbackID(*next_id + 1),
+                        });
+                        *next_id += 2;
+                    }else if *alive && rng.gen_bool(SHOOT_PROBABILITY) {
+                        input.signals.shoot = Some(ShootSignal {
+                            id: RollbackID(*next_id),
+                        });
+                        *next_id += 1;
+                    }
+                    bot.unacked.push(Frame(frame), input);
+                }
+                *last_frame = frame;
+
+                let first = bot.unacked.inputs[0].0;
+                let inputs = bot.unacked.inputs.iter().map(|(_, input)| input.clone()).collect();
+                connection.try_send_message_on(2, ClientMessage::Inputs(first, inputs));    //Unreliable
+            },
+            BotState::Joining | BotState::Disconnected => (),
+        }
+    }
+
+    if bots.0.iter().all(|bot| matches!(bot.state, BotState::Disconnected)) {
+        println!("all bots are disconnected");
+        exit.send(AppExit::Success);
+    }
+}
@@ -22,19 +22,24 @@ pub const BULLET_VELOCITY: f32 =
     //50.0;
     25.0;
 
-const RADIUS: f32 = 0.075;
+pub const RADIUS: f32 = 0.075;
 const MASS: f32 = 4.0/3.0*std::f32::consts::PI*RADIUS*RADIUS*RADIUS * BULLET_DENSITY;
 const ANGULAR_INERTIA: f32 = 2.0/5.0*MASS*RADIUS*RADIUS;
 
 #[derive(Component)]
 pub struct Bullet;
 
+/// The player who shot the bullet, it is the owner Player in the [`State`](crate::networking::rollback::State) of the bullet
+#[derive(Component, Clone, Copy)]
+pub struct Shooter(pub Player);
+
 #[derive(Reflect, Serialize, Deserialize, Clone)]
 pub struct SpawnBullet {
     pub rollback: RollbackID,
     pub transform: Transform,
     pub velocity: Velocity,
     pub index: Option<usize>,
+    pub shooter: Option<Player>,
 }
 
 pub fn spawn_bullet_system(
@@ -73,46 +78,25 @@ pub fn spawn_bullet_system(
             transform,
             velocity,
             index: None,
+            shooter: Some(*player),
         };
         commands.queue(spawn3(make_bullet(spawn)));
     }
 }
 
+/// Bullets disappear when they hit something, the damage is decided by the Server, see [`crate::networking::hits`]
 pub fn bullet_collision_system(
     mut collision_events: EventReader<CollisionEvent>,
-    mut bullets: Query<(Entity, &mut Exists, &Velocity), With<Bullet>>,
-    player_parts: Query<(&crate::player::Player, &crate::player::DamageCoeficient)>,
-    mut players: Query<(&crate::player::Player, &mut crate::player::Health), With<crate::player::Body>>,
+    mut bullets: Query<&mut Exists, With<Bullet>>,
 ) {
     for event in collision_events.read() {
         let CollisionEvent::Started(e1, e2, _flags) = event else{continue};
-        println!("collision event {e1:?} {e2:?}");
-
-        let (bullet, mut exists, velocity, player_e) =
-        if let Ok((bullet, exists, velocity)) = bullets.get_mut(*e1) {
-            (bullet, exists, velocity,e2)
-        }else if let Ok((bullet, exists, velocity)) = bullets.get_mut(*e2) {
-            (bullet, exists, velocity,e1)
-        }else{
-            println!("e1 and e2 was not bullet");
-            continue
-        };
-
-        exists.0 = false;
-        
-        let Ok((player,damage)) = player_parts.get(*player_e) else{
-            println!("collision with something else");
-            continue
-        };
-        let Some((player, mut health)) = players.iter_mut().find(|(p,_)| **p==*player) else{
-            panic!("player {player:?} part {player_e:?} does not have a body");
-        };
-
-        let velocity = velocity.linvel.length();    //TODO: instead use relative velocity with respect to collided player
-        let damage = damage.0*velocity;
-        health.0 -= damage;
-
-        println!("bullet {bullet:?} collision with player {player:?} part {player_e:?} : velocity {velocity} damage {damage} remaining health {}",health.0);
+        if let Ok(mut exists) = bullets.get_mut(*e1) {
+            exists.0 = false;
+        }
+        if let Ok(mut exists) = bullets.get_mut(*e2) {
+            exists.0 = false;
+        }
     }
 }
 
@@ -130,6 +114,7 @@ pub fn make_bullet(event: SpawnBullet) -> impl Fn(ResMut<Assets<Mesh>>, ResMut<A
     let rollback = event.rollback;
     let transform = event.transform;
     let velocity = event.velocity;
+    let shooter = event.shooter;
 
     move |mut mesh_assets, mut material_assets, mut commands| {
         //TODO: this is hacky
@@ -190,6 +175,9 @@ pub fn make_bullet(event: SpawnBullet) -> impl Fn(ResMut<Assets<Mesh>>, ResMut<A
                 MeshMaterial3d(material),
             ));
         }).id();
+        if let Some(shooter) = shooter {
+            commands.entity(id).insert(Shooter(shooter));
+        }
         println!("spawning bullet {id:?}");
         id
     }
@@ -0,0 +1,191 @@
+// Gravishot
+// Copyright (C) 2024 Tomáš Pecl
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use bevy::prelude::*;
+
+use clap::Parser;
+
+use std::path::PathBuf;
+
+pub const DEFAULT_PORT: u16 = 12345;
+
+/// Command line options, they allow skipping the main menu.
+/// Builds with the headless feature are always dedicated servers, they accept the server options without `--server`.
+///
+/// examples:
+///
+///     gravishot --server --port 1234 --map my.map
+///     gravishot --server --interest-distance 100 --line-of-sight
+///     gravishot --connect 1.2.3.4:1234 --name Foo
+///     gravishot --connect 1.2.3.4:1234 --spectate
+///     gravishot --connect 1.2.3.4:1234 --bots 32
+///     gravishot --connect 1.2.3.4:1234 --record match.replay
+///     gravishot --replay match.replay
+#[derive(Parser, Resource, Clone, Debug, Default)]
+#[command(version, about = "GraviShot - first person shooter in space with asteroids and gravity")]
+pub struct Args {
+    /// Start a dedicated server without a window and without the main menu
+    #[arg(long, conflicts_with = "connect")]
+    pub server: bool,
+    /// Port the server listens on
+    #[arg(long)]
+    #[cfg_attr(not(feature="headless"), arg(requires = "server"))]
+    pub port: Option<u16>,
+    /// Map file the server loads instead of generating a new map
+    #[arg(long)]
+    #[cfg_attr(not(feature="headless"), arg(requires = "server"))]
+    pub map: Option<PathBuf>,
+    /// Name of the server shown to players on the local network, also written in the certificate generated by the server
+    #[arg(long)]
+    #[cfg_attr(not(feature="headless"), arg(requires = "server"))]
+    pub hostname: Option<String>,
+    /// Certificate file of the server, it is created when it does not exist
+    #[arg(long, requires = "key")]
+    #[cfg_attr(not(feature="headless"), arg(requires = "server"))]
+    pub cert: Option<PathBuf>,
+    /// Private key file of the server certificate, it is created when it does not exist
+    #[arg(long, requires = "cert")]
+    #[cfg_attr(not(feature="headless"), arg(requires = "server"))]
+    pub key: Option<PathBuf>,
+    /// File with the bans of the server in RON format, bans.ron by default
+    #[arg(long)]
+    #[cfg_attr(not(feature="headless"), arg(requires = "server"))]
+    pub bans: Option<PathBuf>,
+    /// Seconds a disconnected player stays in the game waiting for a reconnect
+    #[arg(long)]
+    #[cfg_attr(not(feature="headless"), arg(requires = "server"))]
+    pub grace_period: Option<f32>,
+    /// Maximum number of players who are not spectating, unlimited by default
+    #[arg(long)]
+    #[cfg_attr(not(feature="headless"), arg(requires = "server"))]
+    pub max_players: Option<usize>,
+    /// Players get only the entities within this distance from them, the further ones rarely or not at all
+    #[arg(long, value_name = "METERS")]
+    #[cfg_attr(not(feature="headless"), arg(requires = "server"))]
+    pub interest_distance: Option<f32>,
+    /// Players do not get the enemies hidden behind asteroids
+    #[arg(long, requires = "interest_distance")]
+    pub line_of_sight: bool,
+    /// Master server at host:port, the server registers with it, the main menu lists the servers it knows
+    #[arg(long)]
+    pub master: Option<String>,
+    /// Simulated one way delay in milliseconds of the messages this process receives
+    #[arg(long, value_name = "MS")]
+    pub sim_delay: Option<f32>,
+    /// Simulated random change of the delay in milliseconds
+    #[arg(long, value_name = "MS")]
+    pub sim_jitter: Option<f32>,
+    /// Simulated probability of losing a message on the unreliable channel, 0 to 1
+    #[arg(long, value_name = "PROBABILITY")]
+    pub sim_loss: Option<f32>,
+    /// Simulated probability of reordering a message on an unordered channel, 0 to 1
+    #[arg(long, value_name = "PROBABILITY")]
+    pub sim_reorder: Option<f32>,
+    /// Connect directly to the server at ip:port
+    #[arg(long)]
+    pub connect: Option<String>,
+    /// Player name used when connecting
+    #[arg(long)]
+    pub name: Option<String>,
+    /// Join as a spectator
+    #[arg(long, requires = "connect")]
+    pub spectate: bool,
+    /// Instead of playing run this many bots without a window, they join the server and play randomly, for load testing
+    #[arg(long, requires = "connect", conflicts_with = "spectate")]
+    pub bots: Option<usize>,
+    /// Seed of the random generator of the bots, a random seed by default
+    #[arg(long, requires = "bots")]
+    pub bot_seed: Option<u64>,
+    /// Record the match into this replay file
+    #[arg(long, value_name = "FILE", conflicts_with = "replay")]
+    pub record: Option<PathBuf>,
+    /// Watch a recorded replay file instead of playing
+    #[arg(long, value_name = "FILE", conflicts_with_all = ["server", "connect"])]
+    pub replay: Option<PathBuf>,
+}
+
+/// What the game should do after loading assets
+pub enum LaunchMode {
+    MainMenu,
+    Server,
+    Client,
+    Replay,
+}
+
+impl Args {
+    /// Should the game run without a window
+    pub fn headless(&self) -> bool {
+        cfg!(feature="headless") || self.server
+    }
+
+    pub fn launch_mode(&self) -> LaunchMode {
+        if self.headless() {
+            LaunchMode::Server
+        }else if self.connect.is_some() {
+            LaunchMode::Client
+        }else if self.replay.is_some() {
+            LaunchMode::Replay
+        }else{
+            LaunchMode::MainMenu
+        }
+    }
+
+    /// Applies the networking related options onto [`NetConfig`](crate::networking::NetConfig)
+    pub fn apply(&self, config: &mut crate::networking::NetConfig) {
+        if let Some(addr) = &self.connect {
+            config.ip_port = addr.clone();
+        }
+        if self.headless() {
+            config.ip_port = format!("0.0.0.0:{}", self.port.unwrap_or(DEFAULT_PORT));
+        }
+        if let Some(name) = &self.name {
+            config.name = name.clone();
+        }
+        config.spectate = self.spectate;
+    }
+
+    /// Conditions of the simulated network, see [`crate::networking::netsim`]
+    pub fn network_conditions(&self) -> crate::networking::netsim::NetworkConditions {
+        crate::networking::netsim::NetworkConditions {
+            delay_ms: self.sim_delay.unwrap_or(0.0),
+            jitter_ms: self.sim_jitter.unwrap_or(0.0),
+            loss: self.sim_loss.unwrap_or(0.0),
+            reorder: self.sim_reorder.unwrap_or(0.0),
+        }
+    }
+
+    /// Which entities are sent to which players, see [`crate::networking::interest`]
+    pub fn interest(&self) -> crate::networking::interest::InterestConfig {
+        crate::networking::interest::InterestConfig {
+            distance: self.interest_distance,
+            line_of_sight: self.line_of_sight,
+        }
+    }
+
+    /// Master server settings, the server registers only when a master server is given
+    pub fn master(&self) -> crate::networking::master::MasterConfig {
+        let mut config = crate::networking::master::MasterConfig::default();
+        if let Some(addr) = &self.master {
+            config.addr = addr.clone();
+            config.register = true;
+        }
+        config
+    }
+
+    /// Certificate of the server, the default one with the given options replaced
+    pub fn certificate(&self) -> crate::networking::server::CertificateConfig {
+        let mut config = crate::networking::server::CertificateConfig::default();
+        if let Some(hostname) = &self.hostname {
+            config.hostname = hostname.clone();
+        }
+        if let Some(cert) = &self.cert {
+            config.cert_file = cert.clone();
+        }
+        if let Some(key) = &self.key {
+            config.key_file = key.clone();
+        }
+        config
+    }
+}
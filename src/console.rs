@@ -0,0 +1,284 @@
+// Gravishot
+// Copyright (C) 2024 Tomáš Pecl
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+// Commands typed into the terminal of a dedicated Server.
+// A thread reads lines from stdin and sends them over a channel, every frame the received lines
+// are parsed and queued as Commands so they run against the live world.
+
+use crate::networking::{ServerMessage, roster::{PlayerRoster, ConnectionState}};
+use crate::networking::admin::{AdminCommand, BanTarget, BanList, PendingKicks, KICK_DELAY};
+use crate::networking::spectator::PlayerLimit;
+use crate::networking::traffic::ServerSend;
+use crate::map::{Map, MapFile, asteroid::AsteroidAssets};
+use crate::player::Player;
+
+use bevy_gravirollback::prelude::*;
+
+use bevy::prelude::*;
+use bevy_quinnet::server::QuinnetServer;
+
+use rand::SeedableRng;
+
+use std::sync::Mutex;
+use std::sync::mpsc::{self, Receiver};
+use std::time::Duration;
+
+const HELP: &str = "\
+commands:
+    help                                  this list
+    status                                frame, uptime, number of players and spectators
+    players                               list of players
+    kick <id> [reason]                    kick a player
+    ban <id|name:NAME|ip:IP> [time] [reason]
+                                          ban a player, time is like 30m, 12h, 7d or perm (default)
+    unban <name:NAME|ip:IP>               remove a ban
+    map reload                            load the map file again
+    map generate <seed>                   generate a new map
+    say <text>                            send a chat message to everyone
+    quit                                  stop the server";
+
+pub struct ConsolePlugin;
+
+impl Plugin for ConsolePlugin {
+    fn build(&self, app: &mut App) {
+        app
+        .add_systems(Startup, start_reader)
+        .add_systems(Update, (
+            read_console.run_if(resource_exists::<ConsoleInput>),
+            stop.run_if(resource_exists::<Stopping>).after(crate::networking::admin::disconnect_kicked),
+        ));
+    }
+}
+
+/// Lines read from stdin
+#[derive(Resource)]
+pub struct ConsoleInput(Mutex<Receiver<String>>);
+
+fn start_reader(mut commands: Commands) {
+    let (sender, receiver) = mpsc::channel();
+    std::thread::spawn(move || {
+        for line in std::io::stdin().lines() {
+            let Ok(line) = line else{break};
+            if sender.send(line).is_err() {break}
+        }
+    });
+    commands.insert_resource(ConsoleInput(Mutex::new(receiver)));
+    println!("console ready, type help for the list of commands");
+}
+
+fn read_console(
+    mut commands: Commands,
+    input: Res<ConsoleInput>,
+) {
+    let receiver = input.0.lock().unwrap();
+    while let Ok(line) = receiver.try_recv() {
+        match ConsoleCommand::parse(&line) {
+            Ok(Some(command)) => commands.queue(move |world: &mut World| command.run(world)),
+            Ok(None) => (),
+            Err(e) => println!("{e}, type help for the list of commands"),
+        }
+    }
+}
+
+/// The Server was told to quit, it exits after the Clients had [`KICK_DELAY`] to read why they got disconnected
+#[derive(Resource)]
+struct Stopping(Timer);
+
+fn stop(
+    mut stopping: ResMut<Stopping>,
+    time: Res<Time<Real>>,
+    mut exit: EventWriter<AppExit>,
+) {
+    if stopping.0.tick(time.delta()).just_finished() {
+        exit.send(AppExit::Success);
+    }
+}
+
+#[derive(Debug)]
+pub enum ConsoleCommand {
+    Help,
+    Status,
+    Players,
+    /// Kick, ban and unban
+    Admin(AdminCommand),
+    MapReload,
+    MapGenerate(u64),
+    Say(String),
+    Quit,
+}
+
+impl ConsoleCommand {
+    /// Returns None for an empty line
+    pub fn parse(line: &str) -> Result<Option<ConsoleCommand>, String> {
+        let line = line.trim();
+        let (name, rest) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+        let rest = rest.trim();
+
+        let command = match name {
+            "" => return Ok(None),
+            "help" => ConsoleCommand::Help,
+            "status" => ConsoleCommand::Status,
+            "players" => ConsoleCommand::Players,
+            "kick" => {
+                let (id, reason) = rest.split_once(char::is_whitespace).unwrap_or((rest, ""));
+                ConsoleCommand::Admin(AdminCommand::Kick {
+                    player: parse_player(id)?,
+                    reason: default_reason(reason),
+                })
+            },
+            "ban" => {
+                let mut parts = rest.splitn(3, char::is_whitespace);
+                let target = parts.next().unwrap_or_default();
+                let (duration, reason) = match parts.next() {
+                    Some(time) => match parse_duration(time) {
+                        Ok(duration) => (duration, parts.next().unwrap_or_default().to_string()),
+                        //the time is optional, this is already the reason
+                        Err(_) if !time.starts_with(|c: char| c.is_ascii_digit()) => (None, rest.split_once(char::is_whitespace).map_or("", |x| x.1).to_string()),
+                        Err(e) => return Err(e),
+                    },
+                    None => (None, String::new()),
+                };
+                let reason = default_reason(&reason);
+                match parse_ban_target(target) {
+                    Ok(target) => ConsoleCommand::Admin(AdminCommand::BanTarget { target, duration, reason }),
+                    Err(_) => ConsoleCommand::Admin(AdminCommand::Ban { player: parse_player(target)?, duration, reason }),
+                }
+            },
+            "unban" => ConsoleCommand::Admin(AdminCommand::Unban(parse_ban_target(rest)?)),
+            "map" => {
+                let (sub, arg) = rest.split_once(char::is_whitespace).unwrap_or((rest, ""));
+                match sub {
+                    "reload" => ConsoleCommand::MapReload,
+                    "generate" => ConsoleCommand::MapGenerate(match arg.trim() {
+                        "" => rand::random(),
+                        seed => seed.parse().map_err(|_| format!("invalid seed {seed}"))?,
+                    }),
+                    _ => return Err("usage: map reload | map generate <seed>".to_string()),
+                }
+            },
+            "say" if !rest.is_empty() => ConsoleCommand::Say(rest.to_string()),
+            "say" => return Err("usage: say <text>".to_string()),
+            "quit" | "exit" => ConsoleCommand::Quit,
+            _ => return Err(format!("unknown command {name}")),
+        };
+        Ok(Some(command))
+    }
+
+    pub fn run(self, world: &mut World) {
+        match self {
+            ConsoleCommand::Help => println!("{HELP}"),
+            ConsoleCommand::Status => {
+                let last_frame = world.resource::<LastFrame>().0;
+                let frame_0_time = world.resource::<crate::gamestate::UpdateTimer>().frame_0_time;
+                let uptime = crate::networking::clock::now().saturating_sub(frame_0_time).as_secs();
+                let roster = world.resource::<PlayerRoster>();
+                let reconnecting = roster.0.values().filter(|info| info.state == ConnectionState::Reconnecting).count();
+                let connected = roster.0.len() - reconnecting;
+                let spectating = roster.0.len() - roster.playing();
+                let limit = match world.resource::<PlayerLimit>().0 {
+                    Some(max) => format!("at most {max} playing"),
+                    None => "no limit".to_string(),
+                };
+                println!("frame {last_frame}, uptime {}h {:02}m {:02}s", uptime / 3600, uptime / 60 % 60, uptime % 60);
+                println!("players: {connected} connected, {reconnecting} reconnecting, {spectating} spectating ({limit})");
+                println!("map: {} asteroids", world.resource::<Map>().asteroid_count());
+                println!("bans: {}", world.resource::<BanList>().bans.len());
+            },
+            ConsoleCommand::Players => {
+                let roster = world.resource::<PlayerRoster>();
+                let mut players: Vec<_> = roster.0.iter().collect();
+                players.sort_by_key(|(player, _)| player.0);
+                println!("{:>5}  {:<32}  {:>7}  state", "id", "name", "ping");
+                for (player, info) in players {
+                    let spectator = if info.spectator {", spectator"}else{""};
+                    println!("{:>5}  {:<32}  {:>4.0} ms  {:?}{spectator}", player.0, info.name, info.ping_ms, info.state);
+                }
+            },
+            ConsoleCommand::Admin(command) => {
+                world.send_event(command);
+            },
+            ConsoleCommand::MapReload => {
+                let Some(path) = world.get_resource::<MapFile>().map(|file| file.0.clone()) else{
+                    println!("there is no map file, start the server with --map to use one");
+                    return
+                };
+                let map = Map::load(&path).and_then(|map| map.validate(world.resource::<AsteroidAssets>()).map(|_| map));
+                match map {
+                    Ok(map) => {
+                        println!("reloaded map {}", path.display());
+                        world.insert_resource(map);
+                    },
+                    Err(e) => println!("{e}"),
+                }
+            },
+            ConsoleCommand::MapGenerate(seed) => {
+                let mut rng = rand::rngs::StdRng::seed_from_u64(seed);
+                let map = Map::generate(world.resource::<AsteroidAssets>(), &mut rng);
+                println!("generated map with seed {seed}");
+                world.insert_resource(map);
+            },
+            ConsoleCommand::Say(text) => {
+                println!("[server] {text}");
+                if let Some(mut server) = world.get_resource_mut::<QuinnetServer>() {
+                    server.endpoint_mut().broadcast(ServerMessage::Chat(None, text));
+                }
+            },
+            ConsoleCommand::Quit => {
+                if world.contains_resource::<Stopping>() {
+                    return
+                }
+                println!("stopping the server");
+                let clients = match world.get_resource_mut::<QuinnetServer>() {
+                    Some(mut server) => {
+                        let endpoint = server.endpoint_mut();
+                        endpoint.broadcast(ServerMessage::Kicked("the server was stopped".to_string()));
+                        endpoint.clients()
+                    },
+                    None => Vec::new(),
+                };
+                //they get disconnected properly before we exit
+                let mut kicks = world.resource_mut::<PendingKicks>();
+                for client_id in clients {
+                    kicks.add(client_id);
+                }
+                world.insert_resource(Stopping(Timer::from_seconds(KICK_DELAY, TimerMode::Once)));
+            },
+        }
+    }
+}
+
+fn default_reason(reason: &str) -> String {
+    match reason.trim() {
+        "" => "no reason given".to_string(),
+        reason => reason.to_string(),
+    }
+}
+
+fn parse_player(id: &str) -> Result<Player, String> {
+    id.parse().map(Player).map_err(|_| format!("invalid player id {id}, see players"))
+}
+
+fn parse_ban_target(target: &str) -> Result<BanTarget, String> {
+    if let Some(name) = target.strip_prefix("name:") {
+        Ok(BanTarget::Name(name.to_string()))
+    }else if let Some(ip) = target.strip_prefix("ip:") {
+        ip.parse().map(BanTarget::Ip).map_err(|_| format!("invalid address {ip}"))
+    }else{
+        Err(format!("invalid ban target {target}, use name:NAME or ip:IP"))
+    }
+}
+
+/// Parses times like 90s, 30m, 12h, 7d, perm means permanently (None)
+fn parse_duration(time: &str) -> Result<Option<Duration>, String> {
+    if time == "perm" {
+        return Ok(None)
+    }
+    let (number, unit_secs) = [('s', 1), ('m', 60), ('h', 60 * 60), ('d', 60 * 60 * 24)].into_iter()
+        .find_map(|(unit, secs)| time.strip_suffix(unit).map(|number| (number, secs)))
+        .ok_or_else(|| format!("invalid time {time}"))?;
+    let number: u64 = number.parse().map_err(|_| format!("invalid time {time}"))?;
+    let secs = number.checked_mul(unit_secs).ok_or_else(|| format!("time {time} is too long, use perm"))?;
+    Ok(Some(Duration::from_secs(secs)))
+}
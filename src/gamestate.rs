@@ -6,15 +6,21 @@
 mod mainmenu;
 mod spawn_menu;
 mod healthbar;
+mod replay;
+mod diagnostics;
+pub mod chat;
+pub mod spectator;
 
 use crate::{map, player, networking, input, gravity, bullet, physics};
+use crate::replay::is_replaying;
+use crate::cli::LaunchMode;
 
 use bevy_gravirollback::prelude::*;
 
 use bevy::prelude::*;
 use bevy_rapier3d::prelude::*;
 
-use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::time::Duration;
 
 //new idea for representing game state:
 //multiple levels of state: 1. level is GameState, other levels define other state when in certain GameState
@@ -34,6 +40,8 @@ pub enum GameState {
     ClientSetup,
     /// Loads/generates map. Changes to Running and adds Server to resources when complete.
     ServerSetup,
+    /// Loads the replay file. Changes to Running when complete.
+    ReplaySetup,
     /// Everything is loaded and simulation is running
     /// This runs both on server and client
     /// Client uses this to predict movement of objects to reduce trafic
@@ -55,7 +63,10 @@ pub enum HandleIO {
 }
 
 /// Registers systems specific to each [`GameState`] and other related state
-pub struct GameStatePlugin;
+pub struct GameStatePlugin {
+    /// Running without a window, only as a dedicated Server
+    pub headless: bool,
+}
 
 impl Plugin for GameStatePlugin {
     fn build(&self, app: &mut App) {
@@ -81,43 +92,68 @@ impl Plugin for GameStatePlugin {
         .add_systems(OnEnter(GameState::LoadingDone),after_load)
 
         //GameState::MainMenu
-        .add_systems(Update,mainmenu::ui.run_if(in_state(GameState::MainMenu)))
+        .add_systems(Update,(
+            mainmenu::ui,
+            networking::discovery::discover_servers,
+            networking::master::browse_servers,
+        ).run_if(in_state(GameState::MainMenu)))
+        .add_systems(OnExit(GameState::MainMenu),(networking::discovery::stop_discovery, networking::master::stop_browsing))
 
         //GameState::ClientSetup
         .add_systems(OnEnter(GameState::ClientSetup),networking::client::connect)
-        .add_systems(Update,networking::client::on_connect.run_if(in_state(GameState::ClientSetup)))
+        //these also run in GameState::Running when the Client reconnects
+        .add_systems(Update,(
+            networking::client::certificate_events,
+            networking::client::on_connect,
+            networking::client::request_connection,
+        ).chain().run_if(resource_exists::<networking::client::ClientMarker>))
         .add_systems(OnExit(GameState::ClientSetup),crate::setup)
 
         //GameState::ServerSetup
         .add_systems(OnEnter(GameState::ServerSetup),
             (
-                (map::generate_map,networking::server::start),
+                (map::setup_map,networking::admin::load_bans,networking::server::start,networking::discovery::start_responder),
                 change_state(GameState::Running),
             ).chain()
         )
         .add_systems(OnExit(GameState::ServerSetup),(crate::setup_server, crate::setup))
 
+        //GameState::ReplaySetup
+        .add_systems(OnEnter(GameState::ReplaySetup),crate::replay::start_playback)
+        .add_systems(OnExit(GameState::ReplaySetup),crate::setup)
+
+        .add_systems(OnExit(GameState::Running),(crate::replay::stop_recording, clear_world))
+
         .configure_sets(Update,
             (HandleIO::LocalInput, HandleIO::Networking, HandleIO::ProcessChanges).chain().in_set(RollbackProcessSet::HandleIO)
         );
 
         //GameState::Running and rollback schedules
-        if cfg!(not(feature="headless")) {
+        if !self.headless {
             app
+            .init_resource::<chat::ChatOverlay>()
+            .init_resource::<spectator::SpectatorView>()
+            .init_resource::<diagnostics::DiagnosticsOverlay>()
             .add_systems(Update,
                 (
                     player::player_control::change_player_control,
                     player::player_control::center_cursor.run_if(player::player_control::is_first_person),
 
                     (
-                        input::get_local_input.run_if(GAME_TICK_CONDITION),
+                        input::get_local_input.run_if(GAME_TICK_CONDITION).run_if(not(is_replaying)),
 
-                        spawn_menu::ui.run_if(not(player::local_player_exists)),
-                        healthbar::ui.run_if(player::local_player_exists),
+                        spawn_menu::ui.run_if(not(player::local_player_exists)).run_if(not(spectator::is_spectating)).run_if(not(is_replaying)),
+                        healthbar::ui.run_if(player::local_player_exists.or(spectator::is_spectating).or(is_replaying)),
+                        spectator::camera.run_if(spectator::is_spectating.or(is_replaying)),
+                        spectator::ui.run_if(spectator::is_spectating),
+                        spectator::remove_camera.run_if(not(spectator::is_spectating.or(is_replaying))),
+                        replay::ui.run_if(is_replaying),
+                        chat::ui.before(input::get_local_input),
+                        diagnostics::ui.after(chat::ui),
                     ).in_set(HandleIO::LocalInput),
 
                     (
-                        input::handle_local_input_event.run_if(GAME_TICK_CONDITION),
+                        input::handle_local_input_event.run_if(GAME_TICK_CONDITION).run_if(not(is_replaying)),
                         /*(
                             spawning::handle_local_spawn_event,
                             spawning::handle_request_spawn_event.run_if(resource_exists::<networking::server::ServerMarker>()),
@@ -138,24 +174,53 @@ impl Plugin for GameStatePlugin {
                 // Talks to all connected clients and syncs with them
                 (
                     networking::server::handle,
+                    networking::replication::replicate.after(networking::server::handle).before(networking::server::send_state_summary),
                     networking::server::send_state_summary,
+                    networking::roster::send_roster,
+                    networking::server::expire_sessions,
+                    networking::admin::handle_admin_commands,
+                    networking::admin::disconnect_kicked,
+                    networking::discovery::answer_probes.run_if(resource_exists::<networking::discovery::DiscoveryResponder>),
+                    networking::master::heartbeat.run_if(networking::master::registering),
+                    networking::checksum::compare_checksums,
                 ).run_if(resource_exists::<networking::server::ServerMarker>),
                 //when client exists    TODO: move to client.rs ? or networking.rs ?
                 // Talks to the connected server and syncs with it
-                networking::client::handle.run_if(resource_exists::<networking::client::ClientMarker>),
+                (
+                    networking::client::handle,
+                    networking::client::send_ping,
+                    networking::client::reconnect.run_if(in_state(GameState::Running)),
+                    networking::checksum::send_checksums.run_if(in_state(GameState::Running)),
+                ).run_if(resource_exists::<networking::client::ClientMarker>),
             ).in_set(HandleIO::Networking),
 
             map::load_from_map.run_if(in_state(GameState::Running)),
 
             (
                 (
-                    update_frame,
+                    update_frame.run_if(not(is_replaying)),
+                    crate::replay::advance.run_if(is_replaying).before(networking::rollback::handle_update_state_event),
                     input::handle_update_input_event,
                     //spawning::handle_update_spawn_event,
                     networking::rollback::handle_update_state_event,
+                    networking::rollback::handle_despawn_event.after(networking::rollback::handle_update_state_event),
                 ).in_set(HandleIO::ProcessChanges),
+                (
+                    networking::hits::register_hits
+                        .run_if(resource_exists::<networking::server::ServerMarker>)
+                        .before(crate::replay::record),
+                    crate::replay::record.run_if(resource_exists::<crate::replay::ReplayRecorder>),
+                    crate::replay::apply_events.run_if(is_replaying),
+                    networking::diagnostics::measure,
+                ).after(RollbackProcessSet::RunRollbackSchedule),
             ).run_if(in_state(GameState::Running)),
-        ));
+        ))
+        .add_systems(RollbackSave,
+            crate::replay::store_inputs
+                .after(clear_resource_input_default::<input::Inputs, { networking::rollback::LEN }>)
+                .after(clear_resource_input_default::<networking::hits::Hits, { networking::rollback::LEN }>)
+                .run_if(is_replaying)
+        );
 
         app
         .add_systems(RollbackUpdate,
@@ -164,7 +229,7 @@ impl Plugin for GameStatePlugin {
                     gravity::force_reset,
                     //spawning::handle_spawns,
                     player::spawn_player_system,
-                    player::health_system,
+                    (networking::hits::apply_hits, player::health_system).chain(),
                     (
                         player::gun::connect_joints,
                         player::gun::update_joints,
@@ -215,10 +280,17 @@ const GAME_TICK_CONDITION: for<'a> fn(Res<'a, WantedFrame>) -> bool = resource_c
 fn update_frame(
     timer: Res<UpdateTimer>,
     mut wanted: ResMut<WantedFrame>,
+    mut clock_adjustment: ResMut<networking::clock::ClockAdjustment>,
+    clock_sync: Res<networking::clock::ClockSync>,
+    time: Res<Time<Real>>,
 ) {
+    clock_adjustment.advance(time.delta());
+
+    //frame_0_time is measured by the Server clock, ClockSync gives us an estimate of it (on the Server it is just the local clock)
     let delay = timer.delay;
-    let frame0 = timer.frame_0_time;
-    let needed_frame = ((SystemTime::now().duration_since(UNIX_EPOCH).unwrap() - frame0).as_millis() / delay as u128) as u64;
+    let frame0 = timer.frame_0_time.as_secs_f64() * 1000.0;
+    let elapsed = clock_sync.server_time().as_secs_f64() * 1000.0 - frame0 + clock_adjustment.shift_ms;
+    let needed_frame = (elapsed.max(0.0) / delay as f64) as u64;
     if needed_frame > wanted.0 {
         wanted.as_mut().0 += 1;
     }
@@ -230,19 +302,71 @@ fn change_state<S: bevy::state::state::FreelyMutableState>(state: S) -> impl Fn(
     }
 }
 
+/// Despawns the entities of the match when we leave it, for example after getting kicked
+fn clear_world(
+    mut commands: Commands,
+    entities: Query<Entity, (
+        Or<(With<RollbackID>, With<map::asteroid::AsteroidMarker>, With<spectator::SpectatorCamera>, With<PointLight>)>,
+        Without<Parent>,
+    )>,
+) {
+    for entity in &entities {
+        commands.entity(entity).despawn_recursive();
+    }
+}
+
 fn after_load(
     mut commands: Commands,
     mut state: ResMut<NextState<GameState>>,
+    args: Res<crate::cli::Args>,
+    mut net_config: ResMut<networking::NetConfig>,
 ) {
-    if cfg!(feature="headless") {
-        //TODO: do these have to be here?
-        //let player = player::Player(0);
-        //commands.insert_resource(networking::LocalPlayer(player));
-        //commands.insert_resource(networking::PlayerMap(bevy::utils::HashMap::from([(player,networking::server::ROLLBACK_ID_COUNTER.get_new())])));
-
-        commands.insert_resource(networking::server::ServerMarker);
-        state.set(GameState::ServerSetup);
-    }else{
-        state.set(GameState::MainMenu);
+    args.apply(&mut net_config);
+    commands.insert_resource(args.master());
+    let conditions = args.network_conditions();
+    if conditions.is_active() {
+        println!("simulating network conditions for received messages: {conditions:?}");
+    }
+    commands.insert_resource(conditions);
+    if let Some(path) = &args.record {
+        match crate::replay::ReplayRecorder::create(path) {
+            Ok(recorder) => commands.insert_resource(recorder),
+            Err(e) => warn!("{e}, the match will not be recorded"),
+        }
+    }
+
+    match args.launch_mode() {
+        LaunchMode::Server => {
+            //TODO: do these have to be here?
+            //let player = player::Player(0);
+            //commands.insert_resource(networking::LocalPlayer(player));
+            //commands.insert_resource(networking::PlayerMap(bevy::utils::HashMap::from([(player,networking::server::ROLLBACK_ID_COUNTER.get_new())])));
+
+            if let Some(path) = &args.map {
+                commands.insert_resource(map::MapFile(path.clone()));
+            }
+            commands.insert_resource(args.certificate());
+            if let Some(path) = &args.bans {
+                commands.insert_resource(networking::admin::BanList::new(path.clone()));
+            }
+            if let Some(grace_period) = args.grace_period {
+                commands.insert_resource(networking::session::SessionConfig { grace_period });
+            }
+            commands.insert_resource(networking::spectator::PlayerLimit(args.max_players));
+            commands.insert_resource(args.interest());
+            networking::server::init(&mut commands);
+            state.set(GameState::ServerSetup);
+        },
+        LaunchMode::Client => {
+            networking::client::init(&mut commands);
+            state.set(GameState::ClientSetup);
+        },
+        LaunchMode::Replay => {
+            if let Some(path) = &args.replay {
+                commands.insert_resource(crate::replay::ReplayFile(path.clone()));
+            }
+            state.set(GameState::ReplaySetup);
+        },
+        LaunchMode::MainMenu => state.set(GameState::MainMenu),
     }
 }
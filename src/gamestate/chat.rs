@@ -0,0 +1,114 @@
+// Gravishot
+// Copyright (C) 2024 Tomáš Pecl
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::networking::{ClientMessage, ServerMessage, LocalPlayer};
+use crate::networking::chat::{Chat, sanitize};
+use crate::networking::roster::PlayerRoster;
+use crate::networking::traffic::{ClientSend, ServerSend};
+use crate::networking::admin::AdminCommand;
+use crate::console::ConsoleCommand;
+
+use bevy::prelude::*;
+use bevy_quinnet::client::QuinnetClient;
+use bevy_quinnet::server::QuinnetServer;
+
+use bevy_egui::{egui,EguiContexts};
+
+/// Number of the latest messages shown
+const VISIBLE_MESSAGES: usize = 8;
+
+/// State of the chat window, while it is open the keyboard does not control the player
+#[derive(Resource, Default)]
+pub struct ChatOverlay {
+    pub open: bool,
+    pub text: String,
+}
+
+pub fn ui(
+    mut ctx: EguiContexts,
+    keyboard: Res<ButtonInput<KeyCode>>,
+    mut overlay: ResMut<ChatOverlay>,
+    mut chat: ResMut<Chat>,
+    roster: Res<PlayerRoster>,
+    local_player: Option<Res<LocalPlayer>>,
+    client: Option<ResMut<QuinnetClient>>,
+    server: Option<ResMut<QuinnetServer>>,
+    mut admin: EventWriter<AdminCommand>,
+) {
+    if !overlay.open && keyboard.just_pressed(KeyCode::Enter) {
+        overlay.open = true;
+        return
+    }
+
+    let ctx = ctx.ctx_mut();
+    let mut send = None;
+
+    egui::Window::new("Chat")
+        .anchor(egui::Align2::LEFT_BOTTOM, egui::vec2(10.0, -10.0))
+        .title_bar(false)
+        .resizable(false)
+        .show(ctx, |ui| {
+            for message in chat.log.iter().rev().take(VISIBLE_MESSAGES).rev() {
+                match message.player {
+                    Some(player) => {
+                        let [r, g, b] = roster.color(player).to_srgba().to_u8_array_no_alpha();
+                        ui.horizontal_wrapped(|ui| {
+                            ui.label(egui::RichText::new(format!("{}:", roster.name(player))).color(egui::Color32::from_rgb(r, g, b)));
+                            ui.label(&message.text);
+                        });
+                    },
+                    None => {
+                        ui.label(egui::RichText::new(format!("[server] {}", message.text)).color(egui::Color32::YELLOW));
+                    },
+                }
+            }
+
+            if overlay.open {
+                let response = ui.text_edit_singleline(&mut overlay.text);
+                response.request_focus();
+                if ui.input(|i| i.key_pressed(egui::Key::Escape)) {
+                    overlay.open = false;
+                    overlay.text.clear();
+                }else if ui.input(|i| i.key_pressed(egui::Key::Enter)) {
+                    send = sanitize(&overlay.text);
+                    overlay.open = false;
+                    overlay.text.clear();
+                }
+            }else{
+                ui.label(egui::RichText::new("press Enter to chat").weak());
+            }
+        });
+
+    let Some(text) = send else{return};
+    if let Some(mut client) = client {
+        //the Server sends it back to everyone
+        client.connection_mut().send(ClientMessage::Chat(text));
+    }else if let (Some(mut server), Some(local_player)) = (server, local_player) {
+        //the host can kick and ban like from the console of a dedicated Server
+        if let Some(line) = text.strip_prefix('/') {
+            host_command(line, &mut chat, &roster, &mut admin);
+            return
+        }
+        server.endpoint_mut().broadcast(ServerMessage::Chat(Some(local_player.0), text.clone()));
+        chat.push(Some(local_player.0), text);
+    }
+}
+
+/// Runs a chat command of the host, the replies are shown only in the local chat
+fn host_command(line: &str, chat: &mut Chat, roster: &PlayerRoster, admin: &mut EventWriter<AdminCommand>) {
+    match ConsoleCommand::parse(line) {
+        Ok(Some(ConsoleCommand::Admin(command))) => {
+            admin.send(command);
+        },
+        Ok(Some(ConsoleCommand::Players)) => {
+            let mut players: Vec<_> = roster.0.iter().collect();
+            players.sort_by_key(|(player, _)| player.0);
+            let list: Vec<_> = players.into_iter().map(|(player, info)| format!("{} {}", player.0, info.name)).collect();
+            chat.push(None, list.join(", "));
+        },
+        Ok(Some(_)) | Ok(None) => chat.push(None, "commands: /players, /kick <id> [reason], /ban <id|name:NAME|ip:IP> [time] [reason], /unban <name:NAME|ip:IP>".to_string()),
+        Err(e) => chat.push(None, e),
+    }
+}
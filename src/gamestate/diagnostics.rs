@@ -0,0 +1,80 @@
+// Gravishot
+// Copyright (C) 2024 Tomáš Pecl
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::networking::diagnostics::{NetDiagnostics, total_bytes};
+use crate::networking::traffic::Counters;
+
+use bevy::prelude::*;
+
+use bevy_egui::{egui,EguiContexts};
+
+use super::chat::ChatOverlay;
+
+/// Whether the diagnostics window is shown, toggled with F3
+#[derive(Resource, Default)]
+pub struct DiagnosticsOverlay {
+    pub open: bool,
+}
+
+fn traffic_table(ui: &mut egui::Ui, id: &str, counters: &Counters) {
+    egui::Grid::new(id).striped(true).show(ui, |ui| {
+        ui.label("message");
+        ui.label("msg/s");
+        ui.label("B/s");
+        ui.end_row();
+        for (kind, counter) in counters {
+            ui.label(*kind);
+            ui.label(counter.messages.to_string());
+            ui.label(counter.bytes.to_string());
+            ui.end_row();
+        }
+        ui.label("total");
+        ui.label(counters.values().map(|counter| counter.messages).sum::<u64>().to_string());
+        ui.label(total_bytes(counters).to_string());
+        ui.end_row();
+    });
+}
+
+pub fn ui(
+    mut ctx: EguiContexts,
+    keyboard: Res<ButtonInput<KeyCode>>,
+    chat: Res<ChatOverlay>,
+    mut overlay: ResMut<DiagnosticsOverlay>,
+    net: Res<NetDiagnostics>,
+) {
+    if !chat.open && keyboard.just_pressed(KeyCode::F3) {
+        overlay.open = !overlay.open;
+    }
+    if !overlay.open {
+        return
+    }
+
+    egui::Window::new("Diagnostics")
+        .anchor(egui::Align2::RIGHT_TOP, egui::vec2(-10.0, 10.0))
+        .resizable(false)
+        .show(ctx.ctx_mut(), |ui| {
+            match net.rtt_ms {
+                Some(rtt) => ui.label(format!("RTT: {rtt:.1} ms")),
+                None => ui.label("RTT: -"),
+            };
+            match net.lead_frames {
+                Some(lead) if lead >= 0 => ui.label(format!("ahead of the server: {lead} frames")),
+                Some(lead) => ui.label(format!("behind the server: {} frames", -lead)),
+                None => ui.label("ahead of the server: -"),
+            };
+            ui.label(format!("resimulated: {} frames", net.resimulated));
+
+            let counters = &net.counters;
+            ui.label(format!("too old updates: {} inputs, {} states", counters.too_old_inputs, counters.too_old_states));
+            ui.label(format!("future updates: {} inputs, {} states", counters.future_inputs, counters.future_states));
+
+            ui.separator();
+            ui.label("inbound");
+            traffic_table(ui, "inbound", &net.inbound);
+            ui.separator();
+            ui.label("outbound");
+            traffic_table(ui, "outbound", &net.outbound);
+        });
+}
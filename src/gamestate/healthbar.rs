@@ -3,6 +3,8 @@
 // You should have received a copy of the GNU General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
+use crate::networking::roster::{PlayerRoster, ConnectionState};
+
 use bevy::prelude::*;
 
 use bevy_egui::{egui,EguiContexts};
@@ -11,17 +13,25 @@ pub fn ui(
     mut ctx: EguiContexts,
 
     players: Query<(&crate::player::Health, Has<crate::player::LocalPlayer>, &crate::player::Player)>,
+    roster: Res<PlayerRoster>,
 ) {
     let ctx = ctx.ctx_mut();
 
     egui::Window::new("Player health").show(ctx, |ui| {
         for (hp, local, player) in &players {
+            let name = roster.name(*player);
+            let info = roster.get(*player);
+            let ping = info.map_or(0.0, |info| info.ping_ms);
+            let reconnecting = info.is_some_and(|info| info.state == ConnectionState::Reconnecting);
+            let [r, g, b] = roster.color(*player).to_srgba().to_u8_array_no_alpha();
             let text = if local {
-                egui::RichText::new(format!("Local player {player:?}: {}",hp.0)).color(egui::Color32::RED)
+                egui::RichText::new(format!("{name} (you): {}",hp.0)).strong()
+            }else if reconnecting {
+                egui::RichText::new(format!("{name} (reconnecting): {}",hp.0)).italics()
             }else{
-                egui::RichText::new(format!("Player {player:?}: {}",hp.0))
+                egui::RichText::new(format!("{name} ({ping:.0} ms): {}",hp.0))
             };
-            ui.label(text);
+            ui.label(text.color(egui::Color32::from_rgb(r, g, b)));
         }
     });
-}
\ No newline at end of file
+}
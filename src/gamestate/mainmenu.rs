@@ -4,7 +4,9 @@
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
 use super::GameState;
-use crate::networking::{NetConfig, client::ClientMarker, server::ServerMarker, LocalPlayer};
+use crate::networking::{NetConfig, ConnectionError, client, server, LocalPlayer, PROTOCOL_VERSION};
+use crate::networking::discovery::{LanServers, ServerInfo};
+use crate::networking::master::{MasterServers, MasterConfig};
 use crate::player::Player;
 
 use bevy::prelude::*;
@@ -15,25 +17,100 @@ pub fn ui(
     mut state: ResMut<NextState<GameState>>,
     mut ctx: EguiContexts,
     mut net: ResMut<NetConfig>,
+    error: Option<Res<ConnectionError>>,
+    lan: Res<LanServers>,
+    mut master: ResMut<MasterServers>,
+    mut master_config: ResMut<MasterConfig>,
 ) {
     let ctx = ctx.ctx_mut();
 
     egui::CentralPanel::default().show(ctx, |ui| {
         ui.label(egui::RichText::new("Main menu").font(egui::FontId::proportional(40.0)));
 
+        if let Some(error) = &error {
+            ui.label(egui::RichText::new(&error.0).color(egui::Color32::RED));
+        }
+
         ui.text_edit_singleline(&mut net.ip_port);
+        ui.horizontal(|ui| {
+            ui.label("name");
+            ui.text_edit_singleline(&mut net.name);
+        });
 
-        if ui.button("join server").clicked() {
-            commands.init_resource::<bevy_quinnet::client::QuinnetClient>();
-            commands.insert_resource(ClientMarker);
+        let mut join = ui.button("join server").clicked();
+
+        ui.separator();
+        ui.label("servers on the local network");
+        if lan.servers.is_empty() {
+            ui.label(egui::RichText::new("searching...").weak());
+        }
+        let mut servers: Vec<_> = lan.servers.values().collect();
+        servers.sort_by(|a, b| a.info.name.cmp(&b.info.name).then(a.addr.cmp(&b.addr)));
+        for server in servers {
+            if server_row(ui, &server.info, server.addr, None) {
+                net.ip_port = server.addr.to_string();
+                join = true;
+            }
+        }
+
+        ui.separator();
+        ui.horizontal(|ui| {
+            ui.label("master server");
+            //resolving the address blocks, so it is not done on every typed character
+            let edited = ui.text_edit_singleline(&mut master_config.addr).lost_focus();
+            if ui.button("refresh").clicked() || edited {
+                master.refresh();
+            }
+        });
+        if let Some(error) = &master.error {
+            ui.label(egui::RichText::new(error).color(egui::Color32::RED));
+        }else if master.servers.is_empty() {
+            ui.label(egui::RichText::new("no servers").weak());
+        }
+        let mut servers: Vec<_> = master.servers.values().collect();
+        servers.sort_by(|a, b| a.ping_ms.unwrap_or(f32::MAX).total_cmp(&b.ping_ms.unwrap_or(f32::MAX)).then(a.info.name.cmp(&b.info.name)));
+        for server in servers {
+            if server_row(ui, &server.info, server.addr, Some(server.ping_ms)) {
+                net.ip_port = server.addr.to_string();
+                join = true;
+            }
+        }
+        ui.separator();
+
+        if join {
+            commands.remove_resource::<ConnectionError>();
+            client::init(&mut commands);
             state.set(GameState::ClientSetup);
         }
         if ui.button("start server").clicked() {
             let player = Player(0);
             commands.insert_resource(LocalPlayer(player));
-            commands.init_resource::<bevy_quinnet::server::QuinnetServer>();
-            commands.insert_resource(ServerMarker);
+            server::init(&mut commands);
             state.set(GameState::ServerSetup);
         }
     });
 }
+
+/// One line of a server list, returns true when join was clicked.
+/// The ping is None when it is not measured and Some(None) when the Server did not answer yet.
+fn server_row(ui: &mut egui::Ui, info: &ServerInfo, addr: std::net::SocketAddr, ping: Option<Option<f32>>) -> bool {
+    let players = match info.max_players {
+        Some(max) => format!("{}/{max}", info.players),
+        None => info.players.to_string(),
+    };
+    ui.horizontal(|ui| {
+        let compatible = info.protocol_version == PROTOCOL_VERSION;
+        let join = ui.add_enabled(compatible, egui::Button::new("join")).clicked();
+        ui.label(egui::RichText::new(&info.name).strong());
+        ui.label(format!("map {}, {players} players, {} spectators, {addr}", info.map, info.spectators));
+        match ping {
+            Some(Some(ping)) => {ui.label(format!("{ping:.0} ms"));},
+            Some(None) => {ui.label(egui::RichText::new("? ms").weak());},
+            None => (),
+        }
+        if !compatible {
+            ui.label(egui::RichText::new("incompatible version").color(egui::Color32::RED));
+        }
+        join
+    }).inner
+}
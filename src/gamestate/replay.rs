@@ -0,0 +1,68 @@
+// Gravishot
+// Copyright (C) 2024 Tomáš Pecl
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::replay::ReplayPlayback;
+use crate::gravity::PHYSICS_TIMESTEP_MS;
+
+use bevy_gravirollback::prelude::*;
+
+use bevy::prelude::*;
+
+use bevy_egui::{egui,EguiContexts};
+
+const SPEEDS: [f32; 6] = [0.25, 0.5, 1.0, 2.0, 4.0, 8.0];
+
+/// Time of the frame since the start of the replay as minutes:seconds
+fn format_time(frames: u64) -> String {
+    let secs = frames * PHYSICS_TIMESTEP_MS / 1000;
+    format!("{}:{:02}", secs / 60, secs % 60)
+}
+
+/// Play, pause, speed and seeking controls
+pub fn ui(
+    mut ctx: EguiContexts,
+    mut playback: ResMut<ReplayPlayback>,
+    last_frame: Res<LastFrame>,
+) {
+    let ctx = ctx.ctx_mut();
+    let start = playback.replay.header.start_frame;
+    let end = playback.replay.end_frame();
+
+    egui::Window::new("Replay")
+        .anchor(egui::Align2::CENTER_BOTTOM, egui::vec2(0.0, -10.0))
+        .title_bar(false)
+        .resizable(false)
+        .show(ctx, |ui| {
+            ui.horizontal(|ui| {
+                let label = if playback.paused {"play"}else{"pause"};
+                if ui.button(label).clicked() {
+                    if playback.paused && playback.playhead() >= end {
+                        playback.seek = Some(start);
+                    }
+                    playback.paused = !playback.paused;
+                }
+
+                for speed in SPEEDS {
+                    if ui.selectable_label(playback.speed == speed, format!("{speed}x")).clicked() {
+                        playback.speed = speed;
+                    }
+                }
+
+                ui.label(format!("{} / {}", format_time(last_frame.0.saturating_sub(start)), format_time(end - start)));
+                if playback.is_catching_up(*last_frame) {
+                    ui.label(egui::RichText::new("seeking...").weak());
+                }
+            });
+
+            //seeking back simulates everything again, so it happens only when the slider is released
+            let mut frame = playback.playhead();
+            let response = ui.add(egui::Slider::new(&mut frame, start..=end).show_value(false));
+            if response.drag_stopped() || (response.changed() && !response.dragged()) {
+                playback.seek = Some(frame);
+            }
+
+            ui.label(egui::RichText::new("Tab: next player, F: free camera").weak());
+        });
+}
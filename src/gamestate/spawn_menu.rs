@@ -4,6 +4,7 @@
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
 use crate::networking::rollback::ROLLBACK_ID_COUNTER;
+use super::spectator::SpectateRequest;
 
 use bevy::prelude::*;
 
@@ -13,6 +14,7 @@ pub fn ui(
     mut ctx: EguiContexts,
     //mut events: EventWriter<crate::spawning::LocalSpawnEvent>,
     mut local_input: ResMut<crate::input::LocalInput>,
+    mut spectate: SpectateRequest,
 ) {
     let ctx = ctx.ctx_mut();
 
@@ -23,5 +25,8 @@ pub fn ui(
                 gun: ROLLBACK_ID_COUNTER.get_new(),
             });
         }
+        if ui.button(egui::RichText::new("spectate").font(egui::FontId::proportional(40.0))).clicked() {
+            spectate.send(true);
+        }
     });
-}
\ No newline at end of file
+}
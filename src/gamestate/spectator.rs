@@ -0,0 +1,200 @@
+// Gravishot
+// Copyright (C) 2024 Tomáš Pecl
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::networking::{ClientMessage, LocalPlayer};
+use crate::networking::roster::PlayerRoster;
+use crate::networking::spectator::{PlayerLimit, set_spectating};
+use crate::networking::chat::Chat;
+use crate::networking::traffic::ClientSend;
+use crate::player::{Player, Body, CAMERA_3RD_PERSON};
+use super::chat::ChatOverlay;
+
+use bevy::prelude::*;
+use bevy::ecs::system::SystemParam;
+use bevy::input::mouse::MouseMotion;
+use bevy_quinnet::client::QuinnetClient;
+use bevy_quinnet::server::QuinnetServer;
+
+use bevy_egui::{egui,EguiContexts};
+
+/// Units per second of the free camera, holding Ctrl makes it faster
+const FLY_SPEED: f32 = 30.0;
+/// How fast the following camera catches up with the player
+const FOLLOW_SMOOTHING: f32 = 5.0;
+/// Radians per pixel of mouse movement
+const LOOK_SENSITIVITY: f32 = 0.003;
+
+#[derive(Component)]
+pub struct SpectatorCamera;
+
+/// What the spectator camera looks at
+#[derive(Resource, Default, Debug)]
+pub struct SpectatorView {
+    /// Followed player, None when the camera flies freely
+    pub follow: Option<Player>,
+}
+
+/// Our player is a spectator, see [`crate::networking::spectator`]
+pub fn is_spectating(
+    local_player: Option<Res<LocalPlayer>>,
+    roster: Res<PlayerRoster>,
+) -> bool {
+    local_player.and_then(|player| roster.get(player.0)).is_some_and(|info| info.spectator)
+}
+
+/// Asks to spectate or to play again. The Client asks the Server, the Server decides directly for its local player.
+#[derive(SystemParam)]
+pub struct SpectateRequest<'w, 's> {
+    commands: Commands<'w, 's>,
+    pub roster: ResMut<'w, PlayerRoster>,
+    limit: Res<'w, PlayerLimit>,
+    chat: ResMut<'w, Chat>,
+    local_player: Option<Res<'w, LocalPlayer>>,
+    client: Option<ResMut<'w, QuinnetClient>>,
+    server: Option<ResMut<'w, QuinnetServer>>,
+}
+
+impl SpectateRequest<'_, '_> {
+    pub fn send(&mut self, spectate: bool) {
+        if let Some(client) = &mut self.client {
+            //the Server replies with the updated roster
+            client.connection_mut().send(ClientMessage::Spectate(spectate));
+        }else if let (Some(server), Some(local_player)) = (&mut self.server, &self.local_player) {
+            if let Err(reason) = set_spectating(server.endpoint_mut(), &mut self.commands, &mut self.roster, &self.limit, local_player.0, spectate) {
+                self.chat.push(None, reason);
+            }
+        }
+    }
+}
+
+/// Moves the spectator camera, Tab follows the next player, F switches to the free camera
+pub fn camera(
+    mut commands: Commands,
+    mut view: ResMut<SpectatorView>,
+    mut camera: Query<&mut Transform, With<SpectatorCamera>>,
+    bodies: Query<(&Player, &Transform), (With<Body>, Without<SpectatorCamera>)>,
+    keyboard: Res<ButtonInput<KeyCode>>,
+    mouse_button: Res<ButtonInput<MouseButton>>,
+    mut mouse_motion: EventReader<MouseMotion>,
+    chat: Res<ChatOverlay>,
+    time: Res<Time>,
+) {
+    let Ok(mut transform) = camera.get_single_mut() else{
+        commands.spawn((
+            Camera3d::default(),
+            Transform::from_xyz(0.0, 0.0, 200.0).looking_at(Vec3::ZERO, Vec3::Y),
+            SpectatorCamera,
+            Name::new("Spectator Camera"),
+        ));
+        return
+    };
+
+    //keys typed into the chat do not move the camera
+    let keyboard_enabled = !chat.open;
+    if keyboard_enabled && keyboard.just_pressed(KeyCode::Tab) {
+        view.follow = next_player(view.follow, bodies.iter().map(|(&player, _)| player));
+    }
+    if keyboard_enabled && keyboard.just_pressed(KeyCode::KeyF) {
+        view.follow = None;
+    }
+
+    let dt = time.delta_secs();
+    let followed = view.follow.and_then(|follow| bodies.iter().find(|(&player, _)| player == follow));
+    match followed {
+        Some((_, body)) => {
+            let target = body.transform_point(CAMERA_3RD_PERSON.translation);
+            transform.translation = transform.translation.lerp(target, (FOLLOW_SMOOTHING * dt).min(1.0));
+            transform.look_at(body.translation, body.up());
+            mouse_motion.clear();
+        },
+        None => {
+            //the followed player died or left
+            view.follow = None;
+
+            if mouse_button.pressed(MouseButton::Left) {
+                for motion in mouse_motion.read() {
+                    let (yaw, pitch, _) = transform.rotation.to_euler(EulerRot::YXZ);
+                    let yaw = yaw - motion.delta.x * LOOK_SENSITIVITY;
+                    let pitch = (pitch - motion.delta.y * LOOK_SENSITIVITY).clamp(-1.5, 1.5);
+                    transform.rotation = Quat::from_euler(EulerRot::YXZ, yaw, pitch, 0.0);
+                }
+            }else{
+                mouse_motion.clear();
+            }
+
+            if keyboard_enabled {
+                let mut direction = Vec3::ZERO;
+                for (key, towards) in [
+                    (KeyCode::KeyW, transform.forward()),
+                    (KeyCode::KeyS, transform.back()),
+                    (KeyCode::KeyA, transform.left()),
+                    (KeyCode::KeyD, transform.right()),
+                    (KeyCode::Space, transform.up()),
+                    (KeyCode::ShiftLeft, transform.down()),
+                ] {
+                    if keyboard.pressed(key) {
+                        direction += *towards;
+                    }
+                }
+                let speed = if keyboard.pressed(KeyCode::ControlLeft) {FLY_SPEED * 4.0}else{FLY_SPEED};
+                transform.translation += direction.normalize_or_zero() * speed * dt;
+            }
+        },
+    }
+}
+
+/// The player after the current one ordered by id, wraps around
+fn next_player(current: Option<Player>, players: impl Iterator<Item = Player>) -> Option<Player> {
+    let mut players: Vec<_> = players.collect();
+    players.sort_by_key(|player| player.0);
+    let next = current.and_then(|current| players.iter().find(|player| player.0 > current.0));
+    next.or(players.first()).copied()
+}
+
+/// Removes the spectator camera when we stop spectating
+pub fn remove_camera(
+    mut commands: Commands,
+    cameras: Query<Entity, With<SpectatorCamera>>,
+    mut view: ResMut<SpectatorView>,
+) {
+    for camera in &cameras {
+        commands.entity(camera).despawn_recursive();
+        view.follow = None;
+    }
+}
+
+pub fn ui(
+    mut ctx: EguiContexts,
+    view: Res<SpectatorView>,
+    mut request: SpectateRequest,
+) {
+    let ctx = ctx.ctx_mut();
+    let mut join = false;
+
+    egui::Window::new("Spectating")
+        .anchor(egui::Align2::CENTER_TOP, egui::vec2(0.0, 10.0))
+        .title_bar(false)
+        .resizable(false)
+        .show(ctx, |ui| {
+            match view.follow {
+                Some(player) => {
+                    let [r, g, b] = request.roster.color(player).to_srgba().to_u8_array_no_alpha();
+                    ui.horizontal(|ui| {
+                        ui.label("following");
+                        ui.label(egui::RichText::new(request.roster.name(player)).color(egui::Color32::from_rgb(r, g, b)));
+                    });
+                },
+                None => {
+                    ui.label("free camera: WASD, Space, Shift, drag with the left mouse button to look around");
+                },
+            }
+            ui.label(egui::RichText::new("Tab: next player, F: free camera").weak());
+            join = ui.button("join the game").clicked();
+        });
+
+    if join {
+        request.send(false);
+    }
+}
@@ -5,6 +5,7 @@
 
 use crate::networking::rollback::{ROLLBACK_ID_COUNTER, Rollback, LEN};
 use crate::networking::LocalPlayer;
+use crate::networking::traffic::{ClientSend, ServerSend};
 use crate::player::player_control::PlayerControl;
 use crate::player::Player;
 
@@ -141,6 +142,7 @@ pub fn get_local_input(
     mouse_button: Res<ButtonInput<MouseButton>>,
     mut mouse_motion: EventReader<MouseMotion>,
     player_control: Res<PlayerControl>,
+    chat: Res<crate::gamestate::chat::ChatOverlay>,
     //mut spawn_events: EventWriter<crate::spawning::LocalSpawnEvent>,
     mut local_input: ResMut<LocalInput>,
 
@@ -157,9 +159,14 @@ pub fn get_local_input(
         });
     }*/
 
+    //keys typed into the chat do not control the player
+    let keyboard_enabled = !chat.open;
+
     let input = &mut local_input.0;
-    for key in keyboard.get_pressed() {
-        input.buttons.set_i(I::K(*key), &player_control);
+    if keyboard_enabled {
+        for key in keyboard.get_pressed() {
+            input.buttons.set_i(I::K(*key), &player_control);
+        }
     }
     for mouse_button in mouse_button.get_pressed() {
         input.buttons.set_i(I::M(*mouse_button), &player_control);
@@ -183,7 +190,7 @@ pub fn get_local_input(
     }
 
     //println!("testing shooting");
-    if mouse_button.pressed(MouseButton::Left) && player_control.first_person || keyboard.pressed(KeyCode::KeyG) {
+    if mouse_button.pressed(MouseButton::Left) && player_control.first_person || keyboard_enabled && keyboard.pressed(KeyCode::KeyG) {
         //println!("shooting");
         input.signals.shoot = Some(ShootSignal {
             id: ROLLBACK_ID_COUNTER.get_new(),
@@ -197,10 +204,107 @@ pub fn get_local_input(
 //                                                                                  |                                       |--(we are the Server)--> broadcast Input
 //                                                                                  --(we are a Client)--> send Input to the Server
 
+/// Maximal number of frames whose Inputs are sent together in one [`ClientMessage::Inputs`](crate::networking::ClientMessage::Inputs)
+pub const MAX_REDUNDANT_INPUTS: usize = 8;
+/// Frames between bundles of an idle Client whose Inputs are all empty, it still needs [`ServerMessage::InputAck`](crate::networking::ServerMessage::InputAck)
+/// and lead hints. One bundle covers all frames since the previous one, so the Server sees no gaps.
+pub const IDLE_INPUT_INTERVAL: u64 = MAX_REDUNDANT_INPUTS as u64;
+
+/// Client side, local Inputs which were not yet acknowledged by the Server.
+/// They are all sent again every frame over the unreliable channel until they are acknowledged,
+/// that way a single lost packet does not delay the Input.
+#[derive(Resource, Default)]
+pub struct UnackedInputs {
+    /// Inputs of consecutive frames, the oldest first
+    pub inputs: std::collections::VecDeque<(Frame, Input)>,
+}
+
+impl UnackedInputs {
+    pub fn push(&mut self, frame: Frame, input: Input) {
+        self.inputs.push_back((frame, input));
+        //Inputs older than this are given up, the Server will not wait for them
+        while self.inputs.len() > MAX_REDUNDANT_INPUTS {
+            self.inputs.pop_front();
+        }
+    }
+
+    /// All Inputs up to and including this frame got to the Server
+    pub fn ack(&mut self, frame: Frame) {
+        self.inputs.retain(|(f, _)| f.0 > frame.0);
+    }
+}
+
+/// Server side, remembers which Input frames were already received from a Client to drop duplicates
+/// and which frames can be acknowledged
+#[derive(Default, Clone, Copy, Debug)]
+pub struct ReceivedInputs {
+    /// The newest received frame
+    pub latest: u64,
+    /// Bit i is set when frame `latest - i` was received
+    mask: u64,
+    /// All frames from the first received one up to and including this one were received,
+    /// missing frames which fell out of the mask are skipped, they would not be accepted anymore
+    contiguous: Option<u64>,
+}
+
+impl ReceivedInputs {
+    /// Marks the frame as received, returns false when it was already received or is too old to tell
+    pub fn insert(&mut self, frame: u64) -> bool {
+        let new = if frame > self.latest || self.mask == 0 {
+            let shift = frame.saturating_sub(self.latest);
+            self.mask = if shift >= 64 {0}else{self.mask << shift};
+            self.mask |= 1;
+            self.latest = frame.max(self.latest);
+            true
+        }else{
+            let age = self.latest - frame;
+            if age >= 64 {
+                return false
+            }
+            let bit = 1 << age;
+            let new = self.mask & bit == 0;
+            self.mask |= bit;
+            new
+        };
+        if new {
+            self.advance(frame);
+        }
+        new
+    }
+
+    /// Was the frame received, frames older than the mask are not known
+    fn contains(&self, frame: u64) -> bool {
+        frame <= self.latest && self.latest - frame < 64 && self.mask & (1 << (self.latest - frame)) != 0
+    }
+
+    fn advance(&mut self, frame: u64) {
+        let mut next = match self.contiguous {
+            Some(contiguous) => contiguous + 1,
+            None => frame,
+        };
+        //missing frames which fell out of the mask can not be received anymore
+        next = next.max(self.latest.saturating_sub(63));
+        while self.contains(next) {
+            self.contiguous = Some(next);
+            next += 1;
+        }
+    }
+
+    /// The highest frame up to which all frames were received, it is sent in [`ServerMessage::InputAck`](crate::networking::ServerMessage::InputAck)
+    pub fn contiguous(&self) -> Option<u64> {
+        self.contiguous
+    }
+}
+
+/// Server side, [`ReceivedInputs`] of each player
+#[derive(Resource, Default)]
+pub struct ReceivedClientInputs(pub HashMap<Player, ReceivedInputs>);
+
 pub fn handle_local_input_event(
     mut local_input: ResMut<LocalInput>,
     mut input_events: EventWriter<UpdateInputEvent>,
     mut client: Option<ResMut<bevy_quinnet::client::QuinnetClient>>,
+    mut unacked: ResMut<UnackedInputs>,
     frame: Res<Frame>,
     local_player: Res<LocalPlayer>,
 ) {
@@ -215,10 +319,16 @@ pub fn handle_local_input_event(
     });
 
     if let Some(ref mut client) = client {  //send local Input to the Server
-        if !input.is_empty() {
+        unacked.push(*frame, input);
+
+        //the bundle contains Inputs of consecutive frames, empty ones too, so the Server can acknowledge them
+        let idle = unacked.inputs.iter().all(|(_, input)| input.is_empty());
+        if !idle || frame.0 % IDLE_INPUT_INTERVAL == 0 {
+            let first = unacked.inputs[0].0;
+            let inputs = unacked.inputs.iter().map(|(_, input)| input.clone()).collect();
             //println!("client sending input frame {frame}");
-            client.connection_mut().try_send_message_on(1, //UnorderedReliable
-                crate::networking::ClientMessage::Input(*frame, input)
+            client.connection_mut().send_on(2, //Unreliable
+                crate::networking::ClientMessage::Inputs(first, inputs)
             );
         }
     }
@@ -239,12 +349,31 @@ pub fn handle_update_input_event(
     frames: Res<Rollback<Frame>>,
     mut modified: ResMut<Rollback<Modified>>,
     mut server: Option<ResMut<bevy_quinnet::server::QuinnetServer>>,
+    sessions: Res<crate::networking::session::Sessions>,
+    mut last_hint: Local<HashMap<Player, u64>>,
+    mut counters: ResMut<crate::networking::diagnostics::RollbackCounters>,
+    interest: Res<crate::networking::interest::Interest>,
 ) {
     let mut events_to_resend = Vec::new();
 
     for event in event_cursor.read(&events) {
         let UpdateInputEvent { frame, player, input } = event.clone();
         //println!("handling input event {frame:?}");
+
+        //tell remote Clients when their Inputs do not arrive on time,
+        //idle Clients send empty Inputs every IDLE_INPUT_INTERVAL frames and need the hints too
+        if let Some(ref mut server) = server {
+            //the local player of the Server has no Client
+            let client_id = sessions.client(player);
+            let can_hint = last_hint.get(&player).map_or(true, |&hint_frame| last_frame.0 >= hint_frame + crate::networking::clock::HINT_INTERVAL);
+            if let Some(client_id) = client_id.filter(|_| can_hint) {
+                if let Some(hint) = crate::networking::clock::time_hint(frame, *last_frame) {
+                    server.endpoint_mut().send(client_id, hint);
+                    last_hint.insert(player, last_frame.0);
+                }
+            }
+        }
+
         if input.is_empty() {
             continue;
         }
@@ -252,7 +381,11 @@ pub fn handle_update_input_event(
         //println!("update input event {frame:?} player {player:?} {input:?}");
         let update = frame.0 < last_frame.0;
         if frame.0 > last_frame.0 {
-            warn!("future update event {frame:?} {last_frame:?} player {player:?}, saving for next frame");
+            //Clients run TARGET_LEAD frames ahead, only Inputs beyond the accepted lead are unexpected
+            if frame.0 - last_frame.0 > crate::networking::clock::MAX_LEAD as u64 {
+                warn!("future update event {frame:?} {last_frame:?} player {player:?}, saving for next frame");
+                counters.future_inputs += 1;
+            }
             events_to_resend.push(event.clone());
             continue;
         }
@@ -268,9 +401,11 @@ pub fn handle_update_input_event(
                     if let Some(ref mut server) = server {
                         let endpoint = server.endpoint_mut();
                         let mut clients = endpoint.clients();
-                        clients.retain(|&x| x!=player.0);   //send to everyone except the Client that sent it
-                        endpoint.try_send_group_message_on(
-                            clients.iter(),
+                        let sender = sessions.client(player);
+                        //send to everyone except the Client that sent it and the Clients to which the player is not relevant
+                        clients.retain(|&x| Some(x)!=sender && interest.relays(x, player));
+                        endpoint.send_group_on(
+                            &clients,
                             1,  //UnorderedReliable
                             crate::networking::ServerMessage::Input(event.clone()),
                         );
@@ -279,12 +414,12 @@ pub fn handle_update_input_event(
                     modified[index].0 |= update;
                 },
                 Entry::Occupied(mut entry) => {
-                    if server.is_none() {
-                        if *entry.get() != input {
-                            warn!("input of player {player:?} from {frame:?} got changed");
-                            entry.insert(input);
-                            modified[index].0 |= update;
-                        }
+                    if *entry.get() == input {
+                        //duplicate, Inputs are sent multiple times
+                    }else if server.is_none() {
+                        warn!("input of player {player:?} from {frame:?} got changed");
+                        entry.insert(input);
+                        modified[index].0 |= update;
                     }else{
                         warn!("input of player {player:?} from {frame:?} tried to change");
                     }
@@ -292,10 +427,90 @@ pub fn handle_update_input_event(
             }
         }else{
             warn!("too old frame updated {frame:?} stored {:?} last {last_frame:?} player {player:?}", frames[index]);
+            counters.too_old_inputs += 1;
         }
     }
 
     for event in events_to_resend {
         events.send(event);
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ReceivedInputs;
+
+    fn received(frames: &[u64]) -> ReceivedInputs {
+        let mut received = ReceivedInputs::default();
+        for &frame in frames {
+            received.insert(frame);
+        }
+        received
+    }
+
+    #[test]
+    fn in_order() {
+        let received = received(&[10, 11, 12, 13]);
+        assert_eq!(received.contiguous(), Some(13));
+        assert_eq!(received.latest, 13);
+    }
+
+    #[test]
+    fn nothing_received() {
+        assert_eq!(ReceivedInputs::default().contiguous(), None);
+    }
+
+    #[test]
+    fn duplicates() {
+        let mut received = received(&[10, 11]);
+        assert!(!received.insert(11));
+        assert!(!received.insert(10));
+        assert!(received.insert(12));
+        assert_eq!(received.contiguous(), Some(12));
+    }
+
+    #[test]
+    fn gap() {
+        let mut received = received(&[10, 11, 13, 14]);
+        assert_eq!(received.contiguous(), Some(11));
+        assert_eq!(received.latest, 14);
+        assert!(received.insert(12));
+        assert_eq!(received.contiguous(), Some(14));
+    }
+
+    #[test]
+    fn out_of_order() {
+        let mut received = received(&[10, 14, 12]);
+        assert_eq!(received.contiguous(), Some(10));
+        assert!(received.insert(11));
+        assert_eq!(received.contiguous(), Some(12));
+        assert!(received.insert(13));
+        assert_eq!(received.contiguous(), Some(14));
+    }
+
+    #[test]
+    fn window_shift() {
+        //frames 11 to 99 never arrive, 11 to 36 fall out of the mask and can not be received anymore
+        let mut received = received(&[10, 100]);
+        assert_eq!(received.contiguous(), Some(10));
+        assert!(!received.insert(36));
+        assert_eq!(received.contiguous(), Some(10));
+        assert!(received.insert(37));
+        assert_eq!(received.contiguous(), Some(37));
+        for frame in 38..100 {
+            assert!(received.insert(frame));
+        }
+        assert_eq!(received.contiguous(), Some(100));
+    }
+
+    #[test]
+    fn window_shift_past_gap() {
+        //the gap falls out of the mask, the received frames after it become contiguous
+        let mut received = received(&[10, 12, 13]);
+        assert_eq!(received.contiguous(), Some(10));
+        for frame in 14..=80 {
+            received.insert(frame);
+        }
+        assert_eq!(received.contiguous(), Some(80));
+    }
+}
@@ -12,17 +12,30 @@ mod input;
 mod spawning;
 mod bullet;
 mod physics;
+mod cli;
+mod console;
+mod bots;
+mod replay;
+mod master_protocol;
 
 use bevy::prelude::*;
 use bevy_rapier3d::prelude::*;
 
-fn main() {
+use clap::Parser;
+
+fn main() -> AppExit {
+    let args = cli::Args::parse();
+    if args.bots.is_some() {
+        return bots::run(args)
+    }
+    let headless = args.headless();
+
     let mut app = App::new();
 
     #[allow(unused_mut)]
     let mut default_plugins = DefaultPlugins.build();
 
-    if cfg!(feature="headless") {
+    if headless {
         default_plugins = default_plugins.set(WindowPlugin {
             primary_window: None,
             exit_condition: bevy::window::ExitCondition::DontExit,
@@ -51,10 +64,13 @@ fn main() {
     
     app.add_plugins(default_plugins);
 
-    if cfg!(feature="headless") { 
-        app.add_plugins(bevy::app::ScheduleRunnerPlugin::run_loop(bevy::utils::Duration::from_secs_f64(
-            1.0 / 60.0  //TODO: figure out why the server laggs behind when there is no wait_duration
-        )));
+    if headless {
+        app.add_plugins((
+            bevy::app::ScheduleRunnerPlugin::run_loop(bevy::utils::Duration::from_secs_f64(
+                1.0 / 60.0  //TODO: figure out why the server laggs behind when there is no wait_duration
+            )),
+            console::ConsolePlugin,
+        ));
     }else{
         app.add_plugins((
             bevy_egui::EguiPlugin,
@@ -79,17 +95,29 @@ fn main() {
         RapierPhysicsPlugin::<NoUserData>::default()
             .with_default_system_setup(false),
         player::PlayerPlugin,
-        gamestate::GameStatePlugin,
+        gamestate::GameStatePlugin { headless },
         networking::NetworkPlugin,
     ))
+    .insert_resource(args)
 
-    .run();
+    .run()
 }
 fn setup_server(
     mut update_timer: ResMut<gamestate::UpdateTimer>,
+    mut roster: ResMut<networking::roster::PlayerRoster>,
+    local_player: Option<Res<networking::LocalPlayer>>,
+    net_config: Res<networking::NetConfig>,
+    map: Res<map::Map>,
 ) {
-    let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap();
+    let now = networking::clock::now();
     update_timer.frame_0_time = now;
+    networking::wire::set_position_bounds(map.half_extent());
+
+    //the Server plays too unless it is dedicated
+    if let Some(player) = local_player {
+        let player = player.0;
+        roster.0.insert(player, networking::roster::PlayerInfo::new(player, net_config.name.clone(), now));
+    }
 }
 
 fn setup(
@@ -97,7 +125,10 @@ fn setup(
     mut window: Query<&mut Window, With<bevy::window::PrimaryWindow>>,
     mut rapier_config: Query<&mut RapierConfiguration>,
 ) {
-    window.single_mut().present_mode = bevy::window::PresentMode::AutoNoVsync;
+    //there is no window on a dedicated server
+    if let Ok(mut window) = window.get_single_mut() {
+        window.present_mode = bevy::window::PresentMode::AutoNoVsync;
+    }
 
     let mut rapier_config = rapier_config.single_mut();
     rapier_config.gravity = Vec3::ZERO;
@@ -10,12 +10,21 @@ use bevy::prelude::*;
 use rand::{Rng, thread_rng};
 use serde::{Serialize, Deserialize};
 
+use std::path::PathBuf;
+
 //This file could be made into a separate dynamicaly linked library
 //which would be used as map file. User could choose the library file and 
 //each one would generate different map. It would be more flexible 
 //than using just gltf files or other formats to store the map data.
 
 
+/// Half of the size of the cube in which positions are sent in the compact encoding until the bounds of the map are known,
+/// see [`crate::networking::wire`]
+pub const DEFAULT_HALF_EXTENT: f32 = 512.0;
+/// Space around the farthest asteroid which still belongs to the map bounds, there is room for the asteroid itself
+/// and for flying around it
+pub const MAP_MARGIN: f32 = 256.0;
+
 /// Contains all the information to construct the map.
 /// Server generates this on startup or loads it from a file.
 /// Server sends this to client which uses this to load the map.
@@ -33,8 +42,7 @@ pub struct AsteroidInstance {
     pub atracted_by_gravity: f32,
 }
 impl AsteroidInstance {
-    pub fn new(transform: Option<Transform>, id: Option<usize>, asteroids: &asteroid::AsteroidAssets) -> AsteroidInstance {
-        let mut rng = thread_rng();
+    pub fn new(transform: Option<Transform>, id: Option<usize>, asteroids: &asteroid::AsteroidAssets, rng: &mut impl Rng) -> AsteroidInstance {
         let l = asteroids.asteroids.len();
         let id = id.and_then(|x| if x<l {Some(x)}else{None}).unwrap_or(rng.gen_range(0..l));
         let size = 100.0;
@@ -52,22 +60,84 @@ impl AsteroidInstance {
     }
 }
 
-pub fn generate_map(
+/// Path to a map file which the Server should load instead of generating a new map.
+/// The file contains [`Map`] serialized in RON format.
+#[derive(Resource, Clone, Debug)]
+pub struct MapFile(pub PathBuf);
+
+impl Map {
+    pub fn load(path: &std::path::Path) -> Result<Map, String> {
+        let data = std::fs::read_to_string(path).map_err(|e| format!("could not read map file {}: {e}", path.display()))?;
+        ron::from_str(&data).map_err(|e| format!("could not parse map file {}: {e}", path.display()))
+    }
+
+    /// Checks that the map can be used with the loaded assets
+    pub fn validate(&self, assets: &asteroid::AsteroidAssets) -> Result<(), String> {
+        match self.asteroids.iter().find(|a| a.id >= assets.asteroids.len()) {
+            Some(a) => Err(format!("unknown asteroid id {}", a.id)),
+            None => Ok(()),
+        }
+    }
+
+    pub fn generate(assets: &asteroid::AsteroidAssets, rng: &mut impl Rng) -> Map {
+        let mut asteroids = Vec::new();
+
+        for _ in 0..5 {
+            asteroids.push(AsteroidInstance::new(None, Some(0), assets, rng));
+        }
+        //for _ in 0..1 {
+        //    asteroids.push(AsteroidInstance::new(Some(Transform::from_scale(Vec3::new(4.0, 3.0, 4.0)*5.0*1.0)), Some(0), assets, rng));
+        //}
+
+        Map {
+            asteroids,
+        }
+    }
+
+    pub fn asteroid_count(&self) -> usize {
+        self.asteroids.len()
+    }
+
+    /// Half of the size of the cube centered at the origin which contains all asteroids with [`MAP_MARGIN`] around them
+    pub fn half_extent(&self) -> f32 {
+        let farthest = self.asteroids.iter().map(|a| a.transform.translation.abs().max_element()).fold(0.0, f32::max);
+        farthest + MAP_MARGIN
+    }
+}
+
+/// Loads the map from [`MapFile`] when it is present, otherwise generates a new one
+pub fn setup_map(
     mut commands: Commands,
-    assets: Res<asteroid::AsteroidAssets>
+    assets: Res<asteroid::AsteroidAssets>,
+    map_file: Option<Res<MapFile>>,
+    mut exit: EventWriter<AppExit>,
 ) {
-    let mut asteroids = Vec::new();
-
-    for _ in 0..5 {
-        asteroids.push(AsteroidInstance::new(None, Some(0), &assets));
+    if let Some(map_file) = map_file {
+        let map = match Map::load(&map_file.0) {
+            Ok(map) => map,
+            Err(e) => {
+                eprintln!("{e}");
+                exit.send(AppExit::error());
+                return
+            },
+        };
+        if let Err(e) = map.validate(&assets) {
+            eprintln!("map file {} contains {e}", map_file.0.display());
+            exit.send(AppExit::error());
+            return
+        }
+        println!("loaded map {}", map_file.0.display());
+        commands.insert_resource(map);
+    }else{
+        generate_map(commands, assets);
     }
-    //for _ in 0..1 {
-    //    asteroids.push(AsteroidInstance::new(Some(Transform::from_scale(Vec3::new(4.0, 3.0, 4.0)*5.0*1.0)), Some(0), &assets));
-    //}
+}
 
-    commands.insert_resource(Map {
-        asteroids,
-    });
+pub fn generate_map(
+    mut commands: Commands,
+    assets: Res<asteroid::AsteroidAssets>
+) {
+    commands.insert_resource(Map::generate(&assets, &mut thread_rng()));
 }
 
 pub fn load_from_map(
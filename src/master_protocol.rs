@@ -0,0 +1,78 @@
+// Gravishot
+// Copyright (C) 2024 Tomáš Pecl
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+// Messages of the master server, shared by the game and by the master server binary (src/bin/master.rs),
+// so this file can only use std, serde and ron.
+//
+// Server: every HEARTBEAT_INTERVAL sends Heartbeat(ServerInfo) to the master server
+// master: remembers the Server under the address the Heartbeat came from with the port from ServerInfo,
+//         forgets it when the heartbeats stop
+// Client: sends List(None) -> master: answers with Servers(page), the first Servers ordered by address
+// Client: sends List(Some(address of the last Server in the page)) for the next page until a page is empty
+//
+// Messages are plain UDP datagrams with MASTER_MAGIC followed by the message in RON format.
+// List requests are padded with spaces to LIST_REQUEST_SIZE and the master server never answers with a larger
+// datagram than it received, so it can not be used to flood the address a spoofed request came from.
+
+use serde::{Serialize, Deserialize};
+
+use std::net::SocketAddr;
+
+/// Port the master server listens on by default
+pub const DEFAULT_MASTER_PORT: u16 = 12347;
+/// Every master server datagram starts with this, others are ignored
+pub const MASTER_MAGIC: &[u8] = b"GRAVIMASTER";
+/// Seconds between heartbeats of a Server, the master server forgets Servers which miss a few of them
+pub const HEARTBEAT_INTERVAL: f32 = 10.0;
+/// Size of a [`MasterMessage::List`] datagram, it limits the size of the answer
+pub const LIST_REQUEST_SIZE: usize = 1200;
+
+/// What a Server tells about itself
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ServerInfo {
+    /// Random number chosen when the Server starts, one Server can answer from several addresses
+    pub id: u64,
+    pub name: String,
+    /// File name of the map or "generated"
+    pub map: String,
+    /// Players who are not spectating
+    pub players: usize,
+    pub max_players: Option<usize>,
+    pub spectators: usize,
+    /// Port of the game
+    pub port: u16,
+    pub protocol_version: u32,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub enum MasterMessage {
+    /// Server registers itself or tells that it is still running
+    Heartbeat(ServerInfo),
+    /// Client asks for the Servers whose address is greater than this one, None for the first page
+    List(Option<SocketAddr>),
+    /// Answer to [`MasterMessage::List`], addresses of the games and infos of the Servers, empty after the last page
+    Servers(Vec<(SocketAddr, ServerInfo)>),
+}
+
+impl MasterMessage {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut data = MASTER_MAGIC.to_vec();
+        data.extend(ron::to_string(self).unwrap().into_bytes());
+        data
+    }
+
+    /// Encodes a [`MasterMessage::List`] request padded to [`LIST_REQUEST_SIZE`], RON ignores the trailing spaces
+    pub fn list(after: Option<SocketAddr>) -> Vec<u8> {
+        let mut data = MasterMessage::List(after).encode();
+        data.resize(data.len().max(LIST_REQUEST_SIZE), b' ');
+        data
+    }
+
+    /// None when the datagram is not a master server message
+    pub fn decode(data: &[u8]) -> Option<MasterMessage> {
+        let data = data.strip_prefix(MASTER_MAGIC)?;
+        ron::from_str(std::str::from_utf8(data).ok()?).ok()
+    }
+}
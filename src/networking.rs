@@ -6,12 +6,30 @@
 pub mod server;
 pub mod client;
 pub mod rollback;
+pub mod clock;
+pub mod validation;
+pub mod delta;
+pub mod wire;
+pub mod roster;
+pub mod session;
+pub mod admin;
+pub mod chat;
+pub mod spectator;
+pub mod discovery;
+pub mod master;
+pub mod netsim;
+pub mod checksum;
+pub mod traffic;
+pub mod diagnostics;
+pub mod interest;
+pub mod hits;
+pub mod replication;
 
 use crate::input::{UpdateInputEvent, LocalInput, Input, Inputs};
 use crate::player::Player;
 use crate::map::Map;
-use rollback::UpdateStateEvent;
-use rollback::{State, States, Snapshot, PhysicsBundle, LEN, Rollback};
+use rollback::{UpdateStateEvent, DespawnEvent};
+use rollback::{State, States, PhysicsBundle, LEN, Rollback};
 
 use bevy_gravirollback::prelude::*;
 
@@ -19,34 +37,175 @@ use bevy::prelude::*;
 
 use serde::{Serialize, Deserialize};
 
+use std::time::Duration;
+
+/// Version of the network protocol, increase it when [`ClientMessage`] or [`ServerMessage`] changes
+pub const PROTOCOL_VERSION: u32 = 11;
+/// Hash of the source code and assets, computed by build.rs
+pub const BUILD_HASH: &str = env!("GRAVISHOT_BUILD_HASH");
+pub const MAX_NAME_LEN: usize = 32;
+
+/// Sent by the Client when it wants to join.
+/// The layout of this and of [`ClientMessage::Connect`] and [`ServerMessage::ConnectionRejected`] must never change
+/// and [`ServerMessage::ConnectionGranted`] has to stay the first variant, otherwise incompatible Clients would not be able to read why they got rejected.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ConnectRequest {
+    pub protocol_version: u32,
+    pub build_hash: String,
+    /// Display name of the player
+    pub name: String,
+}
+
+impl ConnectRequest {
+    pub fn new(name: String) -> Self {
+        Self {
+            protocol_version: PROTOCOL_VERSION,
+            build_hash: BUILD_HASH.to_string(),
+            name,
+        }
+    }
+
+    /// Checks if the Client is compatible with us, returns the reason when it is not
+    pub fn check(&self) -> Result<(), String> {
+        if self.protocol_version != PROTOCOL_VERSION {
+            return Err(format!("incompatible protocol version {}, the server has version {PROTOCOL_VERSION}", self.protocol_version));
+        }
+        if self.build_hash != BUILD_HASH {
+            return Err(format!("different game build {}, the server has build {BUILD_HASH}", self.build_hash));
+        }
+        let name = self.name.trim();
+        if name.is_empty() {
+            return Err("the name can not be empty".to_string());
+        }
+        if name.chars().count() > MAX_NAME_LEN {
+            return Err(format!("the name can have at most {MAX_NAME_LEN} characters"));
+        }
+        if name.chars().any(char::is_control) {
+            return Err("the name contains invalid characters".to_string());
+        }
+        Ok(())
+    }
+}
+
 /// Sent from Client to Server
 #[derive(Serialize, Deserialize)]
 pub enum ClientMessage {
-    /// Client wants to connect
-    Connect,
-    /// Sent to the Server to inform of local player Input
-    Input(Frame, Input),
+    /// Client wants to join the game
+    Connect(ConnectRequest),
+    /// Sent to the Server to inform of local player Inputs, contains Inputs of consecutive frames
+    /// starting with the specified frame. Sent over the unreliable channel, every frame again
+    /// until the Server acknowledges them.
+    Inputs(Frame, Vec<Input>),
     /// Sent to the Server to correct the State of local player in specified frame
-    Correction(Frame, State),
+    Correction(Frame, wire::Wire<State>),
+    /// Clock synchronization request, contains the Client time when it was sent
+    /// and the round trip time the Client measured so far (0 when unknown)
+    Ping(Duration, f32),
+    /// Acknowledges that [`ServerMessage::StateSummary`] of this frame was received, it can be used as a baseline
+    SummaryAck(Frame),
+    /// Client lost the connection and wants to continue the session it got in [`ServerMessage::ConnectionGranted`]
+    Reconnect(ConnectRequest, session::SessionToken),
+    /// Chat message for everyone
+    Chat(String),
+    /// Client wants to only watch the game (true) or play again (false), see [`spectator`]
+    Spectate(bool),
+    /// Checksums of consecutive settled frames starting with the specified frame, see [`checksum`]
+    Checksums(Frame, Vec<u64>),
+    /// Answer to [`ServerMessage::Desync`], the Client States of that frame
+    DesyncStates(Frame, delta::EntityStates),
 }
 
 /// Sent from Server to Clients
 #[derive(Serialize, Deserialize)]
 pub enum ServerMessage {
-    /// Init data for the Client, sent by the Server
-    ConnectionGranted(Player, Map, States),
+    /// Init data for the Client, sent by the Server.
+    /// Contains the session token needed to reconnect, the first RollbackID the Client can use for new entities
+    /// and the bounds of the position encoding, see [`wire::set_position_bounds`].
+    ConnectionGranted(Player, session::SessionToken, RollbackID, Map, f32, States),
+    /// Sent instead of [`ServerMessage::ConnectionGranted`] when the Client can not join, contains the reason
+    ConnectionRejected(String),
     /// Info about newly connected Client sent to all Clients
-    Connected(Player),
-    /// Info about disconnected Client sent to all Clients
+    Connected(Player, roster::PlayerInfo),
+    /// The player left, sent to all Clients to update their roster. Its body and gun are removed by [`ServerMessage::Despawn`].
     Disconnected(Player),
     //DespawnPlayer(Player),
     /// Sent to the Client to inform of player Input in specified frame
     Input(UpdateInputEvent),
-    /// Sent to the Client when they are sending future Inputs.
-    /// Contains the last Server frame
-    SlowDown(LastFrame),
-    StateSummary(Frame, Snapshot),
+    /// Acknowledges that the Client Inputs up to and including this frame were received
+    InputAck(Frame),
+    /// Sent to the Client when their Inputs arrive too far in the future.
+    /// Contains the frame of the received Input and the last Server frame
+    SlowDown(Frame, LastFrame),
+    /// Sent to the Client when their Inputs arrive too late.
+    /// Contains the frame of the received Input and the last Server frame
+    SpeedUp(Frame, LastFrame),
+    /// States of the Rollback entities the Client was told to spawn in a past frame, compressed against the baseline frame
+    /// which was acknowledged by the Client, or against nothing when there is no baseline
+    StateSummary(Frame, Option<Frame>, delta::SnapshotDelta),
     MapUpdate(Map),
+    /// Sent to the Client when its [`ClientMessage::Correction`] got rejected, contains the Server State of that frame
+    CorrectionRejected(Frame, RollbackID, wire::Wire<State>),
+    /// All players in the game, sent to new Clients and periodically to refresh the pings
+    Roster(roster::PlayerRoster),
+    /// Reply to [`ClientMessage::Ping`], contains the Client time from the Ping and the Server time when it was received
+    Pong(Duration, Duration),
+    /// The Client got kicked out of the game, contains the reason. The connection gets closed shortly.
+    Kicked(String),
+    /// Chat message, None means it was sent by the Server owner
+    Chat(Option<Player>, String),
+    /// The checksum of this frame sent by the Client does not match, contains the Server States of that frame
+    Desync(Frame, delta::EntityStates),
+    /// The Server registered a hit, it is applied in the simulation of the frame after this one, see [`hits`]
+    Hit(Frame, hits::Hit),
+    /// The entity exists from this frame on, the Client spawns it when it does not have it yet, see [`replication`]
+    Spawn { frame: Frame, id: RollbackID, entity_type: EntityType, state: wire::Wire<State> },
+    /// The entity does not exist from this frame on, see [`replication`]
+    Despawn { frame: Frame, id: RollbackID },
+}
+
+impl ClientMessage {
+    /// Name of the message type, used to count the traffic
+    pub fn kind(&self) -> &'static str {
+        match self {
+            ClientMessage::Connect(..) => "Connect",
+            ClientMessage::Inputs(..) => "Inputs",
+            ClientMessage::Correction(..) => "Correction",
+            ClientMessage::Ping(..) => "Ping",
+            ClientMessage::SummaryAck(..) => "SummaryAck",
+            ClientMessage::Reconnect(..) => "Reconnect",
+            ClientMessage::Chat(..) => "Chat",
+            ClientMessage::Spectate(..) => "Spectate",
+            ClientMessage::Checksums(..) => "Checksums",
+            ClientMessage::DesyncStates(..) => "DesyncStates",
+        }
+    }
+}
+
+impl ServerMessage {
+    /// Name of the message type, used to count the traffic
+    pub fn kind(&self) -> &'static str {
+        match self {
+            ServerMessage::ConnectionGranted(..) => "ConnectionGranted",
+            ServerMessage::ConnectionRejected(..) => "ConnectionRejected",
+            ServerMessage::Connected(..) => "Connected",
+            ServerMessage::Disconnected(..) => "Disconnected",
+            ServerMessage::Input(..) => "Input",
+            ServerMessage::InputAck(..) => "InputAck",
+            ServerMessage::SlowDown(..) => "SlowDown",
+            ServerMessage::SpeedUp(..) => "SpeedUp",
+            ServerMessage::StateSummary(..) => "StateSummary",
+            ServerMessage::MapUpdate(..) => "MapUpdate",
+            ServerMessage::CorrectionRejected(..) => "CorrectionRejected",
+            ServerMessage::Roster(..) => "Roster",
+            ServerMessage::Pong(..) => "Pong",
+            ServerMessage::Kicked(..) => "Kicked",
+            ServerMessage::Chat(..) => "Chat",
+            ServerMessage::Desync(..) => "Desync",
+            ServerMessage::Hit(..) => "Hit",
+            ServerMessage::Spawn { .. } => "Spawn",
+            ServerMessage::Despawn { .. } => "Despawn",
+        }
+    }
 }
 
 /*
@@ -66,8 +225,17 @@ game schedule:
 #[reflect(Resource)]
 pub struct NetConfig {
     pub ip_port: String,
+    /// Name of the local player
+    pub name: String,
+    /// Join the game as a spectator
+    pub spectate: bool,
 }
 
+/// Why the last connection attempt or replay failed, displayed in the main menu
+#[derive(Resource, Reflect, Default, Clone, Debug)]
+#[reflect(Resource)]
+pub struct ConnectionError(pub String);
+
 #[derive(Resource, Reflect, Default, Clone, Copy)]
 #[reflect(Resource)]
 pub struct LocalPlayer(pub Player); //TODO: is this needed?
@@ -78,16 +246,66 @@ impl Plugin for NetworkPlugin {
     fn build(&self, app: &mut App) {
         app
         .insert_resource(NetConfig {
-            ip_port: "localhost:12345".to_string(),
+            ip_port: format!("localhost:{}", crate::cli::DEFAULT_PORT),
+            name: "Player".to_string(),
+            spectate: false,
         })
         .add_event::<UpdateInputEvent>()
         .add_event::<UpdateStateEvent<State>>()
+        .add_event::<DespawnEvent>()
         .init_resource::<Inputs>()
         .init_resource::<LocalInput>()
+        .init_resource::<crate::input::UnackedInputs>()
+        .init_resource::<crate::input::ReceivedClientInputs>()
         .init_resource::<Rollback<Inputs>>()
         .init_resource::<crate::map::Map>()
+        .init_resource::<clock::ClockAdjustment>()
+        .init_resource::<clock::ClockSync>()
+        .register_type::<clock::ClockAdjustment>()
+        .register_type::<clock::ClockSync>()
+        .init_resource::<delta::SummaryBaselines>()
+        .init_resource::<delta::ReceivedSummaries>()
+        .init_resource::<session::Sessions>()
+        .init_resource::<session::SessionConfig>()
+        .register_type::<session::SessionConfig>()
+        .add_event::<admin::AdminCommand>()
+        .init_resource::<admin::BanList>()
+        .init_resource::<admin::PendingKicks>()
+        .init_resource::<server::CertificateConfig>()
+        .register_type::<server::CertificateConfig>()
+        .init_resource::<chat::Chat>()
+        .init_resource::<spectator::PlayerLimit>()
+        .register_type::<spectator::PlayerLimit>()
+        .init_resource::<discovery::LanServers>()
+        .init_resource::<master::MasterConfig>()
+        .register_type::<master::MasterConfig>()
+        .init_resource::<master::MasterServers>()
+        .init_resource::<netsim::NetworkConditions>()
+        .register_type::<netsim::NetworkConditions>()
+        .init_resource::<netsim::ClientInbox>()
+        .init_resource::<netsim::ServerInbox>()
+        .init_resource::<checksum::ClientChecksums>()
+        .init_resource::<checksum::ServerChecksums>()
+        .init_resource::<interest::InterestConfig>()
+        .register_type::<interest::InterestConfig>()
+        .init_resource::<interest::Interest>()
+        .init_resource::<replication::Replicated>()
+        .init_resource::<hits::Hits>()
+        .init_resource::<Rollback<hits::Hits>>()
+        .init_resource::<hits::HitRegistration>()
+        .register_type::<hits::Hits>()
+        .init_resource::<diagnostics::RollbackCounters>()
+        .register_type::<diagnostics::RollbackCounters>()
+        .init_resource::<diagnostics::NetDiagnostics>()
+        .init_resource::<roster::PlayerRoster>()
+        .register_type::<roster::PlayerRoster>()
+        .init_resource::<validation::CorrectionPolicy>()
+        .init_resource::<validation::CorrectionViolations>()
+        .register_type::<validation::CorrectionPolicy>()
+        .register_type::<validation::CorrectionViolations>()
         .register_type::<NetConfig>()
         .register_type::<LocalPlayer>()
+        .register_type::<ConnectionError>()
         .register_type::<crate::player::PlayerParts>()
         .register_type::<EntityType>()
         .register_type::<RollbackID>()
@@ -97,6 +315,7 @@ impl Plugin for NetworkPlugin {
         .register_type::<Rollback<Modified>>()
         .register_type::<RollbackMap>()
         .register_type::<Rollback<Inputs>>()
+        .register_type::<Rollback<hits::Hits>>()
         .register_type::<Rollback<PhysicsBundle>>()
         .register_type::<Rollback<crate::player::HeadData>>()
         .register_type::<crate::map::Map>()
@@ -113,8 +332,10 @@ impl Plugin for NetworkPlugin {
         ))
         
         //TODO: this is not really networking
-        .add_systems(RollbackUpdate,restore_resource::<Inputs,LEN>.in_set(RollbackUpdateSet::LoadInputs))
-        .add_systems(RollbackSave,clear_resource_input_default::<Inputs,LEN>);
+        .add_systems(RollbackUpdate,(restore_resource::<Inputs,LEN>,restore_resource::<hits::Hits,LEN>).in_set(RollbackUpdateSet::LoadInputs))
+        .add_systems(RollbackSave,(clear_resource_input_default::<Inputs,LEN>,clear_resource_input_default::<hits::Hits,LEN>))
+        .add_systems(RollbackUpdate,diagnostics::count_simulated);
+        diagnostics::register(app);
         RollbackSystemConfigurator::<LEN>::default().add::<(
             PhysicsBundle,
             crate::player::Health,
@@ -0,0 +1,281 @@
+// Gravishot
+// Copyright (C) 2024 Tomáš Pecl
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use super::ServerMessage;
+use super::server::{ClientRecords, disconnect};
+use super::traffic::ServerSend;
+use crate::player::Player;
+
+use bevy::prelude::*;
+use bevy_quinnet::server::{QuinnetServer, Endpoint};
+
+use serde::{Serialize, Deserialize};
+
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::time::Duration;
+
+/// Seconds a kicked Client has to read why it got kicked before the connection is closed
+pub const KICK_DELAY: f32 = 1.0;
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug)]
+pub enum BanTarget {
+    /// Player name, compared without case
+    Name(String),
+    Ip(IpAddr),
+}
+
+impl BanTarget {
+    pub fn matches(&self, name: Option<&str>, ip: Option<IpAddr>) -> bool {
+        match self {
+            BanTarget::Name(banned) => name.is_some_and(|name| name.trim().eq_ignore_ascii_case(banned.trim())),
+            BanTarget::Ip(banned) => ip == Some(*banned),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Ban {
+    pub target: BanTarget,
+    pub reason: String,
+    /// Server time when the ban ends, None when it is permanent
+    pub until: Option<Duration>,
+}
+
+impl Ban {
+    /// Message for the banned Client
+    pub fn message(&self, now: Duration) -> String {
+        match self.until {
+            Some(until) => format!("you are banned for {} more minutes: {}", until.saturating_sub(now).as_secs().div_ceil(60), self.reason),
+            None => format!("you are banned permanently: {}", self.reason),
+        }
+    }
+}
+
+/// Bans of the Server, they are saved in a file in RON format so they survive restarts
+#[derive(Resource, Clone, Debug)]
+pub struct BanList {
+    pub file: PathBuf,
+    pub bans: Vec<Ban>,
+}
+
+impl Default for BanList {
+    fn default() -> Self {
+        Self::new(PathBuf::from("bans.ron"))
+    }
+}
+
+impl BanList {
+    pub fn new(file: PathBuf) -> Self {
+        Self {
+            file,
+            bans: Vec::new(),
+        }
+    }
+
+    /// Loads the bans from the file, a missing file means there are no bans
+    pub fn load(&mut self) -> Result<(), String> {
+        let data = match std::fs::read_to_string(&self.file) {
+            Ok(data) => data,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(format!("could not read ban list {}: {e}", self.file.display())),
+        };
+        self.bans = ron::from_str(&data).map_err(|e| format!("could not parse ban list {}: {e}", self.file.display()))?;
+        Ok(())
+    }
+
+    pub fn save(&self) -> Result<(), String> {
+        let data = ron::ser::to_string_pretty(&self.bans, default()).map_err(|e| format!("could not serialize ban list: {e}"))?;
+        std::fs::write(&self.file, data).map_err(|e| format!("could not write ban list {}: {e}", self.file.display()))
+    }
+
+    /// Returns the ban which applies to the Client
+    pub fn check(&self, name: Option<&str>, ip: Option<IpAddr>, now: Duration) -> Option<&Ban> {
+        self.bans.iter().find(|ban| ban.until.map_or(true, |until| until > now) && ban.target.matches(name, ip))
+    }
+
+    pub fn add(&mut self, ban: Ban, now: Duration) {
+        self.bans.retain(|x| x.target != ban.target && x.until.map_or(true, |until| until > now));
+        self.bans.push(ban);
+    }
+
+    /// Returns false when there was no such ban
+    pub fn remove(&mut self, target: &BanTarget) -> bool {
+        let len = self.bans.len();
+        self.bans.retain(|x| x.target != *target);
+        self.bans.len() != len
+    }
+}
+
+/// Loads [`BanList`] when the Server starts, exits when the file is broken so the bans are not lost by overwriting it
+pub fn load_bans(mut bans: ResMut<BanList>, mut exit: EventWriter<AppExit>) {
+    if let Err(e) = bans.load() {
+        eprintln!("{e}");
+        exit.send(AppExit::error());
+        return
+    }
+    println!("loaded {} bans from {}", bans.bans.len(), bans.file.display());
+}
+
+/// Address of a connected Client
+pub fn client_ip(endpoint: &Endpoint, client_id: u64) -> Option<IpAddr> {
+    endpoint.try_get_connection(client_id).map(|connection| connection.remote_address().ip())
+}
+
+/// Clients which were told that they got kicked or rejected, they get disconnected after [`KICK_DELAY`]
+#[derive(Resource, Default)]
+pub struct PendingKicks(pub Vec<(u64, Timer)>);
+
+impl PendingKicks {
+    pub fn add(&mut self, client_id: u64) {
+        if !self.contains(client_id) {
+            self.0.push((client_id, Timer::from_seconds(KICK_DELAY, TimerMode::Once)));
+        }
+    }
+
+    pub fn contains(&self, client_id: u64) -> bool {
+        self.0.iter().any(|(id, _)| *id == client_id)
+    }
+}
+
+pub fn disconnect_kicked(
+    mut server: ResMut<QuinnetServer>,
+    mut kicks: ResMut<PendingKicks>,
+    time: Res<Time<Real>>,
+) {
+    let endpoint = server.endpoint_mut();
+    kicks.0.retain_mut(|(client_id, timer)| {
+        if timer.tick(time.delta()).finished() {
+            let _ = endpoint.disconnect_client(*client_id);
+            false
+        }else{true}
+    });
+}
+
+/// Sent by the Server owner, for example from the console
+#[derive(Event, Clone, Debug)]
+pub enum AdminCommand {
+    Kick {
+        player: Player,
+        reason: String,
+    },
+    /// Bans the name and the address of a connected player and kicks them, None duration means permanently
+    Ban {
+        player: Player,
+        duration: Option<Duration>,
+        reason: String,
+    },
+    /// Bans a name or an address, None duration means permanently
+    BanTarget {
+        target: BanTarget,
+        duration: Option<Duration>,
+        reason: String,
+    },
+    Unban(BanTarget),
+}
+
+pub fn handle_admin_commands(
+    mut events: EventReader<AdminCommand>,
+    mut server: ResMut<QuinnetServer>,
+    mut commands: Commands,
+    mut records: ClientRecords,
+    mut bans: ResMut<BanList>,
+) {
+    let endpoint = server.endpoint_mut();
+    let now = super::clock::now();
+    let mut changed = false;
+
+    for command in events.read() {
+        match command.clone() {
+            AdminCommand::Kick { player, reason } => {
+                if !records.sessions.contains(player) {
+                    warn!("can not kick {player:?}, there is no such remote player");
+                    continue
+                }
+                println!("kicking {player:?}: {reason}");
+                disconnect(endpoint, &mut commands, &mut records, player, &format!("you were kicked: {reason}"));
+            },
+            AdminCommand::Ban { player, duration, reason } => {
+                let Some(info) = records.roster.0.get(&player).filter(|_| records.sessions.contains(player)) else{
+                    warn!("can not ban {player:?}, there is no such remote player");
+                    continue
+                };
+                let until = match ban_end(now, duration) {
+                    Ok(until) => until,
+                    Err(e) => {
+                        warn!("can not ban {player:?}: {e}");
+                        continue
+                    },
+                };
+                let mut targets = vec![BanTarget::Name(info.name.clone())];
+                if let Some(ip) = records.sessions.client(player).and_then(|client_id| client_ip(endpoint, client_id)) {
+                    targets.push(BanTarget::Ip(ip));
+                }
+                for target in targets {
+                    println!("banning {target:?}: {reason}");
+                    bans.add(Ban { target, reason: reason.clone(), until }, now);
+                }
+                let message = bans.bans.last().unwrap().message(now);
+                disconnect(endpoint, &mut commands, &mut records, player, &message);
+                changed = true;
+            },
+            AdminCommand::BanTarget { target, duration, reason } => {
+                let until = match ban_end(now, duration) {
+                    Ok(until) => until,
+                    Err(e) => {
+                        warn!("can not ban {target:?}: {e}");
+                        continue
+                    },
+                };
+                println!("banning {target:?}: {reason}");
+                let ban = Ban { target, reason, until };
+
+                //kick everyone who is already in the game
+                let clients: Vec<_> = records.roster.0.iter()
+                    .filter(|(&player, info)| {
+                        if !records.sessions.contains(player) {
+                            return false
+                        }
+                        let ip = records.sessions.client(player).and_then(|client_id| client_ip(endpoint, client_id));
+                        ban.target.matches(Some(&info.name), ip)
+                    })
+                    .map(|(&player, _)| player)
+                    .collect();
+                for player in clients {
+                    disconnect(endpoint, &mut commands, &mut records, player, &ban.message(now));
+                }
+
+                bans.add(ban, now);
+                changed = true;
+            },
+            AdminCommand::Unban(target) => {
+                if bans.remove(&target) {
+                    println!("unbanned {target:?}");
+                    changed = true;
+                }else{
+                    warn!("{target:?} is not banned");
+                }
+            },
+        }
+    }
+
+    if changed {
+        if let Err(e) = bans.save() {
+            error!("{e}");
+        }
+    }
+}
+
+/// Server time when a ban of the duration ends, an error when the time does not fit into [`Duration`]
+fn ban_end(now: Duration, duration: Option<Duration>) -> Result<Option<Duration>, String> {
+    duration.map(|duration| now.checked_add(duration).ok_or_else(|| format!("ban time of {} s is too long, use perm", duration.as_secs()))).transpose()
+}
+
+/// Tells the Client why it can not join and disconnects it a moment later
+pub fn reject(endpoint: &mut Endpoint, kicks: &mut PendingKicks, client_id: u64, reason: String) {
+    println!("client {client_id} rejected: {reason}");
+    endpoint.send(client_id, ServerMessage::ConnectionRejected(reason));
+    kicks.add(client_id);
+}
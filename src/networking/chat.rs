@@ -0,0 +1,77 @@
+// Gravishot
+// Copyright (C) 2024 Tomáš Pecl
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+// Client: ClientMessage::Chat(text) -> Server: checks length and throttling -> broadcast ServerMessage::Chat(Some(player), text)
+// The sender gets its own message back, that way everyone sees the messages in the same order.
+
+use crate::player::Player;
+
+use bevy::prelude::*;
+use bevy::utils::HashMap;
+
+use std::collections::VecDeque;
+use std::time::Duration;
+
+/// Longer messages are cut
+pub const MAX_CHAT_LEN: usize = 200;
+/// Number of messages kept in [`Chat::log`]
+pub const MAX_CHAT_LOG: usize = 50;
+/// Number of messages a player can send at once
+pub const CHAT_BURST: f32 = 5.0;
+/// Seconds after which a player can send one more message
+pub const CHAT_REFILL: f32 = 2.0;
+
+#[derive(Clone, Debug)]
+pub struct ChatMessage {
+    /// None when sent by the Server owner
+    pub player: Option<Player>,
+    pub text: String,
+}
+
+/// Throttling of one player, token bucket with [`CHAT_BURST`] tokens
+#[derive(Clone, Copy, Debug)]
+pub struct ChatThrottle {
+    tokens: f32,
+    last: Duration,
+}
+
+#[derive(Resource, Default)]
+pub struct Chat {
+    /// Received messages, the oldest first
+    pub log: VecDeque<ChatMessage>,
+    /// Server side, throttling of each player
+    pub throttle: HashMap<Player, ChatThrottle>,
+}
+
+impl Chat {
+    pub fn push(&mut self, player: Option<Player>, text: String) {
+        if self.log.len() >= MAX_CHAT_LOG {
+            self.log.pop_front();
+        }
+        self.log.push_back(ChatMessage { player, text });
+    }
+
+    /// Server side, returns false when the player sends messages too often
+    pub fn allow(&mut self, player: Player, now: Duration) -> bool {
+        let throttle = self.throttle.entry(player).or_insert(ChatThrottle {
+            tokens: CHAT_BURST,
+            last: now,
+        });
+        let refill = now.saturating_sub(throttle.last).as_secs_f32() / CHAT_REFILL;
+        throttle.tokens = (throttle.tokens + refill).min(CHAT_BURST);
+        throttle.last = now;
+        if throttle.tokens >= 1.0 {
+            throttle.tokens -= 1.0;
+            true
+        }else{false}
+    }
+}
+
+/// Removes control characters and cuts the message to [`MAX_CHAT_LEN`], None when nothing remains
+pub fn sanitize(text: &str) -> Option<String> {
+    let text: String = text.chars().filter(|c| !c.is_control()).take(MAX_CHAT_LEN).collect();
+    let text = text.trim();
+    if text.is_empty() {None}else{Some(text.to_string())}
+}
@@ -0,0 +1,272 @@
+// Gravishot
+// Copyright (C) 2024 Tomáš Pecl
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+// Detection of Clients whose simulation silently diverged from the Server.
+//
+// Client: checksum of every settled frame -> every CHECKSUM_BATCH frames sends Checksums(first frame, checksums)
+// Server: waits until the same frame is settled on the Server too, compares it with its own checksum
+// Server: on the first mismatch sends Desync(frame, Server States) -> Client writes the diff, replies DesyncStates(frame, Client States)
+// Server: writes the diff too
+//
+// The checksum covers PhysicsBundle, HeadData, Health and Exists of every existing Rollback entity.
+// The values are hashed at full precision, so any divergence is found. The Client does not adopt summary values
+// which are the same after sending them over the network (see handle_update_state_event), a Client in sync keeps
+// exactly the values of the Server. The hash is FNV-1a, it is the same on every platform.
+// The diffs are written into DESYNC_DIR, only the first desync of each connection is reported.
+// With interest management the Clients simulate only a part of the world, then the checksums are not compared.
+
+use super::rollback::{State, StateQuery, Rollback, LEN, SETTLED_FRAMES, collect_states};
+use super::delta::EntityStates;
+use super::interest::InterestConfig;
+use super::{ClientMessage, ServerMessage};
+use super::traffic::{ClientSend, ServerSend};
+
+use bevy_gravirollback::prelude::*;
+
+use bevy::prelude::*;
+use bevy::utils::HashMap;
+use bevy_quinnet::client::QuinnetClient;
+use bevy_quinnet::server::QuinnetServer;
+
+use std::collections::{BTreeSet, VecDeque};
+use std::fmt::Write;
+use std::path::PathBuf;
+
+/// Number of frames whose checksums the Client sends together
+pub const CHECKSUM_BATCH: usize = 25;
+/// Directory where the diffs of desynchronized frames are written
+pub const DESYNC_DIR: &str = "desync";
+
+const FNV_OFFSET: u64 = 0xcbf29ce484222325;
+const FNV_PRIME: u64 = 0x100000001b3;
+
+fn fnv1a(mut hash: u64, bytes: &[u8]) -> u64 {
+    for &byte in bytes {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(FNV_PRIME);
+    }
+    hash
+}
+
+/// Hash of one entity State
+pub fn entity_checksum(state: &State) -> u64 {
+    let State(physics, player_data, _, _, exists) = state;
+    let mut hash = FNV_OFFSET;
+    let values = physics.transform.translation.to_array().into_iter()
+        .chain(physics.transform.rotation.to_array())
+        .chain(physics.velocity.linvel.to_array())
+        .chain(physics.velocity.angvel.to_array());
+    for x in values {
+        hash = fnv1a(hash, &x.to_bits().to_le_bytes());
+    }
+    if let Some((head_data, health)) = player_data {
+        for x in head_data.rotation.to_array() {
+            hash = fnv1a(hash, &x.to_bits().to_le_bytes());
+        }
+        hash = fnv1a(hash, &health.0.to_bits().to_le_bytes());
+    }
+    fnv1a(hash, &[exists.0 as u8])
+}
+
+/// Hash of the States of all existing entities in one frame, it does not depend on their order
+pub fn checksum(states: &EntityStates) -> u64 {
+    let mut ids: Vec<_> = states.iter().filter(|(_, state)| state.4.0).map(|(id, _)| *id).collect();
+    ids.sort_by_key(|id| id.0);
+    let mut hash = FNV_OFFSET;
+    for id in ids {
+        hash = fnv1a(hash, &id.0.to_le_bytes());
+        hash = fnv1a(hash, &entity_checksum(&states[&id]).to_le_bytes());
+    }
+    hash
+}
+
+/// Writes which entities differ between the local and the remote States, returns the path of the file
+pub fn write_diff(name: &str, frame: u64, local: &EntityStates, remote: &EntityStates) -> Result<PathBuf, String> {
+    let existing = |states: &EntityStates, id: &RollbackID| states.get(id).filter(|state| state.4.0).cloned();
+    let ids: BTreeSet<u64> = local.iter().chain(remote.iter()).filter(|(_, state)| state.4.0).map(|(id, _)| id.0).collect();
+
+    let mut text = String::new();
+    let _ = writeln!(text, "desync in frame {frame}");
+    let _ = writeln!(text, "local checksum {:016x}, remote checksum {:016x}", checksum(local), checksum(remote));
+    let mut matching = 0;
+    for id in ids {
+        let id = RollbackID(id);
+        let (local, remote) = (existing(local, &id), existing(remote, &id));
+        match (&local, &remote) {
+            (Some(l), Some(r)) if entity_checksum(l) == entity_checksum(r) => matching += 1,
+            _ => {
+                let _ = writeln!(text, "\n{id:?}");
+                let _ = writeln!(text, "  local:  {local:?}");
+                let _ = writeln!(text, "  remote: {remote:?}");
+            },
+        }
+    }
+    let _ = writeln!(text, "\n{matching} entities match");
+
+    let path = PathBuf::from(DESYNC_DIR).join(format!("{name}-frame{frame}.txt"));
+    let _ = std::fs::create_dir_all(DESYNC_DIR);
+    std::fs::write(&path, text).map_err(|e| format!("could not write {}: {e}", path.display()))?;
+    Ok(path)
+}
+
+/// Client side, checksums waiting to be sent and recent States for the diff
+#[derive(Resource, Default)]
+pub struct ClientChecksums {
+    /// The next frame to compute
+    next_frame: Option<u64>,
+    /// First frame of the batch and checksums of consecutive frames
+    batch: Option<(u64, Vec<u64>)>,
+    /// States of the last LEN settled frames, the oldest first
+    history: VecDeque<(u64, EntityStates)>,
+}
+
+/// Client side, computes checksums of settled frames and sends them
+pub fn send_checksums(
+    mut client: ResMut<QuinnetClient>,
+    mut checksums: ResMut<ClientChecksums>,
+    query: StateQuery,
+    frames: Res<Rollback<Frame>>,
+    last_frame: Res<LastFrame>,
+) {
+    let checksums = checksums.as_mut();
+    let Some(settled) = last_frame.0.checked_sub(SETTLED_FRAMES) else{return};
+    let mut next = checksums.next_frame.unwrap_or(settled);
+    //after connecting the frames jump forward
+    if next > settled + 1 || settled.saturating_sub(next) >= LEN as u64 {
+        next = settled;
+        checksums.batch = None;
+    }
+
+    for frame in next..=settled {
+        let index = index::<LEN>(frame);
+        if frames[index].0 != frame {
+            checksums.batch = None;
+            continue
+        }
+        let states = collect_states(&query, index);
+        let (first, batch) = checksums.batch.get_or_insert_with(|| (frame, Vec::new()));
+        if *first + batch.len() as u64 != frame {
+            *first = frame;
+            batch.clear();
+        }
+        batch.push(checksum(&states));
+
+        if checksums.history.len() >= LEN {
+            checksums.history.pop_front();
+        }
+        checksums.history.push_back((frame, states));
+    }
+    checksums.next_frame = Some(settled + 1);
+
+    if checksums.batch.as_ref().is_some_and(|(_, batch)| batch.len() >= CHECKSUM_BATCH) {
+        let (first, batch) = checksums.batch.take().unwrap();
+        if client.connection().is_connected() {
+            client.connection_mut().send(ClientMessage::Checksums(Frame(first), batch));
+        }
+    }
+}
+
+/// Client side, handles [`ServerMessage::Desync`], writes the diff and sends our States to the Server
+pub fn report_desync(frame: Frame, server_states: EntityStates) -> impl FnOnce(&mut World) {
+    move |world: &mut World| {
+        let checksums = world.resource::<ClientChecksums>();
+        let Some((_, states)) = checksums.history.iter().find(|(f, _)| *f == frame.0) else{
+            warn!("desync with the Server in {frame:?}, our States of that frame are not stored anymore");
+            return
+        };
+        let states = states.clone();
+        match write_diff("client", frame.0, &states, &server_states) {
+            Ok(path) => warn!("desync with the Server in {frame:?}, the diff was written to {}", path.display()),
+            Err(e) => warn!("desync with the Server in {frame:?}: {e}"),
+        }
+        world.resource_mut::<QuinnetClient>().connection_mut().send(ClientMessage::DesyncStates(frame, states));
+    }
+}
+
+/// Server side, what is known about the simulation of one Client
+#[derive(Default)]
+pub struct ClientDesync {
+    /// Received checksums of frames which are not yet settled on the Server, the oldest first
+    pending: VecDeque<(u64, u64)>,
+    /// Frame which did not match and our States of it, waiting for the States of the Client
+    desync: Option<(u64, EntityStates)>,
+    /// A desync was found, later frames are not compared
+    found: bool,
+}
+
+/// Server side, checksums received from each Client
+#[derive(Resource, Default)]
+pub struct ServerChecksums(pub HashMap<u64, ClientDesync>);
+
+impl ServerChecksums {
+    pub fn received(&mut self, client_id: u64, first: Frame, checksums: Vec<u64>) {
+        let client = self.0.entry(client_id).or_default();
+        if client.found {
+            return
+        }
+        //a misbehaving Client could send too many
+        let space = LEN.saturating_sub(client.pending.len());
+        client.pending.extend(checksums.into_iter().take(space).enumerate().map(|(i, checksum)| (first.0 + i as u64, checksum)));
+    }
+
+    /// The Client sent its States of the desynchronized frame
+    pub fn client_states(&mut self, client_id: u64, frame: Frame, states: EntityStates) {
+        let Some(client) = self.0.get_mut(&client_id) else{return};
+        if client.desync.as_ref().map(|(f, _)| *f) != Some(frame.0) {
+            return
+        }
+        let Some((_, server_states)) = client.desync.take() else{return};
+        match write_diff(&format!("server-client{client_id}"), frame.0, &server_states, &states) {
+            Ok(path) => warn!("desync of client {client_id} in {frame:?}, the diff was written to {}", path.display()),
+            Err(e) => warn!("desync of client {client_id} in {frame:?}: {e}"),
+        }
+    }
+}
+
+/// Server side, compares the checksums of the Clients once their frames are settled on the Server
+pub fn compare_checksums(
+    mut server: ResMut<QuinnetServer>,
+    mut checksums: ResMut<ServerChecksums>,
+    query: StateQuery,
+    frames: Res<Rollback<Frame>>,
+    last_frame: Res<LastFrame>,
+    interest: Res<InterestConfig>,
+) {
+    //filtered Clients do not simulate everything, their checksums can not match
+    if interest.is_active() {
+        checksums.0.values_mut().for_each(|client| client.pending.clear());
+        return
+    }
+    let Some(settled) = last_frame.0.checked_sub(SETTLED_FRAMES) else{return};
+    let endpoint = server.endpoint_mut();
+    //many Clients send checksums of the same frames
+    let mut ours: HashMap<u64, (u64, EntityStates)> = HashMap::new();
+
+    for (&client_id, client) in checksums.0.iter_mut() {
+        while let Some(&(frame, client_checksum)) = client.pending.front() {
+            if frame > settled {
+                break
+            }
+            client.pending.pop_front();
+            let index = index::<LEN>(frame);
+            if frames[index].0 != frame {
+                //too old, it can not be checked
+                continue
+            }
+            let (our_checksum, states) = ours.entry(frame).or_insert_with(|| {
+                let states = collect_states(&query, index);
+                (checksum(&states), states)
+            });
+            if *our_checksum != client_checksum {
+                warn!("client {client_id} desynchronized in frame {frame}, checksum {client_checksum:016x} expected {our_checksum:016x}");
+                endpoint.send(client_id, ServerMessage::Desync(Frame(frame), states.clone()));
+                client.desync = Some((frame, states.clone()));
+                client.found = true;
+                client.pending.clear();
+                break
+            }
+        }
+    }
+}
@@ -7,49 +7,91 @@ use crate::input::UpdateInputEvent;
 use super::rollback::*;
 use super::rollback::{State, Rollback};
 use super::{ClientMessage, ServerMessage};
+use super::traffic::ClientSend;
+use super::wire::Wire;
 
 use bevy_gravirollback::prelude::*;
 
 use bevy::prelude::*;
 
-use bevy_quinnet::client::{QuinnetClient, certificate::CertificateVerificationMode};
+use bevy_quinnet::client::QuinnetClient;
+use bevy_quinnet::client::certificate::{
+    CertificateVerificationMode, TrustOnFirstUseConfig, KnownHosts,
+    CertTrustUpdateEvent, CertConnectionAbortEvent,
+};
 
 use std::net::ToSocketAddrs;
 
 #[derive(Resource)]
 pub struct ClientMarker;
 
+/// Fingerprints of the Server certificates seen so far.
+/// A Server is trusted on the first connection, later its certificate must stay the same.
+pub const KNOWN_HOSTS_FILE: &str = "certificates/known_hosts";
+
+/// Prepares resources needed for running as a Client, switch to [`GameState::ClientSetup`](crate::gamestate::GameState::ClientSetup) afterwards
+pub fn init(commands: &mut Commands) {
+    commands.remove_resource::<super::session::ClientSession>();
+    commands.init_resource::<QuinnetClient>();
+    commands.insert_resource(ClientMarker);
+}
+
 pub fn handle(
     mut client: ResMut<QuinnetClient>,
     //local_player: Option<Res<super::LocalPlayer>>,      //TODO: can this fail?
     //mut net_config: ResMut<super::NetConfig>,
     mut commands: Commands,
+    current_state: Res<bevy::state::state::State<crate::gamestate::GameState>>,
     mut state: ResMut<NextState<crate::gamestate::GameState>>,
 
-    mut update_timer: ResMut<crate::gamestate::UpdateTimer>,
+    mut clock: super::clock::ClientClock,
 
     mut current_frame: ResMut<Frame>,
     mut last_frame: ResMut<LastFrame>,
     mut frames: ResMut<Rollback<Frame>>,
     mut input_event: EventWriter<UpdateInputEvent>,
     mut state_event_writer: EventWriter<UpdateStateEvent<State>>,
-    rollback_map: ResMut<RollbackMap>,
+    mut received_summaries: ResMut<super::delta::ReceivedSummaries>,
+    mut unacked_inputs: ResMut<crate::input::UnackedInputs>,
+    mut roster: ResMut<super::roster::PlayerRoster>,
+    mut chat: ResMut<super::chat::Chat>,
+    mut receiver: super::netsim::ClientReceiver,
 ) {
-    while let Some((_channel_id, msg)) = client.connection_mut().try_receive_message::<ServerMessage>() {
+    while let Some((_channel_id, msg)) = receiver.receive(&mut client) {
         match msg {
             //TODO: move ConnectionGranted in different GameState
-            ServerMessage::ConnectionGranted(player, map, states) => {
+            ServerMessage::ConnectionGranted(player, token, first_id, map, position_bounds, states) => {
                 //TODO: move somewhere else (system set when ClientSetup) such that this system does not need ResMut<NetConfig>?
 
-                ROLLBACK_ID_COUNTER.0.store((player.0 << 32).into(), std::sync::atomic::Ordering::SeqCst);
+                //the following messages are already encoded with these bounds
+                super::wire::set_position_bounds(position_bounds);
+
+                commands.insert_resource(super::session::ClientSession(token));
+                commands.remove_resource::<Reconnecting>();
+                received_summaries.0.clear();
+
+                if *current_state.get() == crate::gamestate::GameState::Running {
+                    //we reconnected, the game kept running so the frames and our entities stay as they are
+                    ROLLBACK_ID_COUNTER.0.fetch_max(first_id.0, std::sync::atomic::Ordering::SeqCst);
+                    println!("client reconnected player {player:?}");
+                    continue
+                }
+
+                ROLLBACK_ID_COUNTER.0.store(first_id.0, std::sync::atomic::Ordering::SeqCst);
 
                 commands.insert_resource(super::LocalPlayer(player));
                 commands.insert_resource(map);
                 
-                //TODO: sync our frame number with the server
+                //our clock is synchronized with the Server clock by ClockSync, frame numbers are computed from it
                 let last = states.last_frame.0;
                 let frame_0_time = states.frame_0_time;
-                update_timer.frame_0_time = frame_0_time;
+                clock.update_timer.frame_0_time = frame_0_time;
+
+                //start a little bit ahead so our Inputs do not arrive late, the Server will tell us how to adjust further
+                unacked_inputs.inputs.clear();
+                roster.0.clear();
+                clock.adjustment.reset();
+                clock.adjustment.target_ms = clock.sync.rtt_ms / 2.0 + (super::clock::TARGET_LEAD * clock.update_timer.delay as i64) as f64;
 
                 last_frame.0 = last;
                 current_frame.0 = last;
@@ -59,13 +101,20 @@ pub fn handle(
 
                 state.set(crate::gamestate::GameState::Running);
             },
-            ServerMessage::Connected(player) => {
-                println!("Player {player:?} connected");
-                //TODO: store connected players somewhere
+            ServerMessage::ConnectionRejected(reason) => {
+                println!("connection rejected: {reason}");
+                commands.insert_resource(super::ConnectionError(format!("could not join: {reason}")));
+                disconnect(&mut commands, &mut client);
+                state.set(crate::gamestate::GameState::MainMenu);
+                return
+            },
+            ServerMessage::Connected(player, info) => {
+                println!("Player {player:?} connected with name {}", info.name);
+                roster.0.insert(player, info);
             },
             ServerMessage::Disconnected(player) => {
                 println!("Player {} disconneted",player.0);
-                commands.queue(crate::player::despawn_player(player));
+                roster.0.remove(&player);
             },
             /*ServerMessage::SpawnPlayer { player, rollback, transform } => {
                 event_spawn.send(crate::player::SpawnPlayerEvent {
@@ -81,35 +130,44 @@ pub fn handle(
                 //println!("server message input frame {frame} player {player:?}");
                 input_event.send(update_input_event);
             },
-            ServerMessage::SlowDown(_frame) => {
-                todo!()
-                /*if now>frame {
-                    //past snapshot
-                    commands.insert_resource(super::rollback::FuturePastSnapshot::<MyState> {
-                        frame,
-                        snapshot: Snapshot::default()
-                    });
-                }*/
+            ServerMessage::InputAck(frame) => {
+                unacked_inputs.ack(frame);
+            },
+            ServerMessage::SlowDown(frame, server_frame) | ServerMessage::SpeedUp(frame, server_frame) => {
+                clock.adjustment.hint(frame, server_frame, clock.update_timer.delay);
             }
-            ServerMessage::StateSummary(frame, snapshot_summary) => {
+            ServerMessage::StateSummary(frame, baseline, mut delta) => {
                 let diff = current_frame.0 as i64 - frame.0 as i64;
-                println!("got summary {frame:?} current {:?} diff {diff}",*current_frame);
+                println!("got summary {frame:?} baseline {baseline:?} current {:?} diff {diff}",*current_frame);
+
+                let base = match baseline {
+                    Some(baseline) => match received_summaries.get(baseline.0) {
+                        Some(base) => Some(base),
+                        None => {
+                            warn!("summary baseline {baseline:?} is not known, ignoring summary {frame:?}");
+                            continue
+                        },
+                    },
+                    None => None,
+                };
+                //stale entities stay as they are, their States in the summary are old
+                let stale = std::mem::take(&mut delta.stale);
+                let snapshot_summary = match delta.apply(base) {
+                    Ok(snapshot) => snapshot,
+                    Err(e) => {
+                        warn!("could not apply summary {frame:?}: {e}");
+                        continue
+                    },
+                };
+                received_summaries.insert(frame.0, snapshot_summary.states.clone());
+                client.connection_mut().send(ClientMessage::SummaryAck(frame));
 
                 let inputs = snapshot_summary.inputs.0;
                 let states = snapshot_summary.states;
 
-                for (id, &entity) in &rollback_map.0 {
-                    if !states.contains_key(id) {
-                        //println!("got summary despawning {entity:?}");
-                        commands.entity(entity).despawn_recursive();
-                    }
-                }
-                //for e in deleted {
-                //    rollback_map.remove(e);
-                //}
-
+                //the summary only reconciles, entities are spawned and despawned by ServerMessage::Spawn and Despawn
                 input_event.send_batch(inputs.into_iter().map(|(player, input)| UpdateInputEvent { frame, player, input }));
-                state_event_writer.send_batch(states.into_iter().filter(|(_,state)| state.4.0).map(|(id, state)| UpdateStateEvent {frame, id, state}));
+                state_event_writer.send_batch(states.into_iter().filter(|(id,state)| state.4.0 && !stale.contains(id)).map(|(id, state)| UpdateStateEvent {frame, id, state, spawn: false}));
 
                 /*
                 //TODO: move this into update event handler
@@ -251,34 +309,272 @@ pub fn handle(
             ServerMessage::MapUpdate(map) => {
                 println!("map update");
                 commands.insert_resource(map);
-            }
+            },
+            ServerMessage::CorrectionRejected(frame, id, Wire(state)) => {
+                warn!("our correction in {frame:?} got rejected");
+                state_event_writer.send(UpdateStateEvent {frame, id, state, spawn: false});
+            },
+            ServerMessage::Spawn { frame, id, entity_type, state: Wire(mut state) } => {
+                state.3 = entity_type;
+                state_event_writer.send(UpdateStateEvent {frame, id, state, spawn: true});
+            },
+            ServerMessage::Despawn { frame, id } => {
+                commands.queue(move |world: &mut World| {
+                    world.send_event(DespawnEvent { frame, id });
+                });
+            },
+            ServerMessage::Roster(new_roster) => {
+                *roster = new_roster;
+            },
+            ServerMessage::Pong(client_time, server_time) => {
+                clock.sync.pong(client_time, server_time);
+            },
+            ServerMessage::Chat(player, text) => {
+                let name = player.map_or("server".to_string(), |player| roster.name(player));
+                println!("[{name}] {text}");
+                chat.push(player, text);
+            },
+            ServerMessage::Desync(frame, states) => {
+                commands.queue(super::checksum::report_desync(frame, states));
+            },
+            ServerMessage::Hit(frame, hit) => {
+                commands.queue(super::hits::receive_hit(frame, hit));
+            },
+            ServerMessage::Kicked(reason) => {
+                println!("kicked: {reason}");
+                commands.insert_resource(super::ConnectionError(reason));
+                disconnect(&mut commands, &mut client);
+                state.set(crate::gamestate::GameState::MainMenu);
+                return
+            },
         }
     }
 }
 
-pub fn connect(mut client: ResMut<QuinnetClient>, myconfig: Res<super::NetConfig>) {
-    let addr = myconfig.ip_port.to_socket_addrs().unwrap().next().unwrap();
+pub fn connect(
+    mut commands: Commands,
+    mut client: ResMut<QuinnetClient>,
+    myconfig: Res<super::NetConfig>,
+    mut state: ResMut<NextState<crate::gamestate::GameState>>,
+) {
+    commands.remove_resource::<ConnectRequested>();
+    if let Err(reason) = open_connection(&mut client, &myconfig) {
+        error!("{reason}");
+        commands.insert_resource(super::ConnectionError(reason));
+        disconnect(&mut commands, &mut client);
+        state.set(crate::gamestate::GameState::MainMenu);
+    }
+}
+
+fn open_connection(client: &mut QuinnetClient, myconfig: &super::NetConfig) -> Result<(), String> {
+    let addr = myconfig.ip_port.to_socket_addrs().ok()
+        .and_then(|mut addrs| addrs.next())
+        .ok_or_else(|| format!("unknown server address {}", myconfig.ip_port))?;
+
+    if let Some(dir) = std::path::Path::new(KNOWN_HOSTS_FILE).parent() {
+        let _ = std::fs::create_dir_all(dir);
+    }
 
     println!("socket: {addr}");
 
     use bevy_quinnet::shared::channels::{ChannelType, ChannelsConfiguration};
     client.open_connection(
         bevy_quinnet::client::connection::ClientEndpointConfiguration::from_addrs(addr,str::parse("0.0.0.0:0").unwrap()),
-        CertificateVerificationMode::SkipVerification,
-        ChannelsConfiguration::from_types(vec![ChannelType::OrderedReliable, ChannelType::UnorderedReliable]).unwrap(),
-    ).unwrap();
+        CertificateVerificationMode::TrustOnFirstUse(TrustOnFirstUseConfig {
+            known_hosts: KnownHosts::HostsFile(KNOWN_HOSTS_FILE.to_string()),
+            ..default()
+        }),
+        ChannelsConfiguration::from_types(vec![ChannelType::OrderedReliable, ChannelType::UnorderedReliable, ChannelType::Unreliable]).unwrap(),
+    ).map_err(|e| format!("could not connect to {addr}: {e}"))?;
+    Ok(())
+}
+
+/// Reports what happened with the Server certificate. When it changed since the last time, the connection
+/// is aborted because someone could be pretending to be the Server.
+pub fn certificate_events(
+    mut trust_updates: EventReader<CertTrustUpdateEvent>,
+    mut aborts: EventReader<CertConnectionAbortEvent>,
+    mut commands: Commands,
+    mut client: ResMut<QuinnetClient>,
+    mut state: ResMut<NextState<crate::gamestate::GameState>>,
+) {
+    for event in trust_updates.read() {
+        let info = &event.cert_info;
+        warn!("first connection to {:?}, trusting its certificate with fingerprint {}", info.server_name, info.fingerprint);
+    }
+
+    if let Some(event) = aborts.read().last() {
+        let info = &event.cert_info;
+        let known = info.known_fingerprint.as_ref().map_or("none".to_string(), |f| f.to_string());
+        let reason = format!(
+            "THE CERTIFICATE OF THE SERVER {:?} CHANGED! Someone could be pretending to be the server. \
+            Known fingerprint {known}, received fingerprint {}. \
+            If the server owner really changed the certificate, remove the server from {KNOWN_HOSTS_FILE}",
+            info.server_name, info.fingerprint,
+        );
+        error!("{reason}");
+        commands.insert_resource(super::ConnectionError(reason));
+        disconnect(&mut commands, &mut client);
+        state.set(crate::gamestate::GameState::MainMenu);
+    }
+    aborts.clear();
 }
 
 pub fn on_connect(
     mut events: EventReader<bevy_quinnet::client::connection::ConnectionEvent>,
-    mut client: ResMut<QuinnetClient>
+    mut client: ResMut<QuinnetClient>,
+    mut clock_sync: ResMut<super::clock::ClockSync>,
 ) {
     if let Some(connection) = events.read().next() {
         let client_id = connection.id;   //TODO: is this really client_id?
 
-        println!("Joining with client_id {client_id}");
+        println!("Connected with client_id {client_id}, synchronizing clock");
 
-        client.connection_mut().try_send_message(ClientMessage::Connect);
+        //the Connect message is sent by request_connection after the clock gets synchronized,
+        //the old estimate is kept until then because a reconnecting Client uses it to keep the game running
+        clock_sync.samples.clear();
+        client.connection_mut().send(ClientMessage::Ping(super::clock::now(), 0.0));
     }
     events.clear();
 }
+
+/// Closes the connection and removes resources added by [`init`]
+pub fn disconnect(commands: &mut Commands, client: &mut QuinnetClient) {
+    let _ = client.close_all_connections();
+    commands.remove_resource::<super::session::ClientSession>();
+    commands.remove_resource::<Reconnecting>();
+    commands.remove_resource::<QuinnetClient>();
+    commands.remove_resource::<ClientMarker>();
+    //messages of the closed connection still waiting in the simulated network
+    commands.insert_resource(super::netsim::ClientInbox::default());
+}
+
+/// Marks that [`ClientMessage::Connect`] was already sent during this [`GameState::ClientSetup`](crate::gamestate::GameState::ClientSetup)
+#[derive(Resource)]
+pub struct ConnectRequested;
+
+/// Asks the Server to join the game once our clock is synchronized with it
+pub fn request_connection(
+    mut commands: Commands,
+    mut client: ResMut<QuinnetClient>,
+    clock_sync: Res<super::clock::ClockSync>,
+    requested: Option<Res<ConnectRequested>>,
+    net_config: Res<super::NetConfig>,
+    session: Option<Res<super::session::ClientSession>>,
+) {
+    if requested.is_none() && clock_sync.is_synced() {
+        println!("clock synchronized, offset {:.1} ms rtt {:.1} ms, joining", clock_sync.offset_ms, clock_sync.rtt_ms);
+        let request = super::ConnectRequest::new(net_config.name.clone());
+        match session {
+            Some(session) => {
+                client.connection_mut().send(ClientMessage::Reconnect(request, session.0));
+            },
+            None => {
+                client.connection_mut().send(ClientMessage::Connect(request));
+                //messages are ordered, the Server handles this after we join
+                if net_config.spectate {
+                    client.connection_mut().send(ClientMessage::Spectate(true));
+                }
+            },
+        }
+        commands.insert_resource(ConnectRequested);
+    }
+}
+
+/// Reconnect attempts after the connection got lost, then the Client gives up and goes back to the main menu
+pub const MAX_RECONNECT_ATTEMPTS: u32 = 6;
+/// Seconds before the first reconnect attempt, the delay doubles with every failed attempt
+pub const RECONNECT_DELAY: f32 = 0.5;
+
+/// Client side, present while the connection is lost and we are trying to get it back
+#[derive(Resource)]
+pub struct Reconnecting {
+    /// Attempts which were already made
+    attempts: u32,
+    /// Waits before the next attempt
+    timer: Timer,
+}
+
+/// Opens a new connection when the old one gets lost while we are in the game, the Server keeps our player for a while.
+/// The attempts are delayed more and more, after [`MAX_RECONNECT_ATTEMPTS`] the Client goes back to the main menu.
+pub fn reconnect(
+    mut lost: EventReader<bevy_quinnet::client::connection::ConnectionLostEvent>,
+    mut failed: EventReader<bevy_quinnet::client::connection::ConnectionFailedEvent>,
+    mut commands: Commands,
+    mut client: ResMut<QuinnetClient>,
+    net_config: Res<super::NetConfig>,
+    session: Option<Res<super::session::ClientSession>>,
+    reconnecting: Option<ResMut<Reconnecting>>,
+    mut receiver: super::netsim::ClientReceiver,
+    mut state: ResMut<NextState<crate::gamestate::GameState>>,
+    time: Res<Time<Real>>,
+) {
+    let dropped = lost.read().count() + failed.read().count() > 0;
+    if session.is_none() {
+        return
+    }
+
+    if dropped {
+        let attempts = reconnecting.as_ref().map_or(0, |reconnecting| reconnecting.attempts);
+        if attempts >= MAX_RECONNECT_ATTEMPTS {
+            give_up_reconnecting(&mut commands, &mut client, &mut state, attempts);
+            return
+        }
+        let delay = RECONNECT_DELAY * 2f32.powi(attempts as i32);
+        warn!("connection to the Server lost, reconnecting in {delay} s");
+        let _ = client.close_all_connections();
+        receiver.clear();
+        commands.remove_resource::<ConnectRequested>();
+        commands.insert_resource(Reconnecting {
+            attempts,
+            timer: Timer::from_seconds(delay, TimerMode::Once),
+        });
+        return
+    }
+
+    let Some(mut reconnecting) = reconnecting else{return};
+    if reconnecting.timer.tick(time.delta()).just_finished() {
+        reconnecting.attempts += 1;
+        println!("reconnect attempt {}/{MAX_RECONNECT_ATTEMPTS}", reconnecting.attempts);
+        if let Err(e) = open_connection(&mut client, &net_config) {
+            //for example DNS does not work during the outage, it counts as a failed attempt
+            warn!("{e}");
+            if reconnecting.attempts >= MAX_RECONNECT_ATTEMPTS {
+                give_up_reconnecting(&mut commands, &mut client, &mut state, reconnecting.attempts);
+            }else{
+                reconnecting.timer = Timer::from_seconds(RECONNECT_DELAY * 2f32.powi(reconnecting.attempts as i32), TimerMode::Once);
+            }
+        }
+    }
+}
+
+fn give_up_reconnecting(commands: &mut Commands, client: &mut QuinnetClient, state: &mut NextState<crate::gamestate::GameState>, attempts: u32) {
+    let reason = format!("lost the connection to the server, {attempts} attempts to reconnect failed");
+    error!("{reason}");
+    commands.insert_resource(super::ConnectionError(reason));
+    disconnect(commands, client);
+    state.set(crate::gamestate::GameState::MainMenu);
+}
+
+/// Sends Pings used for clock synchronization, often while in [`GameState::ClientSetup`](crate::gamestate::GameState::ClientSetup), rarely afterwards
+pub fn send_ping(
+    mut client: ResMut<QuinnetClient>,
+    clock_sync: Res<super::clock::ClockSync>,
+    time: Res<Time<Real>>,
+    mut timer: Local<Timer>,
+) {
+    use super::clock::{SETUP_PING_INTERVAL, RUNNING_PING_INTERVAL};
+
+    if !client.connection().is_connected() {
+        return
+    }
+
+    let interval = if clock_sync.is_synced() {RUNNING_PING_INTERVAL}else{SETUP_PING_INTERVAL};
+    if timer.duration().as_secs_f32() != interval {
+        *timer = Timer::from_seconds(interval, TimerMode::Repeating);
+    }
+
+    if timer.tick(time.delta()).just_finished() {
+        client.connection_mut().send(ClientMessage::Ping(super::clock::now(), clock_sync.rtt_ms as f32));
+    }
+}
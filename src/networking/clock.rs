@@ -0,0 +1,169 @@
+// Gravishot
+// Copyright (C) 2024 Tomáš Pecl
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use super::ServerMessage;
+
+use bevy_gravirollback::prelude::*;
+
+use bevy::prelude::*;
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// Client Inputs should arrive at the Server a little bit before the Server simulates their frame,
+// that way the Server does not have to roll back because of them. The Client therefore runs
+// a few frames ahead of the Server. The Server measures how far ahead the Inputs arrive and
+// sends SlowDown/SpeedUp hints, the Client then stretches or shrinks its frames until it gets
+// to the wanted lead.
+
+/// How many frames ahead of the Server the Client Inputs should arrive
+pub const TARGET_LEAD: i64 = 2;
+/// Inputs arriving more frames ahead than this make the Server send [`ServerMessage::SlowDown`]
+pub const MAX_LEAD: i64 = TARGET_LEAD + 4;
+/// Inputs arriving more frames ahead than this are fine, less makes the Server send [`ServerMessage::SpeedUp`]
+pub const MIN_LEAD: i64 = 0;
+/// Minimal number of frames between two hints sent to the same Client
+pub const HINT_INTERVAL: u64 = 25;
+/// Maximal change of the frame length while adjusting, 0.1 means frames are at most 10% longer or shorter
+pub const MAX_ADJUST_RATE: f64 = 0.1;
+
+/// Decides if the Client which sent an Input for `frame` should change its speed,
+/// `last_frame` is the current Server frame
+pub fn time_hint(frame: Frame, last_frame: LastFrame) -> Option<ServerMessage> {
+    let lead = frame.0 as i64 - last_frame.0 as i64;
+    if lead > MAX_LEAD {
+        Some(ServerMessage::SlowDown(frame, last_frame))
+    }else if lead < MIN_LEAD {
+        Some(ServerMessage::SpeedUp(frame, last_frame))
+    }else{
+        None
+    }
+}
+
+/// Shift of the local game clock against the Server clock, used by the Client to stay
+/// [`TARGET_LEAD`] frames ahead of the Server. Stays zero on the Server.
+#[derive(Resource, Reflect, Default, Debug)]
+#[reflect(Resource)]
+pub struct ClockAdjustment {
+    /// Current shift in milliseconds, positive means we are ahead
+    pub shift_ms: f64,
+    /// Shift we are slowly moving towards
+    pub target_ms: f64,
+}
+
+impl ClockAdjustment {
+    pub fn is_adjusting(&self) -> bool {
+        (self.target_ms - self.shift_ms).abs() > 0.5
+    }
+
+    /// Handles SlowDown/SpeedUp hint from the Server, `frame` is the frame of our Input and
+    /// `last_frame` is the Server frame at the moment it received that Input
+    pub fn hint(&mut self, frame: Frame, last_frame: LastFrame, frame_delay_ms: u64) {
+        //the hints that were sent before the previous adjustment finished are outdated
+        if self.is_adjusting() {
+            return
+        }
+        let lead = frame.0 as i64 - last_frame.0 as i64;
+        self.target_ms = self.shift_ms - ((lead - TARGET_LEAD) * frame_delay_ms as i64) as f64;
+        println!("clock adjustment: input lead {lead} frames, shifting clock to {} ms", self.target_ms);
+    }
+
+    /// Moves the shift towards the target, at most by [`MAX_ADJUST_RATE`] of the elapsed time
+    pub fn advance(&mut self, delta: Duration) {
+        let max_step = delta.as_secs_f64() * 1000.0 * MAX_ADJUST_RATE;
+        let remaining = self.target_ms - self.shift_ms;
+        self.shift_ms += remaining.clamp(-max_step, max_step);
+    }
+
+    pub fn reset(&mut self) {
+        *self = Self::default();
+    }
+}
+
+// The Client and the Server can not rely on their wall clocks being the same. Before the Client
+// asks to join, it exchanges a few Ping/Pong messages with the Server (NTP style) to estimate
+// the round trip time and the offset between the clocks. This is later repeated now and then.
+//
+// Client                         Server
+//   t0 --- Ping(t0) ------------->
+//                                  t1
+//   t3 <-- Pong(t0, t1) ----------
+//
+// rtt = t3 - t0, offset = t1 - (t0 + t3)/2
+
+/// Number of Pong replies needed before the clock is considered synchronized
+pub const SYNC_SAMPLES: usize = 5;
+/// Number of the latest samples used for the estimate
+pub const MAX_SAMPLES: usize = 16;
+/// Time between Pings while synchronizing in [`GameState::ClientSetup`](crate::gamestate::GameState::ClientSetup)
+pub const SETUP_PING_INTERVAL: f32 = 0.1;
+/// Time between Pings while the game is running
+pub const RUNNING_PING_INTERVAL: f32 = 5.0;
+
+/// Local wall clock time
+pub fn now() -> Duration {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap()
+}
+
+#[derive(Reflect, Clone, Copy, Debug)]
+pub struct ClockSample {
+    pub rtt_ms: f64,
+    pub offset_ms: f64,
+}
+
+/// Estimate of the Server clock made from Ping/Pong exchanges
+#[derive(Resource, Reflect, Default, Debug)]
+#[reflect(Resource)]
+pub struct ClockSync {
+    pub samples: Vec<ClockSample>,
+    /// Server clock minus local clock in milliseconds
+    pub offset_ms: f64,
+    /// Round trip time in milliseconds
+    pub rtt_ms: f64,
+}
+
+impl ClockSync {
+    pub fn is_synced(&self) -> bool {
+        self.samples.len() >= SYNC_SAMPLES
+    }
+
+    /// Handles Pong reply, `client_time` is the time we sent the Ping, `server_time` is the time the Server received it
+    pub fn pong(&mut self, client_time: Duration, server_time: Duration) {
+        let t0 = client_time.as_secs_f64() * 1000.0;
+        let t1 = server_time.as_secs_f64() * 1000.0;
+        let t3 = now().as_secs_f64() * 1000.0;
+
+        if t3 < t0 {
+            warn!("pong from the future, ignoring");
+            return
+        }
+
+        if self.samples.len() >= MAX_SAMPLES {
+            self.samples.remove(0);
+        }
+        self.samples.push(ClockSample {
+            rtt_ms: t3 - t0,
+            offset_ms: t1 - (t0 + t3) / 2.0,
+        });
+
+        //the sample with the smallest round trip time has the smallest error
+        let best = self.samples.iter().min_by(|a, b| a.rtt_ms.total_cmp(&b.rtt_ms)).unwrap();
+        self.offset_ms = best.offset_ms;
+        self.rtt_ms = best.rtt_ms;
+    }
+
+    /// Estimate of the current Server wall clock time
+    pub fn server_time(&self) -> Duration {
+        let local = now().as_secs_f64() * 1000.0;
+        Duration::from_secs_f64((local + self.offset_ms).max(0.0) / 1000.0)
+    }
+}
+
+/// Clock related resources used by the Client when handling Server messages
+#[derive(bevy::ecs::system::SystemParam)]
+pub struct ClientClock<'w> {
+    pub update_timer: ResMut<'w, crate::gamestate::UpdateTimer>,
+    pub adjustment: ResMut<'w, ClockAdjustment>,
+    pub sync: ResMut<'w, ClockSync>,
+}
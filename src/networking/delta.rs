@@ -0,0 +1,294 @@
+// Gravishot
+// Copyright (C) 2024 Tomáš Pecl
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use super::EntityType;
+use super::rollback::{State, Snapshot, PhysicsBundle};
+use super::wire::Wire;
+use crate::input::Inputs;
+use crate::player::{Player, HeadData, Health};
+
+use bevy_gravirollback::prelude::*;
+
+use bevy::prelude::*;
+use bevy::utils::HashMap;
+
+use serde::{Serialize, Deserialize};
+
+use std::collections::VecDeque;
+
+// StateSummary is sent as a difference against the last summary the Client acknowledged (the baseline).
+//
+// Server: States of the summary frame -> diff against the Client baseline -> send StateSummary(frame, baseline frame, SnapshotDelta)
+// Client: baseline + SnapshotDelta -> full Snapshot -> update states -> send SummaryAck(frame)
+// Server: SummaryAck(frame) -> the summary of that frame becomes the new baseline of the Client
+//
+// When the Client has not acknowledged anything yet the delta is made against nothing, so it contains everything.
+
+/// Maximal number of summaries remembered for each Client while waiting for acknowledgement
+pub const MAX_PENDING_SUMMARIES: usize = 8;
+
+pub type EntityStates = HashMap<RollbackID, State>;
+
+/// Fields of [`State`] which changed against the baseline, None means unchanged.
+/// Sent in the compact encoding, see [`super::wire`].
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct StateDelta {
+    pub physics: Option<Wire<PhysicsBundle>>,
+    pub player_data: Option<Wire<Option<(HeadData, Health)>>>,
+    pub player: Option<Option<Player>>,
+    pub entity_type: Option<EntityType>,
+    pub exists: Option<bool>,
+}
+
+impl StateDelta {
+    /// Returns None when nothing changed
+    pub fn diff(base: Option<&State>, new: &State) -> Option<StateDelta> {
+        let Some(base) = base else{
+            return Some(StateDelta {
+                physics: Some(Wire(new.0.clone())),
+                player_data: Some(Wire(new.1.clone())),
+                player: Some(new.2),
+                entity_type: Some(new.3),
+                exists: Some(new.4.0),
+            })
+        };
+
+        let delta = StateDelta {
+            //compare what the Client would see, changes smaller than the precision of the wire encoding are not sent
+            physics: (!base.0.wire_eq(&new.0)).then(|| Wire(new.0.clone())),
+            player_data: (!player_data_wire_eq(&base.1, &new.1)).then(|| Wire(new.1.clone())),
+            player: (base.2 != new.2).then_some(new.2),
+            entity_type: (base.3 != new.3).then_some(new.3),
+            exists: (base.4.0 != new.4.0).then_some(new.4.0),
+        };
+
+        if delta.is_empty() {None}else{Some(delta)}
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.physics.is_none()
+            && self.player_data.is_none()
+            && self.player.is_none()
+            && self.entity_type.is_none()
+            && self.exists.is_none()
+    }
+
+    /// Applies the changes onto the baseline, fails when there is no baseline and some field is missing
+    pub fn apply(self, base: Option<&State>) -> Option<State> {
+        let physics = self.physics.map(|x| x.0).or_else(|| base.map(|x| x.0.clone()))?;
+        let player_data = self.player_data.map(|x| x.0).or_else(|| base.map(|x| x.1.clone()))?;
+        let player = self.player.or_else(|| base.map(|x| x.2))?;
+        let entity_type = self.entity_type.or_else(|| base.map(|x| x.3))?;
+        let exists = self.exists.or_else(|| base.map(|x| x.4.0))?;
+        Some(State(physics, player_data, player, entity_type, Exists(exists)))
+    }
+}
+
+/// Compares the values as they would be seen after sending them over the network
+pub fn player_data_wire_eq(a: &Option<(HeadData, Health)>, b: &Option<(HeadData, Health)>) -> bool {
+    match (a, b) {
+        (Some((a_head, a_health)), Some((b_head, b_health))) => super::wire::quat_eq(a_head.rotation, b_head.rotation) && a_health == b_health,
+        (None, None) => true,
+        _ => false,
+    }
+}
+
+/// [`Snapshot`] compressed against a baseline snapshot
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub struct SnapshotDelta {
+    /// Entities which are new or changed against the baseline
+    pub changed: HashMap<RollbackID, StateDelta>,
+    /// Entities which were in the baseline but are not present anymore
+    pub removed: Vec<RollbackID>,
+    pub inputs: Inputs,
+    /// Entities kept from the baseline which did not get updated, the Client must not apply their States, see [`super::interest`]
+    pub stale: Vec<RollbackID>,
+}
+
+impl SnapshotDelta {
+    pub fn diff(base: Option<&EntityStates>, states: &EntityStates, inputs: Inputs) -> SnapshotDelta {
+        let changed = states.iter()
+            .filter_map(|(id, state)| StateDelta::diff(base.and_then(|base| base.get(id)), state).map(|delta| (*id, delta)))
+            .collect();
+        let removed = base.map_or(Vec::new(), |base| base.keys().filter(|id| !states.contains_key(*id)).copied().collect());
+
+        SnapshotDelta {
+            changed,
+            removed,
+            inputs,
+            stale: Vec::new(),
+        }
+    }
+
+    pub fn apply(self, base: Option<&EntityStates>) -> Result<Snapshot, String> {
+        let mut states = base.cloned().unwrap_or_default();
+        for id in self.removed {
+            states.remove(&id);
+        }
+        for (id, delta) in self.changed {
+            let state = delta.apply(states.get(&id)).ok_or_else(|| format!("incomplete delta of new entity {id:?}"))?;
+            states.insert(id, state);
+        }
+        Ok(Snapshot {
+            states,
+            inputs: self.inputs,
+        })
+    }
+}
+
+/// Summaries sent to one Client
+#[derive(Default)]
+pub struct ClientSummaries {
+    /// Summaries which were sent but not yet acknowledged, the oldest first
+    pub pending: VecDeque<(u64, EntityStates)>,
+    /// The newest summary acknowledged by the Client
+    pub baseline: Option<(u64, EntityStates)>,
+}
+
+impl ClientSummaries {
+    pub fn sent(&mut self, frame: u64, states: EntityStates) {
+        if self.pending.len() >= MAX_PENDING_SUMMARIES {
+            self.pending.pop_front();
+        }
+        self.pending.push_back((frame, states));
+    }
+
+    pub fn ack(&mut self, frame: u64) {
+        if self.baseline.as_ref().is_some_and(|(base, _)| *base >= frame) {
+            return
+        }
+        if let Some(i) = self.pending.iter().position(|(f, _)| *f == frame) {
+            //older pending summaries will never become the baseline
+            self.pending.drain(..i);
+            self.baseline = self.pending.pop_front();
+        }
+    }
+}
+
+/// Server side, summaries sent to each Client
+#[derive(Resource, Default)]
+pub struct SummaryBaselines(pub HashMap<u64, ClientSummaries>);
+
+/// Client side, the latest summaries received from the Server, they can be used as baselines by the next summaries
+#[derive(Resource, Default)]
+pub struct ReceivedSummaries(pub VecDeque<(u64, EntityStates)>);
+
+impl ReceivedSummaries {
+    pub fn get(&self, frame: u64) -> Option<&EntityStates> {
+        self.0.iter().find(|(f, _)| *f == frame).map(|(_, states)| states)
+    }
+
+    pub fn insert(&mut self, frame: u64, states: EntityStates) {
+        if self.0.len() >= MAX_PENDING_SUMMARIES {
+            self.0.pop_front();
+        }
+        self.0.push_back((frame, states));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::wire::{WireEncode, max_position_error};
+    use bevy_rapier3d::prelude::Velocity;
+
+    fn player_state(x: f32) -> State {
+        let physics = PhysicsBundle { transform: Transform::from_xyz(x, 2.0, -3.0), velocity: Velocity::linear(Vec3::new(1.0, 0.0, x)) };
+        State(physics, Some((HeadData { rotation: Quat::from_rotation_y(x) }, Health(100.0))), Some(Player(1)), EntityType::Player, Exists(true))
+    }
+
+    fn bullet_state(x: f32) -> State {
+        let physics = PhysicsBundle { transform: Transform::from_xyz(-x, x, 5.0), velocity: Velocity::linear(Vec3::splat(x)) };
+        State(physics, None, Some(Player(1)), EntityType::Bullet, Exists(true))
+    }
+
+    /// The State as the Client sees it after receiving it
+    fn received(state: &State) -> State {
+        State::decode(state.encode())
+    }
+
+    fn received_states(states: &EntityStates) -> EntityStates {
+        states.iter().map(|(id, state)| (*id, received(state))).collect()
+    }
+
+    /// Sends the delta over the network
+    fn send(delta: SnapshotDelta) -> SnapshotDelta {
+        bincode::deserialize(&bincode::serialize(&delta).unwrap()).unwrap()
+    }
+
+    fn assert_states_eq(a: &EntityStates, b: &EntityStates) {
+        let mut ids: Vec<_> = a.keys().map(|id| id.0).collect();
+        let mut other: Vec<_> = b.keys().map(|id| id.0).collect();
+        ids.sort();
+        other.sort();
+        assert_eq!(ids, other);
+        for (id, a) in a {
+            let b = &b[id];
+            assert_eq!(a.0, b.0, "{id:?}");
+            assert_eq!(a.1, b.1, "{id:?}");
+            assert_eq!(a.2, b.2, "{id:?}");
+            assert_eq!(a.3, b.3, "{id:?}");
+            assert_eq!(a.4.0, b.4.0, "{id:?}");
+        }
+    }
+
+    #[test]
+    fn delta_round_trip() {
+        let base: EntityStates = [(RollbackID(1), player_state(1.0)), (RollbackID(2), bullet_state(1.0)), (RollbackID(3), bullet_state(7.0))].into_iter().collect();
+        let new: EntityStates = [(RollbackID(1), player_state(1.5)), (RollbackID(2), bullet_state(1.0)), (RollbackID(4), bullet_state(2.0))].into_iter().collect();
+
+        let delta = send(SnapshotDelta::diff(Some(&base), &new, Inputs::default()));
+        assert!(!delta.changed.contains_key(&RollbackID(2)), "unchanged entity is not sent");
+        //the Client keeps the baseline it received, not the exact Server values
+        let snapshot = delta.apply(Some(&received_states(&base))).unwrap();
+        assert_states_eq(&snapshot.states, &received_states(&new));
+    }
+
+    #[test]
+    fn delta_without_baseline() {
+        let new: EntityStates = [(RollbackID(1), player_state(1.0)), (RollbackID(2), bullet_state(3.0))].into_iter().collect();
+        let snapshot = send(SnapshotDelta::diff(None, &new, Inputs::default())).apply(None).unwrap();
+        assert_states_eq(&snapshot.states, &received_states(&new));
+
+        //the Client lost the baseline, the delta does not contain the unchanged fields
+        let moved: EntityStates = [(RollbackID(1), player_state(2.0)), (RollbackID(2), bullet_state(3.0))].into_iter().collect();
+        assert!(send(SnapshotDelta::diff(Some(&new), &moved, Inputs::default())).apply(None).is_err());
+    }
+
+    #[test]
+    fn removed_entity() {
+        let base: EntityStates = [(RollbackID(1), player_state(1.0)), (RollbackID(2), bullet_state(1.0))].into_iter().collect();
+        let new: EntityStates = [(RollbackID(1), player_state(1.0))].into_iter().collect();
+
+        let delta = send(SnapshotDelta::diff(Some(&base), &new, Inputs::default()));
+        assert_eq!(delta.removed, vec![RollbackID(2)]);
+        assert!(delta.changed.is_empty());
+        let snapshot = delta.apply(Some(&received_states(&base))).unwrap();
+        assert_states_eq(&snapshot.states, &received_states(&new));
+    }
+
+    #[test]
+    fn added_entity() {
+        let base: EntityStates = [(RollbackID(1), player_state(1.0))].into_iter().collect();
+        let new: EntityStates = [(RollbackID(1), player_state(1.0)), (RollbackID(2), bullet_state(4.0))].into_iter().collect();
+
+        let delta = send(SnapshotDelta::diff(Some(&base), &new, Inputs::default()));
+        assert!(delta.removed.is_empty());
+        assert_eq!(delta.changed.len(), 1);
+        //a new entity has all of its fields
+        let added = &delta.changed[&RollbackID(2)];
+        assert!(added.physics.is_some() && added.player_data.is_some() && added.player.is_some() && added.entity_type.is_some() && added.exists.is_some());
+        let snapshot = delta.apply(Some(&received_states(&base))).unwrap();
+        assert_states_eq(&snapshot.states, &received_states(&new));
+    }
+
+    #[test]
+    fn change_below_precision_not_sent() {
+        let state = received(&player_state(1.0));
+        let mut moved = state.clone();
+        moved.0.transform.translation.x += max_position_error() / 4.0;
+        assert!(StateDelta::diff(Some(&state), &moved).is_none());
+    }
+}
@@ -0,0 +1,155 @@
+// Gravishot
+// Copyright (C) 2024 Tomáš Pecl
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+// Network and rollback measurements, shown in the diagnostics overlay (F3) and recorded as Bevy Diagnostics.
+//
+// RTT comes from ClockSync, the lead is our last frame minus the estimate of the Server frame.
+// Resimulated frames are the frames simulated in one update minus the new frames, that is the cost of rollbacks.
+// The traffic is counted in traffic, the rollback events where they are handled.
+
+use super::clock::ClockSync;
+use super::traffic::{Counters, TRAFFIC};
+use crate::gamestate::UpdateTimer;
+
+use bevy_gravirollback::prelude::*;
+
+use bevy::prelude::*;
+use bevy::diagnostic::{Diagnostic, DiagnosticPath, Diagnostics, RegisterDiagnostic};
+
+pub const RTT: DiagnosticPath = DiagnosticPath::const_new("net/rtt");
+pub const LEAD: DiagnosticPath = DiagnosticPath::const_new("net/lead");
+pub const RESIMULATED: DiagnosticPath = DiagnosticPath::const_new("net/resimulated");
+pub const BYTES_IN: DiagnosticPath = DiagnosticPath::const_new("net/bytes_in");
+pub const BYTES_OUT: DiagnosticPath = DiagnosticPath::const_new("net/bytes_out");
+pub const TOO_OLD: DiagnosticPath = DiagnosticPath::const_new("net/too_old_updates");
+pub const FUTURE: DiagnosticPath = DiagnosticPath::const_new("net/future_updates");
+
+/// Seconds over which the traffic is summed
+const TRAFFIC_WINDOW: f32 = 1.0;
+
+pub fn register(app: &mut App) {
+    app
+    .register_diagnostic(Diagnostic::new(RTT).with_suffix("ms"))
+    .register_diagnostic(Diagnostic::new(LEAD).with_suffix(" frames"))
+    .register_diagnostic(Diagnostic::new(RESIMULATED).with_suffix(" frames"))
+    .register_diagnostic(Diagnostic::new(BYTES_IN).with_suffix(" B/s"))
+    .register_diagnostic(Diagnostic::new(BYTES_OUT).with_suffix(" B/s"))
+    .register_diagnostic(Diagnostic::new(TOO_OLD))
+    .register_diagnostic(Diagnostic::new(FUTURE));
+}
+
+/// Counts of events which happen inside the rollback handling, since the start
+#[derive(Resource, Reflect, Default, Clone, Copy, Debug)]
+#[reflect(Resource)]
+pub struct RollbackCounters {
+    /// Inputs of frames which are not stored anymore
+    pub too_old_inputs: u64,
+    /// Inputs further ahead of our last frame than [`MAX_LEAD`](super::clock::MAX_LEAD)
+    pub future_inputs: u64,
+    /// States of frames which are not stored anymore
+    pub too_old_states: u64,
+    /// States of frames we did not simulate yet
+    pub future_states: u64,
+    /// Frames simulated, including the simulated again
+    pub simulated: u64,
+}
+
+/// Counts every run of the RollbackUpdate schedule
+pub fn count_simulated(mut counters: ResMut<RollbackCounters>) {
+    counters.simulated += 1;
+}
+
+/// The latest measurements
+#[derive(Resource, Default)]
+pub struct NetDiagnostics {
+    /// Round trip time to the Server, None on the Server
+    pub rtt_ms: Option<f64>,
+    /// How many frames we are ahead of the Server (negative means behind), None on the Server
+    pub lead_frames: Option<i64>,
+    /// Frames simulated again in the last update
+    pub resimulated: u64,
+    /// Received messages per second by message type
+    pub inbound: Counters,
+    /// Sent messages per second by message type
+    pub outbound: Counters,
+    /// The counters at the last update
+    pub counters: RollbackCounters,
+
+    last_frame: u64,
+    window: f32,
+    window_inbound: Counters,
+    window_outbound: Counters,
+}
+
+fn sum(into: &mut Counters, from: Counters) {
+    for (kind, counter) in from {
+        let total = into.entry(kind).or_default();
+        total.messages += counter.messages;
+        total.bytes += counter.bytes;
+    }
+}
+
+pub fn total_bytes(counters: &Counters) -> u64 {
+    counters.values().map(|counter| counter.bytes).sum()
+}
+
+/// Runs after the rollback schedule, collects the measurements of this update
+pub fn measure(
+    mut net: ResMut<NetDiagnostics>,
+    mut diagnostics: Diagnostics,
+    counters: Res<RollbackCounters>,
+    last_frame: Res<LastFrame>,
+    timer: Res<UpdateTimer>,
+    clock_sync: Res<ClockSync>,
+    client: Option<Res<super::client::ClientMarker>>,
+    time: Res<Time<Real>>,
+) {
+    let net = net.as_mut();
+
+    if client.is_some() {
+        net.rtt_ms = Some(clock_sync.rtt_ms);
+        let elapsed = clock_sync.server_time().saturating_sub(timer.frame_0_time).as_millis() as u64;
+        let server_frame = elapsed / timer.delay.max(1);
+        net.lead_frames = Some(last_frame.0 as i64 - server_frame as i64);
+    }else{
+        net.rtt_ms = None;
+        net.lead_frames = None;
+    }
+
+    let new_frames = last_frame.0.saturating_sub(net.last_frame);
+    let simulated = counters.simulated - net.counters.simulated;
+    net.resimulated = simulated.saturating_sub(new_frames);
+    net.last_frame = last_frame.0;
+    let too_old = (counters.too_old_inputs + counters.too_old_states) - (net.counters.too_old_inputs + net.counters.too_old_states);
+    let future = (counters.future_inputs + counters.future_states) - (net.counters.future_inputs + net.counters.future_states);
+    net.counters = *counters;
+
+    let (inbound, outbound) = TRAFFIC.take();
+    sum(&mut net.window_inbound, inbound);
+    sum(&mut net.window_outbound, outbound);
+    net.window += time.delta_secs();
+    if net.window >= TRAFFIC_WINDOW {
+        let scale = |counters: Counters, window: f32| counters.into_iter().map(|(kind, mut counter)| {
+            counter.messages = (counter.messages as f32 / window).round() as u64;
+            counter.bytes = (counter.bytes as f32 / window).round() as u64;
+            (kind, counter)
+        }).collect();
+        net.inbound = scale(std::mem::take(&mut net.window_inbound), net.window);
+        net.outbound = scale(std::mem::take(&mut net.window_outbound), net.window);
+        net.window = 0.0;
+        diagnostics.add_measurement(&BYTES_IN, || total_bytes(&net.inbound) as f64);
+        diagnostics.add_measurement(&BYTES_OUT, || total_bytes(&net.outbound) as f64);
+    }
+
+    if let Some(rtt) = net.rtt_ms {
+        diagnostics.add_measurement(&RTT, || rtt);
+    }
+    if let Some(lead) = net.lead_frames {
+        diagnostics.add_measurement(&LEAD, || lead as f64);
+    }
+    diagnostics.add_measurement(&RESIMULATED, || net.resimulated as f64);
+    diagnostics.add_measurement(&TOO_OLD, || too_old as f64);
+    diagnostics.add_measurement(&FUTURE, || future as f64);
+}
@@ -0,0 +1,186 @@
+// Gravishot
+// Copyright (C) 2024 Tomáš Pecl
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+// Finding Servers on the local network.
+//
+// Client: every PROBE_INTERVAL sends DiscoveryMessage::Probe to the broadcast address and to localhost on DISCOVERY_PORT
+// Server: listens on DISCOVERY_PORT, answers DiscoveryMessage::Info(ServerInfo) to the sender
+// Client: lists the Servers which answered recently, the game port is in ServerInfo, the ip is the one the answer came from
+//
+// Messages are plain UDP datagrams with DISCOVERY_MAGIC followed by the message in RON format.
+// Only one Server per machine can listen on DISCOVERY_PORT, others are not discoverable.
+
+use super::NetConfig;
+use super::server::CertificateConfig;
+use super::roster::PlayerRoster;
+use super::spectator::PlayerLimit;
+use crate::map::MapFile;
+
+pub use crate::master_protocol::ServerInfo;
+
+use bevy::prelude::*;
+use bevy::ecs::system::SystemParam;
+use bevy::utils::HashMap;
+
+use serde::{Serialize, Deserialize};
+
+use std::net::{UdpSocket, SocketAddr, Ipv4Addr, ToSocketAddrs};
+
+/// Port the Servers listen on for discovery probes
+pub const DISCOVERY_PORT: u16 = 12346;
+/// Every discovery datagram starts with this, others are ignored
+pub const DISCOVERY_MAGIC: &[u8] = b"GRAVISHOT";
+/// Seconds between probes sent by the Client
+pub const PROBE_INTERVAL: f32 = 2.0;
+/// Seconds after which a Server which stopped answering is removed from the list
+pub const SERVER_TIMEOUT: f32 = 3.0 * PROBE_INTERVAL;
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub enum DiscoveryMessage {
+    Probe,
+    Info(ServerInfo),
+}
+
+impl DiscoveryMessage {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut data = DISCOVERY_MAGIC.to_vec();
+        data.extend(ron::to_string(self).unwrap().into_bytes());
+        data
+    }
+
+    /// None when the datagram is not a discovery message
+    pub fn decode(data: &[u8]) -> Option<DiscoveryMessage> {
+        let data = data.strip_prefix(DISCOVERY_MAGIC)?;
+        ron::from_str(std::str::from_utf8(data).ok()?).ok()
+    }
+}
+
+/// Random id of the Server, see [`ServerInfo::id`]
+#[derive(Resource, Clone, Copy, Debug)]
+pub struct ServerId(pub u64);
+
+/// Everything needed to describe the Server in [`ServerInfo`]
+#[derive(SystemParam)]
+pub struct ServerDescription<'w> {
+    id: Res<'w, ServerId>,
+    config: Res<'w, NetConfig>,
+    cert: Res<'w, CertificateConfig>,
+    map_file: Option<Res<'w, MapFile>>,
+    roster: Res<'w, PlayerRoster>,
+    limit: Res<'w, PlayerLimit>,
+}
+
+impl ServerDescription<'_> {
+    pub fn info(&self) -> ServerInfo {
+        let map = self.map_file.as_ref()
+            .and_then(|file| file.0.file_name())
+            .map_or("generated".to_string(), |name| name.to_string_lossy().into_owned());
+        let port = self.config.ip_port.to_socket_addrs().ok().and_then(|mut addrs| addrs.next()).map_or(0, |addr| addr.port());
+        ServerInfo {
+            id: self.id.0,
+            name: self.cert.hostname.clone(),
+            map,
+            players: self.roster.playing(),
+            max_players: self.limit.0,
+            spectators: self.roster.0.len() - self.roster.playing(),
+            port,
+            protocol_version: super::PROTOCOL_VERSION,
+        }
+    }
+}
+
+/// Server side, socket answering discovery probes
+#[derive(Resource)]
+pub struct DiscoveryResponder(UdpSocket);
+
+pub fn start_responder(mut commands: Commands) {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, DISCOVERY_PORT)).and_then(|socket| {
+        socket.set_nonblocking(true)?;
+        Ok(socket)
+    });
+    match socket {
+        Ok(socket) => {
+            println!("answering LAN discovery on port {DISCOVERY_PORT}");
+            commands.insert_resource(DiscoveryResponder(socket));
+        },
+        Err(e) => warn!("LAN discovery disabled, could not listen on port {DISCOVERY_PORT}: {e}"),
+    }
+}
+
+pub fn answer_probes(
+    responder: Res<DiscoveryResponder>,
+    description: ServerDescription,
+) {
+    let mut buf = [0; 1024];
+    while let Ok((len, addr)) = responder.0.recv_from(&mut buf) {
+        let Some(DiscoveryMessage::Probe) = DiscoveryMessage::decode(&buf[..len]) else{continue};
+        let _ = responder.0.send_to(&DiscoveryMessage::Info(description.info()).encode(), addr);
+    }
+}
+
+pub struct LanServer {
+    pub info: ServerInfo,
+    /// Address of the game to connect to
+    pub addr: SocketAddr,
+    /// Real time when it last answered
+    pub seen: f32,
+}
+
+/// Client side, Servers found on the local network, keyed by [`ServerInfo::id`]
+#[derive(Resource, Default)]
+pub struct LanServers {
+    socket: Option<UdpSocket>,
+    timer: Option<Timer>,
+    pub servers: HashMap<u64, LanServer>,
+}
+
+/// Sends probes and collects the answers while the main menu is open
+pub fn discover_servers(
+    mut lan: ResMut<LanServers>,
+    time: Res<Time<Real>>,
+) {
+    let lan = lan.as_mut();
+    if lan.socket.is_none() {
+        let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).and_then(|socket| {
+            socket.set_broadcast(true)?;
+            socket.set_nonblocking(true)?;
+            Ok(socket)
+        });
+        match socket {
+            Ok(socket) => lan.socket = Some(socket),
+            Err(e) => {
+                warn!("LAN discovery disabled: {e}");
+                return
+            },
+        }
+    }
+    let Some(socket) = &lan.socket else{return};
+
+    //the first probe is sent right away
+    let first = lan.timer.is_none();
+    let timer = lan.timer.get_or_insert_with(|| Timer::from_seconds(PROBE_INTERVAL, TimerMode::Repeating));
+    if timer.tick(time.delta()).just_finished() || first {
+        let probe = DiscoveryMessage::Probe.encode();
+        //broadcasts do not always come back to the same machine, so localhost is asked directly
+        for ip in [Ipv4Addr::BROADCAST, Ipv4Addr::LOCALHOST] {
+            let _ = socket.send_to(&probe, (ip, DISCOVERY_PORT));
+        }
+    }
+
+    let now = time.elapsed_secs();
+    let mut buf = [0; 1024];
+    while let Ok((len, addr)) = socket.recv_from(&mut buf) {
+        if let Some(DiscoveryMessage::Info(info)) = DiscoveryMessage::decode(&buf[..len]) {
+            let addr = SocketAddr::new(addr.ip(), info.port);
+            lan.servers.insert(info.id, LanServer { info, addr, seen: now });
+        }
+    }
+    lan.servers.retain(|_, server| now - server.seen < SERVER_TIMEOUT);
+}
+
+/// Closes the socket when leaving the main menu
+pub fn stop_discovery(mut lan: ResMut<LanServers>) {
+    *lan = LanServers::default();
+}
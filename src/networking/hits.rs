@@ -0,0 +1,205 @@
+// Gravishot
+// Copyright (C) 2024 Tomáš Pecl
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+// Server authoritative hit registration with lag compensation.
+//
+// Server: every simulated frame -> path of each bullet in that frame against the hitboxes of the players
+//         rewound by the ping of the shooter -> Hit stored into Rollback<Hits> of that frame -> broadcast Hit(frame, Hit)
+// Everyone: Hit(frame, Hit) -> stored into Rollback<Hits> of that frame, the frames after it are simulated again
+//           -> apply_hits removes the bullet and lowers the Health in the simulation of the next frame
+//
+// The shooter saw the other players where they were about one round trip ago, their later Inputs had not reached it yet,
+// so the hitboxes are taken from the Rollback<PhysicsBundle> history of that older frame.
+// Bullets are checked as soon as the Server simulates their frame. The Clients lead the Server, so the Inputs which
+// spawned the bullets have usually arrived by then, and the Hit reaches the Clients long before they settle the frame
+// and compute its checksum (see checksum). Replays record the frame only after it settles (see crate::replay).
+// Collisions in the simulation only remove bullets, they do not cause damage.
+
+use super::rollback::{PhysicsBundle, Rollback, LEN};
+use super::roster::PlayerRoster;
+use super::traffic::ServerSend;
+use super::ServerMessage;
+use crate::bullet::{Bullet, Shooter};
+use crate::player::{Player, Body, Health};
+use crate::gravity::PHYSICS_TIMESTEP_MS;
+
+use bevy_gravirollback::prelude::*;
+
+use bevy::prelude::*;
+use bevy::utils::HashMap;
+use bevy_quinnet::server::QuinnetServer;
+
+use serde::{Serialize, Deserialize};
+
+/// The hitboxes are rewound by at most this many frames
+pub const MAX_REWIND_FRAMES: u64 = 10;
+
+/// Bullet which hit a player
+#[derive(Reflect, Serialize, Deserialize, Clone, Debug)]
+pub struct Hit {
+    pub bullet: RollbackID,
+    pub shooter: Player,
+    pub target: Player,
+    pub damage: f32,
+}
+
+/// Hits of this frame, they will influence the next frame
+#[derive(Resource, Reflect, Default, Serialize, Deserialize, Clone)]
+#[reflect(Resource)]
+pub struct Hits(pub Vec<Hit>);
+
+/// Server side, which frames and bullets were checked
+#[derive(Resource, Default)]
+pub struct HitRegistration {
+    /// The next frame to check
+    next_frame: Option<u64>,
+    /// Bullets which already hit someone and the frame of the hit
+    registered: HashMap<RollbackID, u64>,
+}
+
+/// Closest distance between the segment from `a` to `a + u` and the segment from `b` to `b + v`,
+/// together with the parameter of the closest point on the first segment
+fn segment_distance(a: Vec3, u: Vec3, b: Vec3, v: Vec3) -> (f32, f32) {
+    let r = a - b;
+    let (uu, vv, vr) = (u.dot(u), v.dot(v), v.dot(r));
+    let (s, t) = if uu <= f32::EPSILON {
+        (0.0, if vv <= f32::EPSILON {0.0}else{(vr / vv).clamp(0.0, 1.0)})
+    }else{
+        let ur = u.dot(r);
+        if vv <= f32::EPSILON {
+            ((-ur / uu).clamp(0.0, 1.0), 0.0)
+        }else{
+            let uv = u.dot(v);
+            let denom = uu * vv - uv * uv;
+            let s = if denom > f32::EPSILON {((uv * vr - ur * vv) / denom).clamp(0.0, 1.0)}else{0.0};
+            let t = (uv * s + vr) / vv;
+            if t < 0.0 {
+                ((-ur / uu).clamp(0.0, 1.0), 0.0)
+            }else if t > 1.0 {
+                (((uv - ur) / uu).clamp(0.0, 1.0), 1.0)
+            }else{
+                (s, t)
+            }
+        }
+    };
+    ((a + u * s).distance(b + v * t), s)
+}
+
+/// Where along the bullet path the bullet hits the player and the damage multiplier of the part, the head first
+fn hit_test(from: Vec3, path: Vec3, body: &Transform) -> Option<(f32, f32)> {
+    use crate::player::{HEIGHT, RADIUS, BODY_DAMAGE, HEAD_DAMAGE};
+    let reach = RADIUS + crate::bullet::RADIUS;
+
+    let head = body.translation + body.rotation * Vec3::new(0.0, HEIGHT, 0.0);
+    let (distance, s) = segment_distance(from, path, head, Vec3::ZERO);
+    if distance <= reach {
+        return Some((s, HEAD_DAMAGE))
+    }
+
+    let half = (HEIGHT - 2.0 * RADIUS) / 2.0;
+    let axis = body.rotation * Vec3::Y * half;
+    let (distance, s) = segment_distance(from, path, body.translation - axis, 2.0 * axis);
+    (distance <= reach).then_some((s, BODY_DAMAGE))
+}
+
+/// Server side, checks the bullets of newly simulated frames against the rewound hitboxes
+pub fn register_hits(
+    mut server: ResMut<QuinnetServer>,
+    mut registration: ResMut<HitRegistration>,
+    mut hits: ResMut<Rollback<Hits>>,
+    mut modified: ResMut<Rollback<Modified>>,
+    bullets: Query<(&RollbackID, &Shooter, &Rollback<PhysicsBundle>, &Rollback<Exists>), With<Bullet>>,
+    targets: Query<(&Player, &Rollback<PhysicsBundle>, &Rollback<Exists>), With<Body>>,
+    roster: Res<PlayerRoster>,
+    frames: Res<Rollback<Frame>>,
+    last_frame: Res<LastFrame>,
+) {
+    let registration = registration.as_mut();
+    let last = last_frame.0;
+    let stored = |frame: u64| frames[index::<LEN>(frame)].0 == frame;
+    let next = registration.next_frame.unwrap_or(last).max(last.saturating_sub(LEN as u64 / 2));
+    registration.next_frame = Some(last + 1);
+    registration.registered.retain(|_, frame| *frame + LEN as u64 > last);
+
+    let dt = PHYSICS_TIMESTEP_MS as f32 / 1000.0;
+    let endpoint = server.endpoint_mut();
+
+    for frame in next.max(1)..=last {
+        let previous = index::<LEN>(frame - 1);
+        if !stored(frame) || !stored(frame - 1) {
+            continue
+        }
+        for (&bullet, &Shooter(shooter), bullet_physics, bullet_exists) in &bullets {
+            if !bullet_exists.0[previous].0 || registration.registered.contains_key(&bullet) {
+                continue
+            }
+            let from = bullet_physics.0[previous].transform.translation;
+            let linvel = bullet_physics.0[previous].velocity.linvel;
+
+            //the shooter saw the others as they were about one round trip ago
+            let ping = roster.0.get(&shooter).map_or(0.0, |info| info.ping_ms);
+            let rewind = ((ping / PHYSICS_TIMESTEP_MS as f32).round() as u64).min(MAX_REWIND_FRAMES);
+            let seen = frame.saturating_sub(rewind);
+            let seen = if stored(seen) {seen}else{frame};
+            let seen_index = index::<LEN>(seen);
+
+            let hit = targets.iter()
+                .filter(|(player, _, exists)| **player != shooter && exists.0[index::<LEN>(frame)].0 && exists.0[seen_index].0)
+                .filter_map(|(&player, physics, _)| {
+                    let target = &physics.0[seen_index];
+                    let relative = linvel - target.velocity.linvel;
+                    let (s, multiplier) = hit_test(from, relative * dt, &target.transform)?;
+                    Some((s, player, multiplier * relative.length()))
+                })
+                .min_by(|a, b| a.0.total_cmp(&b.0));
+
+            let Some((_, target, damage)) = hit else{continue};
+            let hit = Hit {
+                bullet,
+                shooter,
+                target,
+                damage,
+            };
+            println!("bullet {bullet:?} of player {shooter:?} hit player {target:?} in frame {frame} (rewound to {seen}), damage {damage}");
+            registration.registered.insert(bullet, frame);
+            //the hit happened in that frame, the following ones are simulated again with it
+            let index = index::<LEN>(frame);
+            hits.0[index].0.push(hit.clone());
+            modified[index].0 |= frame < last;
+            endpoint.broadcast(ServerMessage::Hit(Frame(frame), hit));
+        }
+    }
+}
+
+/// Client side, handles [`ServerMessage::Hit`], the frames after the hit get simulated again
+pub fn receive_hit(frame: Frame, hit: Hit) -> impl FnOnce(&mut World) {
+    move |world: &mut World| {
+        let last_frame = world.resource::<LastFrame>().0;
+        let index = index::<LEN>(frame.0);
+        if frame.0 > last_frame || world.resource::<Rollback<Frame>>()[index].0 != frame.0 {
+            warn!("hit of player {:?} in {frame:?} can not be applied, last frame {last_frame}", hit.target);
+            return
+        }
+        world.resource_mut::<Rollback<Hits>>().0[index].0.push(hit);
+        world.resource_mut::<Rollback<Modified>>()[index].0 |= frame.0 < last_frame;
+    }
+}
+
+/// Applies the hits in the simulation, runs in RollbackUpdate
+pub fn apply_hits(
+    hits: Res<Hits>,
+    rollback_map: Res<RollbackMap>,
+    mut bullets: Query<&mut Exists, With<Bullet>>,
+    mut players: Query<(&Player, &mut Health), With<Body>>,
+) {
+    for hit in &hits.0 {
+        if let Some(mut exists) = rollback_map.0.get(&hit.bullet).and_then(|&entity| bullets.get_mut(entity).ok()) {
+            exists.0 = false;
+        }
+        if let Some((_, mut health)) = players.iter_mut().find(|(player, _)| **player == hit.target) {
+            health.0 -= hit.damage;
+        }
+    }
+}
@@ -0,0 +1,191 @@
+// Gravishot
+// Copyright (C) 2024 Tomáš Pecl
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+// Interest management, every Client gets only the entities relevant to its player.
+//
+// Server: summary States -> relevance of every entity for the Client -> StateSummary with only the relevant ones
+// Server: relays Inputs of a player only to the Clients for which the player is fully relevant
+//
+// Relevance is decided by the distance from the player of the Client and optionally by line of sight past asteroids:
+// - own entities and entities within the distance are Full, they are in every summary
+// - distant players and their guns are Low, their States are sent every LOW_RATE_SUMMARIES summary,
+//   in the other summaries they are listed as stale, the Client keeps them but does not update them
+// - distant bullets and players hidden behind asteroids are not sent at all, the Client gets Despawn for them (see replication)
+// Clients without a player (spectators, dead players) get everything.
+//
+// Filtered Clients do not simulate the whole world, so their checksums are not compared.
+
+use super::EntityType;
+use super::delta::EntityStates;
+use super::session::Sessions;
+use crate::input::Inputs;
+use crate::player::Player;
+use crate::map::asteroid::AsteroidMarker;
+
+use bevy_gravirollback::prelude::*;
+
+use bevy::prelude::*;
+use bevy::ecs::system::SystemParam;
+use bevy::utils::{HashMap, HashSet};
+use bevy_rapier3d::prelude::*;
+
+/// Every this many summaries the Low relevance entities get updated
+pub const LOW_RATE_SUMMARIES: u64 = 4;
+
+#[derive(Resource, Reflect, Default, Clone, Debug)]
+#[reflect(Resource)]
+pub struct InterestConfig {
+    /// Entities further from the player than this are sent at a low rate or not at all, None disables interest management
+    pub distance: Option<f32>,
+    /// Players hidden behind asteroids are not sent
+    pub line_of_sight: bool,
+}
+
+impl InterestConfig {
+    pub fn is_active(&self) -> bool {
+        self.distance.is_some()
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Relevance {
+    Full,
+    Low,
+    Hidden,
+}
+
+/// Server side, what the Client currently gets
+#[derive(Default)]
+pub struct ClientInterest {
+    /// Players whose Inputs are relayed to the Client
+    pub players: HashSet<Player>,
+    /// Number of summaries sent to the Client
+    summaries: u64,
+}
+
+/// Server side, Clients without an entry get everything
+#[derive(Resource, Default)]
+pub struct Interest(pub HashMap<u64, ClientInterest>);
+
+impl Interest {
+    /// Should Inputs of the player be relayed to the Client
+    pub fn relays(&self, client_id: u64, player: Player) -> bool {
+        self.0.get(&client_id).map_or(true, |interest| interest.players.contains(&player))
+    }
+}
+
+/// Relevance of everything for one Client
+pub struct Relevances {
+    pub players: HashMap<Player, Relevance>,
+    pub entities: HashMap<RollbackID, Relevance>,
+}
+
+impl Relevances {
+    pub fn entity(&self, id: RollbackID) -> Relevance {
+        self.entities.get(&id).copied().unwrap_or(Relevance::Hidden)
+    }
+}
+
+/// What one Client gets in a summary
+pub struct Filtered {
+    pub states: EntityStates,
+    pub inputs: Inputs,
+    /// Entities which are kept from the baseline without an update
+    pub stale: Vec<RollbackID>,
+}
+
+#[derive(SystemParam)]
+pub struct InterestFilter<'w, 's> {
+    pub config: Res<'w, InterestConfig>,
+    pub interest: ResMut<'w, Interest>,
+    sessions: Res<'w, Sessions>,
+    context: ReadDefaultRapierContext<'w, 's>,
+    asteroids: Query<'w, 's, (), With<AsteroidMarker>>,
+}
+
+impl InterestFilter<'_, '_> {
+    fn visible(&self, from: Vec3, to: Vec3) -> bool {
+        let Ok(dir) = Dir3::new(to - from) else{return true};
+        let distance = from.distance(to);
+        let asteroids = |entity| self.asteroids.contains(entity);
+        let filter = QueryFilter::new().predicate(&asteroids);
+        self.context.cast_ray(from, *dir, distance, true, filter).is_none()
+    }
+
+    /// Relevance of the players and of every entity for the Client, None when the Client gets everything
+    pub fn relevance(&self, client_id: u64, states: &EntityStates) -> Option<Relevances> {
+        let distance = self.config.distance?;
+        let viewer = self.sessions.player(client_id)?;
+        let eye = states.values()
+            .find(|state| state.2 == Some(viewer) && state.3 == EntityType::Player && state.4.0)
+            .map(|state| state.0.transform.translation)?;
+
+        let mut players = HashMap::new();
+        for state in states.values().filter(|state| state.3 == EntityType::Player && state.4.0) {
+            let Some(player) = state.2 else{continue};
+            let position = state.0.transform.translation;
+            let relevance = if player == viewer {
+                Relevance::Full
+            }else if self.config.line_of_sight && !self.visible(eye, position) {
+                Relevance::Hidden
+            }else if eye.distance(position) <= distance {
+                Relevance::Full
+            }else{
+                Relevance::Low
+            };
+            players.insert(player, relevance);
+        }
+
+        let entities = states.iter().map(|(id, state)| {
+            let near = eye.distance(state.0.transform.translation) <= distance;
+            let relevance = match (state.3, state.2.and_then(|player| players.get(&player))) {
+                //own bullets too, the Client must not despawn them when they fly far
+                _ if state.2 == Some(viewer) => Relevance::Full,
+                (EntityType::Player | EntityType::Gun, Some(relevance)) => *relevance,
+                _ => if near {Relevance::Full}else{Relevance::Hidden},
+            };
+            (*id, relevance)
+        }).collect();
+        Some(Relevances { players, entities })
+    }
+
+    /// Selects the States and Inputs the Client gets in the summary, `baseline` are the States it acknowledged.
+    /// Also updates which players are relevant to the Client.
+    pub fn filter(&mut self, client_id: u64, states: &EntityStates, inputs: &Inputs, baseline: Option<&EntityStates>) -> Filtered {
+        let Some(relevances) = self.relevance(client_id, states) else{
+            self.interest.0.remove(&client_id);
+            return Filtered {
+                states: states.clone(),
+                inputs: inputs.clone(),
+                stale: Vec::new(),
+            }
+        };
+
+        let interest = self.interest.0.entry(client_id).or_default();
+        let update_low = interest.summaries % LOW_RATE_SUMMARIES == 0;
+        interest.summaries += 1;
+        interest.players = relevances.players.iter().filter(|(_, relevance)| **relevance == Relevance::Full).map(|(player, _)| *player).collect();
+
+        let mut filtered = Filtered {
+            states: EntityStates::default(),
+            inputs: Inputs(inputs.0.iter().filter(|(player, _)| interest.players.contains(*player)).map(|(player, input)| (*player, input.clone())).collect()),
+            stale: Vec::new(),
+        };
+        for (id, state) in states {
+            match relevances.entity(*id) {
+                Relevance::Full => {filtered.states.insert(*id, state.clone());},
+                Relevance::Low => match baseline.and_then(|baseline| baseline.get(id)) {
+                    Some(base) if !update_low => {
+                        filtered.states.insert(*id, base.clone());
+                        filtered.stale.push(*id);
+                    },
+                    _ => {filtered.states.insert(*id, state.clone());},
+                },
+                Relevance::Hidden => (),
+            }
+        }
+        filtered
+    }
+}
@@ -0,0 +1,208 @@
+// Gravishot
+// Copyright (C) 2024 Tomáš Pecl
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+// Registering with the master server and browsing its list, see crate::master_protocol for the messages.
+//
+// Server: when MasterConfig::register is set, sends Heartbeat every HEARTBEAT_INTERVAL
+// Client: while the main menu is open asks the master server for the list every LIST_INTERVAL,
+//         it requests the next page right after receiving one
+// Client: the address of the master server is resolved only when the list is refreshed, DNS lookups block
+// Client: measures the ping of every listed Server with a discovery Probe, the Server answers it on DISCOVERY_PORT
+
+use super::discovery::{ServerDescription, ServerInfo, DiscoveryMessage, DISCOVERY_PORT};
+use crate::master_protocol::{MasterMessage, DEFAULT_MASTER_PORT, HEARTBEAT_INTERVAL};
+
+use bevy::prelude::*;
+use bevy::utils::HashMap;
+
+use std::net::{UdpSocket, SocketAddr, Ipv4Addr, ToSocketAddrs};
+
+/// Seconds between requests for the list of Servers
+pub const LIST_INTERVAL: f32 = 5.0;
+
+#[derive(Resource, Reflect, Clone, Debug)]
+#[reflect(Resource)]
+pub struct MasterConfig {
+    /// Address of the master server as host:port
+    pub addr: String,
+    /// Server side, the Server registers itself only when this is set
+    pub register: bool,
+}
+
+impl Default for MasterConfig {
+    fn default() -> Self {
+        Self {
+            addr: format!("localhost:{DEFAULT_MASTER_PORT}"),
+            register: false,
+        }
+    }
+}
+
+fn open_socket() -> Result<UdpSocket, String> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).map_err(|e| format!("could not open socket: {e}"))?;
+    socket.set_nonblocking(true).map_err(|e| format!("could not open socket: {e}"))?;
+    Ok(socket)
+}
+
+fn resolve(addr: &str) -> Result<SocketAddr, String> {
+    addr.to_socket_addrs().ok()
+        .and_then(|mut addrs| addrs.next())
+        .ok_or_else(|| format!("unknown master server address {addr}"))
+}
+
+pub fn registering(config: Res<MasterConfig>) -> bool {
+    config.register
+}
+
+/// Server side, tells the master server that we are running
+pub fn heartbeat(
+    config: Res<MasterConfig>,
+    description: ServerDescription,
+    time: Res<Time<Real>>,
+    mut timer: Local<Option<Timer>>,
+    mut socket: Local<Option<UdpSocket>>,
+) {
+    //the first heartbeat is sent right away
+    let first = timer.is_none();
+    let timer = timer.get_or_insert_with(|| Timer::from_seconds(HEARTBEAT_INTERVAL, TimerMode::Repeating));
+    if !timer.tick(time.delta()).just_finished() && !first {
+        return
+    }
+
+    if socket.is_none() {
+        match open_socket() {
+            Ok(new) => *socket = Some(new),
+            Err(e) => {
+                warn!("{e}");
+                return
+            },
+        }
+    }
+    let Some(socket) = socket.as_ref() else{return};
+
+    //resolved every time, the address of the master server can change
+    match resolve(&config.addr) {
+        Ok(addr) => {
+            if first {
+                println!("registering with master server {addr}");
+            }
+            let _ = socket.send_to(&MasterMessage::Heartbeat(description.info()).encode(), addr);
+        },
+        Err(e) => warn!("{e}"),
+    }
+}
+
+pub struct BrowsedServer {
+    pub info: ServerInfo,
+    /// Address of the game to connect to
+    pub addr: SocketAddr,
+    /// Round trip time of the last answered discovery probe
+    pub ping_ms: Option<f32>,
+    /// Real time when the probe was sent
+    probe_sent: f32,
+    /// Real time when the master server last listed it
+    seen: f32,
+}
+
+/// Client side, Servers listed by the master server, keyed by the address of the game
+#[derive(Resource, Default)]
+pub struct MasterServers {
+    socket: Option<UdpSocket>,
+    timer: Option<Timer>,
+    /// Resolved address of the master server
+    master_addr: Option<SocketAddr>,
+    pub servers: HashMap<SocketAddr, BrowsedServer>,
+    /// Why the list could not be requested
+    pub error: Option<String>,
+}
+
+impl MasterServers {
+    /// Resolves the address of the master server and requests the list again right away
+    pub fn refresh(&mut self) {
+        self.timer = None;
+        self.master_addr = None;
+        self.error = None;
+        self.servers.clear();
+    }
+}
+
+fn send_probe(socket: &UdpSocket, server: &mut BrowsedServer, now: f32) {
+    server.probe_sent = now;
+    let _ = socket.send_to(&DiscoveryMessage::Probe.encode(), (server.addr.ip(), DISCOVERY_PORT));
+}
+
+/// Requests the list of Servers and measures their pings while the main menu is open
+pub fn browse_servers(
+    mut master: ResMut<MasterServers>,
+    config: Res<MasterConfig>,
+    time: Res<Time<Real>>,
+) {
+    let master = master.as_mut();
+    if master.socket.is_none() {
+        match open_socket() {
+            Ok(socket) => master.socket = Some(socket),
+            Err(e) => {
+                master.error = Some(e);
+                return
+            },
+        }
+    }
+    let Some(socket) = &master.socket else{return};
+    let now = time.elapsed_secs();
+
+    let first = master.timer.is_none();
+    let timer = master.timer.get_or_insert_with(|| Timer::from_seconds(LIST_INTERVAL, TimerMode::Repeating));
+    if timer.tick(time.delta()).just_finished() || first {
+        //after a failed lookup it is tried again only on refresh
+        if master.master_addr.is_none() && master.error.is_none() {
+            match resolve(&config.addr) {
+                Ok(addr) => master.master_addr = Some(addr),
+                Err(e) => master.error = Some(e),
+            }
+        }
+        if let Some(addr) = master.master_addr {
+            let _ = socket.send_to(&MasterMessage::list(None), addr);
+        }
+        for server in master.servers.values_mut() {
+            send_probe(socket, server, now);
+        }
+    }
+
+    let mut buf = [0; 2048];
+    while let Ok((len, from)) = socket.recv_from(&mut buf) {
+        let data = &buf[..len];
+        if let Some(MasterMessage::Servers(page)) = MasterMessage::decode(data).filter(|_| Some(from) == master.master_addr) {
+            if let Some(&(last, _)) = page.last() {
+                let _ = socket.send_to(&MasterMessage::list(Some(last)), from);
+            }
+            for (addr, info) in page {
+                match master.servers.get_mut(&addr) {
+                    Some(server) => {
+                        server.info = info;
+                        server.seen = now;
+                    },
+                    None => {
+                        let mut server = BrowsedServer { info, addr, ping_ms: None, probe_sent: now, seen: now };
+                        send_probe(socket, &mut server, now);
+                        master.servers.insert(addr, server);
+                    },
+                }
+            }
+        }else if let Some(DiscoveryMessage::Info(info)) = DiscoveryMessage::decode(data) {
+            let server = master.servers.values_mut().find(|server| server.info.id == info.id && server.addr.ip() == from.ip());
+            if let Some(server) = server {
+                server.ping_ms = Some((now - server.probe_sent) * 1000.0);
+            }
+        }
+    }
+
+    //the master server stopped listing it
+    master.servers.retain(|_, server| now - server.seen < 2.0 * LIST_INTERVAL);
+}
+
+/// Closes the socket when leaving the main menu
+pub fn stop_browsing(mut master: ResMut<MasterServers>) {
+    *master = MasterServers::default();
+}
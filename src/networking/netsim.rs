@@ -0,0 +1,161 @@
+// Gravishot
+// Copyright (C) 2024 Tomáš Pecl
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+// Simulation of a bad network, for testing on loopback where the latency is zero.
+//
+// Received messages wait in a DelayQueue until the simulated network would deliver them, only then
+// client::handle and server::handle see them. Every process simulates what it receives:
+// the conditions set on the Server affect the Client -> Server direction,
+// the conditions set on the Client affect the Server -> Client direction.
+//
+// The channels keep their guarantees: only messages on the unreliable channel get lost,
+// messages on the ordered channel are never reordered, they wait for the ones before them.
+
+use super::{ClientMessage, ServerMessage};
+
+use bevy::prelude::*;
+use bevy::ecs::system::SystemParam;
+use bevy::utils::HashMap;
+use bevy_quinnet::client::QuinnetClient;
+use bevy_quinnet::server::Endpoint;
+use bevy_quinnet::shared::channels::ChannelId;
+
+use rand::Rng;
+
+use std::collections::VecDeque;
+use std::time::Duration;
+
+/// Channels as configured in [`super::server::start`] and [`super::client::connect`]
+const ORDERED_CHANNEL: ChannelId = 0;
+const UNRELIABLE_CHANNEL: ChannelId = 2;
+/// Extra delay of a message which gets reordered
+const REORDER_DELAY_MS: f32 = 50.0;
+
+/// Conditions of the simulated network for the messages this process receives, all zero means no simulation
+#[derive(Resource, Reflect, Default, Clone, Debug)]
+#[reflect(Resource)]
+pub struct NetworkConditions {
+    /// One way delay
+    pub delay_ms: f32,
+    /// The delay of each message is randomly changed by up to this much
+    pub jitter_ms: f32,
+    /// Probability that a message on the unreliable channel gets lost, 0 to 1
+    pub loss: f32,
+    /// Probability that a message on an unordered channel arrives after the ones sent after it, 0 to 1
+    pub reorder: f32,
+}
+
+impl NetworkConditions {
+    pub fn is_active(&self) -> bool {
+        self.delay_ms > 0.0 || self.jitter_ms > 0.0 || self.loss > 0.0 || self.reorder > 0.0
+    }
+
+    /// When the message should be delivered, None when it gets lost
+    fn delivery(&self, channel: ChannelId, now: Duration) -> Option<Duration> {
+        let mut rng = rand::thread_rng();
+        if channel == UNRELIABLE_CHANNEL && rng.gen_bool(self.loss.clamp(0.0, 1.0) as f64) {
+            return None
+        }
+        let mut delay = self.delay_ms;
+        if self.jitter_ms > 0.0 {
+            delay += rng.gen_range(-self.jitter_ms..=self.jitter_ms);
+        }
+        if channel != ORDERED_CHANNEL && rng.gen_bool(self.reorder.clamp(0.0, 1.0) as f64) {
+            delay += REORDER_DELAY_MS;
+        }
+        Some(now + Duration::from_secs_f32(delay.max(0.0) / 1000.0))
+    }
+}
+
+/// Messages received from one connection waiting for their simulated delivery, the earliest first
+pub struct DelayQueue<T> {
+    queue: VecDeque<(Duration, ChannelId, T)>,
+    /// Delivery time of the last message on the ordered channel
+    last_ordered: Duration,
+}
+
+impl<T> Default for DelayQueue<T> {
+    fn default() -> Self {
+        Self {
+            queue: VecDeque::new(),
+            last_ordered: Duration::ZERO,
+        }
+    }
+}
+
+impl<T> DelayQueue<T> {
+    pub fn push(&mut self, channel: ChannelId, message: T, conditions: &NetworkConditions, now: Duration) {
+        let Some(mut delivery) = conditions.delivery(channel, now) else{return};
+        if channel == ORDERED_CHANNEL {
+            delivery = delivery.max(self.last_ordered);
+            self.last_ordered = delivery;
+        }
+        //after all messages with the same time, so messages without delay keep their order
+        let index = self.queue.partition_point(|(time, ..)| *time <= delivery);
+        self.queue.insert(index, (delivery, channel, message));
+    }
+
+    /// The next message which is already delivered
+    pub fn pop(&mut self, now: Duration) -> Option<(ChannelId, T)> {
+        if self.queue.front()?.0 <= now {
+            self.queue.pop_front().map(|(_, channel, message)| (channel, message))
+        }else{None}
+    }
+}
+
+/// Client side, messages from the Server
+#[derive(Resource, Default)]
+pub struct ClientInbox(pub DelayQueue<ServerMessage>);
+
+/// Server side, messages from each Client
+#[derive(Resource, Default)]
+pub struct ServerInbox(pub HashMap<u64, DelayQueue<ClientMessage>>);
+
+/// Receives messages through the simulated network, use it instead of receiving from [`QuinnetClient`] directly
+#[derive(SystemParam)]
+pub struct ClientReceiver<'w> {
+    conditions: Res<'w, NetworkConditions>,
+    inbox: ResMut<'w, ClientInbox>,
+}
+
+impl ClientReceiver<'_> {
+    pub fn receive(&mut self, client: &mut QuinnetClient) -> Option<(ChannelId, ServerMessage)> {
+        let now = super::clock::now();
+        while let Some((channel, message)) = client.connection_mut().try_receive_message::<ServerMessage>() {
+            super::traffic::TRAFFIC.received_from_server(&message);
+            self.inbox.0.push(channel, message, &self.conditions, now);
+        }
+        self.inbox.0.pop(now)
+    }
+
+    /// Forgets messages of the old connection
+    pub fn clear(&mut self) {
+        self.inbox.0 = DelayQueue::default();
+    }
+}
+
+/// Receives messages through the simulated network, use it instead of receiving from [`Endpoint`] directly
+#[derive(SystemParam)]
+pub struct ServerReceiver<'w> {
+    conditions: Res<'w, NetworkConditions>,
+    inbox: ResMut<'w, ServerInbox>,
+}
+
+impl ServerReceiver<'_> {
+    pub fn receive(&mut self, endpoint: &mut Endpoint, client_id: u64) -> Option<(ChannelId, ClientMessage)> {
+        let now = super::clock::now();
+        let queue = self.inbox.0.entry(client_id).or_default();
+        while let Some((channel, message)) = endpoint.try_receive_message_from::<ClientMessage>(client_id) {
+            super::traffic::TRAFFIC.received_from_client(&message);
+            queue.push(channel, message, &self.conditions, now);
+        }
+        queue.pop(now)
+    }
+
+    /// Forgets messages of a closed connection
+    pub fn remove(&mut self, client_id: u64) {
+        self.inbox.0.remove(&client_id);
+    }
+}
@@ -0,0 +1,80 @@
+// Gravishot
+// Copyright (C) 2024 Tomáš Pecl
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+// Explicit replication of the entity lifetimes.
+//
+// Server: every update, the settled frame -> entities existing in it and relevant to the Client (see interest)
+//         -> Spawn(frame, id, EntityType, State) for the new ones, Despawn(frame, id) for the ones which are gone
+// Client: Spawn -> the entity exists from that frame, it is spawned when we do not have it yet
+//         Despawn -> the entity does not exist from that frame
+// Both are applied through the rollback, the frames after them are simulated again.
+//
+// Summaries only contain the entities the Client was told to spawn, they reconcile the States and never
+// create or destroy entities. A summary can not tell when an entity appeared, so spawning from it could
+// spawn an entity before its spawn frame and then a second time when the simulation reaches that frame.
+
+use super::rollback::{StateQuery, LEN, SETTLED_FRAMES, collect_states};
+use super::interest::{InterestFilter, Relevance};
+use super::session::Sessions;
+use super::traffic::ServerSend;
+use super::wire::Wire;
+use super::ServerMessage;
+
+use bevy_gravirollback::prelude::*;
+
+use bevy::prelude::*;
+use bevy::utils::{HashMap, HashSet};
+use bevy_quinnet::server::QuinnetServer;
+
+/// Server side, the entities each Client was told to spawn and not yet told to despawn
+#[derive(Resource, Default)]
+pub struct Replicated(pub HashMap<u64, HashSet<RollbackID>>);
+
+impl Replicated {
+    /// Does the Client know about the entity
+    pub fn knows(&self, client_id: u64, id: &RollbackID) -> bool {
+        self.0.get(&client_id).is_some_and(|known| known.contains(id))
+    }
+}
+
+/// Server side, sends Spawn and Despawn of the settled frame, runs before the summary is sent
+pub fn replicate(
+    mut server: ResMut<QuinnetServer>,
+    mut replicated: ResMut<Replicated>,
+    interest: InterestFilter,
+    sessions: Res<Sessions>,
+    query: StateQuery,
+    frames: Res<Rollback<Frame>>,
+    last_frame: Res<LastFrame>,
+) {
+    let Some(frame) = last_frame.0.checked_sub(SETTLED_FRAMES) else{return};
+    let index = index::<LEN>(frame);
+    if frames[index].0 != frame {
+        return
+    }
+    let mut states = collect_states(&query, index);
+    states.retain(|_, state| state.4.0);
+
+    let endpoint = server.endpoint_mut();
+    //Clients which did not join yet get everything after ConnectionGranted
+    for client_id in endpoint.clients().into_iter().filter(|&client_id| sessions.player(client_id).is_some()) {
+        let relevances = interest.relevance(client_id, &states);
+        let relevant = |id: RollbackID| relevances.as_ref().map_or(true, |relevances| relevances.entity(id) != Relevance::Hidden);
+        let known = replicated.0.entry(client_id).or_default();
+
+        known.retain(|&id| {
+            let keep = states.contains_key(&id) && relevant(id);
+            if !keep {
+                endpoint.send(client_id, ServerMessage::Despawn { frame: Frame(frame), id });
+            }
+            keep
+        });
+        for (&id, state) in &states {
+            if relevant(id) && known.insert(id) {
+                endpoint.send(client_id, ServerMessage::Spawn { frame: Frame(frame), id, entity_type: state.3, state: Wire(state.clone()) });
+            }
+        }
+    }
+}
@@ -15,7 +15,7 @@ use bevy::prelude::*;
 use bevy_rapier3d::prelude::*;
 
 use bevy::ecs::system::StaticSystemParam;
-use bevy::utils::HashMap;
+use bevy::utils::{HashMap, HashSet};
 use bevy::ecs::query::WorldQuery;
 use serde::{Serialize, Deserialize};
 
@@ -23,6 +23,8 @@ use std::sync::atomic::{AtomicU64, Ordering};
 use std::time::Duration;
 
 pub const LEN: usize = 128;
+/// Frames this much older than [`LastFrame`] are not likely to change anymore, they are used for summaries and replays
+pub const SETTLED_FRAMES: u64 = LEN as u64 / 4;
 
 pub type Rollback<T> = bevy_gravirollback::Rollback<T,LEN>;
 
@@ -34,12 +36,19 @@ impl RollbackIdCounter {
     }
 }
 
-#[derive(Bundle, Reflect, Serialize, Deserialize, Default, Clone, Debug)]
+#[derive(Bundle, Reflect, Serialize, Deserialize, Default, Clone, PartialEq, Debug)]
 pub struct PhysicsBundle {
     pub transform: Transform,
     pub velocity: Velocity,
 }
 
+impl PhysicsBundle {
+    /// Compares the values as they would be seen after sending them over the network
+    pub fn wire_eq(&self, other: &PhysicsBundle) -> bool {
+        super::wire::transform_eq(&self.transform, &other.transform) && super::wire::velocity_eq(&self.velocity, &other.velocity)
+    }
+}
+
 impl RollbackCapable for PhysicsBundle {
     type RestoreQuery<'a> = (&'a mut Transform, &'a mut Velocity);
     type RestoreExtraParam<'a> = ();
@@ -68,6 +77,24 @@ pub struct States {
     pub frame_0_time: Duration,
 }
 
+/// Rollback entities with everything needed to build their [`State`]
+pub type StateQuery<'w, 's> = Query<'w, 's, (&'static RollbackID, &'static Rollback<Exists>, &'static Rollback<PhysicsBundle>, Option<(&'static Rollback<HeadData>, &'static Rollback<Health>)>, Option<&'static crate::player::Player>, Option<&'static crate::bullet::Shooter>, &'static EntityType)>;
+
+/// States of all Rollback entities in the frame stored at this index
+pub fn collect_states(query: &StateQuery, index: usize) -> HashMap<RollbackID, State> {
+    let mut states = HashMap::new();
+    for (&id, exists, physics_bundle, player_data, player, shooter, &entity_type) in query {
+        //entities spawned after this frame are collected too, they do not exist in it
+        let exists = exists.0[index];
+
+        let player_data = player_data.map(|x| (x.0.0[index].clone(),x.1.0[index].clone()));
+        //bullets belong to the player who shot them
+        let player = player.copied().or(shooter.map(|shooter| shooter.0));
+        states.insert(id, State(physics_bundle.0[index].clone(), player_data, player, entity_type, exists));
+    }
+    states
+}
+
 /// Snapshot of one game frame
 #[derive(Serialize, Deserialize, Clone)]
 pub struct Snapshot {
@@ -82,6 +109,16 @@ pub struct UpdateStateEvent<S> {
     pub frame: Frame,
     pub id: RollbackID,
     pub state: S,
+    /// The entity exists from this frame on, it gets spawned when it is not known.
+    /// Without it only known entities are updated.
+    pub spawn: bool,
+}
+
+/// The entity does not exist from this frame on
+#[derive(Event)]
+pub struct DespawnEvent {
+    pub frame: Frame,
+    pub id: RollbackID,
 }
 
 //pub struct FutureUpdates(Vec<>);
@@ -92,26 +129,37 @@ pub fn handle_update_state_event(
     frames: Res<Rollback<Frame>>,
     mut modified: ResMut<Rollback<Modified>>,
     rollback_map: Res<RollbackMap>,
-    mut query: Query<(&mut Rollback<PhysicsBundle>, Option<(&mut Rollback<HeadData>, &mut Rollback<Health>)>)>,
+    mut query: Query<(&mut Rollback<PhysicsBundle>, Option<(&mut Rollback<HeadData>, &mut Rollback<Health>)>, &mut Rollback<Exists>)>,
     mut commands: Commands,
+    mut counters: ResMut<super::diagnostics::RollbackCounters>,
 ) {
-    for UpdateStateEvent { frame, id, state } in events.read() {
+    //entities spawned here get into the RollbackMap only after the commands are applied
+    let mut spawned = HashSet::new();
+    for UpdateStateEvent { frame, id, state, spawn } in events.read() {
         let frame = frame;
         let update = frame.0 < last_frame.0;
         if frame.0 > last_frame.0 {
             warn!("future update event {frame:?} {last_frame:?}");
+            counters.future_states += 1;
             //TODO: resend them like in handle_update_input_event
             continue;
         }
 
         let index = index::<LEN>(frame.0);
-        if frames[index].0 == frame.0 {
-            //insert this state
-            modified[index].0 |= update;
-            //println!("update_state_event id {id:?}");
-            if let Some(&entity) = rollback_map.0.get(id) {
-                //println!("update_state_event updating");
-                let (mut physics_bundle, player_data) = query.get_mut(entity).expect("this entity should exist");
+        let stored = frames[index].0 == frame.0;
+        match rollback_map.0.get(id) {
+            Some(&entity) if stored => {
+                let (mut physics_bundle, player_data, mut exists) = query.get_mut(entity).expect("this entity should exist");
+                //a Client in sync keeps its exact values, otherwise its checksums would not match the Server
+                if !*spawn {
+                    let stored_player_data = player_data.as_ref().map(|(head_data, health)| (head_data.0[index].clone(), health.0[index]));
+                    if physics_bundle.0[index].wire_eq(&state.0) && super::delta::player_data_wire_eq(&stored_player_data, &state.1) {
+                        continue
+                    }
+                }
+                //insert this state
+                modified[index].0 |= update;
+                //println!("update_state_event id {id:?}");
                 physics_bundle.0[index].transform = state.0.transform;
                 physics_bundle.0[index].velocity = state.0.velocity;
                 if let Some(mut player_data) = player_data {
@@ -121,7 +169,24 @@ pub fn handle_update_state_event(
                 }else{
                     assert!(state.1.is_none());
                 }
-            }else{
+                if *spawn {
+                    exists.0[index] = state.4;
+                }
+            },
+            Some(_) => {
+                //too old frame
+                println!("update_state_event too old frame");
+                counters.too_old_states += 1;
+            },
+            None if *spawn && spawned.insert(*id) => {
+                //a Client which just connected does not have the frame of the spawn, the entity appears in its last frame
+                let index = if stored {
+                    modified[index].0 |= update;
+                    index
+                }else{
+                    counters.too_old_states += 1;
+                    index::<LEN>(last_frame.0)
+                };
                 println!("update_state_event spawning id {id:?}");
                 let player = state.2;
                 match state.3 {
@@ -149,12 +214,47 @@ pub fn handle_update_state_event(
                         transform: state.0.transform,
                         velocity: state.0.velocity,
                         index: Some(index),
+                        shooter: player,
                     }))),
                 }
+            },
+            //only Spawn creates entities, see super::replication
+            None => (),
+        }
+    }
+}
+
+pub fn handle_despawn_event(
+    mut events: EventReader<DespawnEvent>,
+    last_frame: Res<LastFrame>,
+    frames: Res<Rollback<Frame>>,
+    mut modified: ResMut<Rollback<Modified>>,
+    rollback_map: Res<RollbackMap>,
+    mut query: Query<&mut Rollback<Exists>>,
+    mut commands: Commands,
+    mut counters: ResMut<super::diagnostics::RollbackCounters>,
+) {
+    for DespawnEvent { frame, id } in events.read() {
+        let Some(&entity) = rollback_map.0.get(id) else{continue};
+        //we did not simulate the frame yet, the entity disappears in our last frame
+        let frame = if frame.0 > last_frame.0 {
+            counters.future_states += 1;
+            last_frame.0
+        }else{
+            frame.0
+        };
+
+        let index = index::<LEN>(frame);
+        if frames[index].0 == frame {
+            modified[index].0 |= frame < last_frame.0;
+            if let Ok(mut exists) = query.get_mut(entity) {
+                exists.0[index].0 = false;
             }
         }else{
-            //too old frame
-            println!("update_state_event too old frame");
+            //too old frame, the entity is gone in all frames we have
+            println!("despawn_event too old frame, despawning {id:?}");
+            counters.too_old_states += 1;
+            commands.entity(entity).despawn_recursive();
         }
     }
 }
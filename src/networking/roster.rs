@@ -0,0 +1,112 @@
+// Gravishot
+// Copyright (C) 2024 Tomáš Pecl
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use super::ServerMessage;
+use super::traffic::ServerSend;
+use crate::player::Player;
+
+use bevy::prelude::*;
+use bevy::utils::HashMap;
+use bevy_quinnet::server::QuinnetServer;
+
+use serde::{Serialize, Deserialize};
+
+use std::time::Duration;
+
+/// How often the Server sends the whole [`PlayerRoster`] to refresh the pings
+pub const ROSTER_INTERVAL: f32 = 5.0;
+
+/// Everything other players need to know about a player, stored in [`PlayerRoster`]
+#[derive(Reflect, Serialize, Deserialize, Clone, Debug)]
+pub struct PlayerInfo {
+    pub name: String,
+    /// sRGB color used to label the player
+    pub color: [u8; 3],
+    /// Round trip time reported by the Client
+    pub ping_ms: f32,
+    pub team: Option<u8>,
+    /// Server time when the player joined
+    pub joined: Duration,
+    pub state: ConnectionState,
+    /// Spectators have no body and can not spawn, see [`super::spectator`]
+    pub spectator: bool,
+}
+
+#[derive(Reflect, Serialize, Deserialize, PartialEq, Eq, Clone, Copy, Debug)]
+pub enum ConnectionState {
+    Connected,
+    /// The connection was lost, the player stays in the game for a while waiting for the Client to come back
+    Reconnecting,
+}
+
+impl PlayerInfo {
+    pub fn new(player: Player, name: String, joined: Duration) -> Self {
+        Self {
+            name,
+            color: player_color(player),
+            ping_ms: 0.0,
+            team: None,
+            joined,
+            state: ConnectionState::Connected,
+            spectator: false,
+        }
+    }
+
+    pub fn color(&self) -> Color {
+        let [r, g, b] = self.color;
+        Color::srgb_u8(r, g, b)
+    }
+}
+
+/// Gives every player a different color, consecutive players get hues far apart
+pub fn player_color(player: Player) -> [u8; 3] {
+    let hue = (player.0 as f32 * 137.508) % 360.0;
+    Color::hsl(hue, 0.8, 0.6).to_srgba().to_u8_array_no_alpha()
+}
+
+/// All players in the game. The Server owns it and replicates it to the Clients with
+/// [`ServerMessage::Roster`], [`ServerMessage::Connected`] and [`ServerMessage::Disconnected`].
+#[derive(Resource, Reflect, Serialize, Deserialize, Default, Clone, Debug)]
+#[reflect(Resource)]
+pub struct PlayerRoster(pub HashMap<Player, PlayerInfo>);
+
+impl PlayerRoster {
+    pub fn get(&self, player: Player) -> Option<&PlayerInfo> {
+        self.0.get(&player)
+    }
+
+    /// Name of the player, or its id when it is not known (yet)
+    pub fn name(&self, player: Player) -> String {
+        match self.0.get(&player) {
+            Some(info) => info.name.clone(),
+            None => format!("Player {}", player.0),
+        }
+    }
+
+    /// Number of players who are not spectating
+    pub fn playing(&self) -> usize {
+        self.0.values().filter(|info| !info.spectator).count()
+    }
+
+    pub fn color(&self, player: Player) -> Color {
+        match self.0.get(&player) {
+            Some(info) => info.color(),
+            None => Color::WHITE,
+        }
+    }
+}
+
+/// Server side, periodically sends the whole roster to all Clients so they see up to date pings
+pub fn send_roster(
+    mut server: ResMut<QuinnetServer>,
+    roster: Res<PlayerRoster>,
+    time: Res<Time<Real>>,
+    mut timer: Local<Option<Timer>>,
+) {
+    let timer = timer.get_or_insert_with(|| Timer::from_seconds(ROSTER_INTERVAL, TimerMode::Repeating));
+    if timer.tick(time.delta()).just_finished() {
+        server.endpoint_mut().broadcast(ServerMessage::Roster(roster.clone()));
+    }
+}
@@ -4,22 +4,65 @@
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
 use super::rollback::*;
-use super::rollback::{State, States, Snapshot, Rollback, LEN};
+use super::rollback::{State, States, Rollback, LEN};
 use super::{ClientMessage, ServerMessage, NetConfig};
-use crate::input::{UpdateInputEvent, Inputs};
+use super::validation::{CorrectionPolicy, CorrectionViolations};
+use super::delta::{SnapshotDelta, SummaryBaselines};
+use super::roster::{PlayerRoster, PlayerInfo, ConnectionState};
+use super::session::{Sessions, SessionConfig};
+use super::admin::{BanList, PendingKicks, client_ip, reject};
+use super::chat::Chat;
+use super::spectator::{PlayerLimit, set_spectating};
+use super::netsim::ServerReceiver;
+use super::checksum::ServerChecksums;
+use super::interest::{Interest, InterestFilter};
+use super::replication::Replicated;
+use super::traffic::ServerSend;
+use super::wire::{self, Wire};
+use crate::input::{UpdateInputEvent, Inputs, ReceivedClientInputs};
+use crate::player::{Player, HeadData, Health};
 
 use bevy_gravirollback::prelude::*;
 
 use bevy::prelude::*;
+use bevy::ecs::system::SystemParam;
 
-use bevy::utils::HashMap;
-use bevy_quinnet::server::{QuinnetServer, ConnectionLostEvent, ConnectionEvent};
+use bevy_quinnet::server::{QuinnetServer, Endpoint, ConnectionLostEvent, ConnectionEvent};
 
 use std::net::ToSocketAddrs;
+use std::path::PathBuf;
 
 #[derive(Resource)]
 pub struct ServerMarker;
 
+/// Certificate of the Server. It is generated on the first start and saved, so the Clients which
+/// remember its fingerprint can recognize the Server next time.
+#[derive(Resource, Reflect, Clone, Debug)]
+#[reflect(Resource)]
+pub struct CertificateConfig {
+    /// Name of the Server shown in LAN discovery and written in a newly generated certificate
+    pub hostname: String,
+    pub cert_file: PathBuf,
+    pub key_file: PathBuf,
+}
+
+impl Default for CertificateConfig {
+    fn default() -> Self {
+        Self {
+            hostname: "GraviShot server".to_string(),
+            cert_file: PathBuf::from("certificates/server.pem"),
+            key_file: PathBuf::from("certificates/server.key"),
+        }
+    }
+}
+
+/// Prepares resources needed for running as a Server, switch to [`GameState::ServerSetup`](crate::gamestate::GameState::ServerSetup) afterwards
+pub fn init(commands: &mut Commands) {
+    commands.init_resource::<QuinnetServer>();
+    commands.insert_resource(ServerMarker);
+    commands.insert_resource(super::discovery::ServerId(rand::random()));
+}
+
 pub struct SummaryTimer(Timer);
 impl Default for SummaryTimer {
     fn default() -> Self {
@@ -27,11 +70,55 @@ impl Default for SummaryTimer {
     }
 }
 
+/// Everything the Server remembers about each connected Client
+#[derive(SystemParam)]
+pub struct ClientRecords<'w> {
+    pub sessions: ResMut<'w, Sessions>,
+    pub violations: ResMut<'w, CorrectionViolations>,
+    pub baselines: ResMut<'w, SummaryBaselines>,
+    pub roster: ResMut<'w, PlayerRoster>,
+    pub received_inputs: ResMut<'w, ReceivedClientInputs>,
+    pub kicks: ResMut<'w, PendingKicks>,
+    pub chat: ResMut<'w, Chat>,
+    pub checksums: ResMut<'w, ServerChecksums>,
+    pub interest: ResMut<'w, Interest>,
+    pub replicated: ResMut<'w, Replicated>,
+}
+
+/// Settings deciding who can join and what they are allowed to do
+#[derive(SystemParam)]
+pub struct ServerRules<'w> {
+    pub policy: Res<'w, CorrectionPolicy>,
+    pub bans: Res<'w, BanList>,
+    pub limit: Res<'w, PlayerLimit>,
+}
+
+impl ClientRecords<'_> {
+    /// Forgets everything about the Client, its session ends
+    pub fn remove(&mut self, player: Player) {
+        if let Some(client_id) = self.sessions.client(player) {
+            self.baselines.0.remove(&client_id);
+            self.checksums.0.remove(&client_id);
+            self.interest.0.remove(&client_id);
+            self.replicated.0.remove(&client_id);
+        }
+        self.sessions.remove(player);
+        self.violations.remove(player);
+        self.roster.0.remove(&player);
+        self.received_inputs.0.remove(&player);
+        self.chat.throttle.remove(&player);
+    }
+}
+
 pub fn handle(
     mut server: ResMut<QuinnetServer>,
     //local_player: Option<Res<super::LocalPlayer>>,  //TODO: can this fail?
-    players: Query<(&crate::player::Player, &RollbackID)>,
-    
+    players: Query<(&crate::player::Player, &RollbackID, &Rollback<PhysicsBundle>, &Rollback<HeadData>, &Rollback<Health>, &Rollback<Exists>), With<crate::player::Body>>,
+    frames: Res<Rollback<Frame>>,
+    rules: ServerRules,
+    mut records: ClientRecords,
+    rollback_map: Res<RollbackMap>,
+
     mut commands: Commands,
 
     last_frame: Res<LastFrame>,
@@ -44,19 +131,29 @@ pub fn handle(
     
     mut events_conn: EventReader<ConnectionEvent>,
     mut events_lost: EventReader<ConnectionLostEvent>,
+    mut receiver: ServerReceiver,
 ) {
     let endpoint = server.endpoint_mut();
 
     for event in events_conn.read() {
-        println!("ConnectionEvent: Player {} connected",event.id);
+        println!("ConnectionEvent: client {} connected",event.id);
+        let now = super::clock::now();
+        if let Some(ban) = rules.bans.check(None, client_ip(endpoint, event.id), now) {
+            reject(endpoint, &mut records.kicks, event.id, ban.message(now));
+        }
     }
 
-    //handle lost connections
+    //handle lost connections, the player stays in the game for a while so the Client can reconnect
     for event in events_lost.read() {
-        let player = crate::player::Player(event.id);
-        println!("Player {} disconnected",player.0);
-        endpoint.try_broadcast_message(ServerMessage::Disconnected(player));
-        commands.queue(crate::player::despawn_player(player));
+        records.baselines.0.remove(&event.id);
+        records.interest.0.remove(&event.id);
+        records.replicated.0.remove(&event.id);
+        receiver.remove(event.id);
+        let Some(player) = records.sessions.lost(event.id, super::clock::now()) else{continue};
+        println!("Player {} lost connection, waiting for reconnect",player.0);
+        if let Some(info) = records.roster.0.get_mut(&player) {
+            info.state = ConnectionState::Reconnecting;
+        }
     }
 
     //send local player input
@@ -78,189 +175,319 @@ pub fn handle(
     }*/
 
     if map.is_changed() {
-        endpoint.try_broadcast_message(ServerMessage::MapUpdate(map.clone()));
+        endpoint.broadcast(ServerMessage::MapUpdate(map.clone()));
     }
 
     //handle received messages
     for client_id in endpoint.clients() {
-        let player = crate::player::Player(client_id);
-        while let Some((_channel_id, msg)) = endpoint.try_receive_message_from::<ClientMessage>(client_id) {
+        while let Some((_channel_id, msg)) = receiver.receive(endpoint, client_id) {
+            //None until the Client joins
+            let player = records.sessions.player(client_id);
             match msg {
-                ClientMessage::Connect => {
-                    println!("Player {player:?} connected");
-                    endpoint.try_send_message(client_id, ServerMessage::ConnectionGranted(
+                msg @ (ClientMessage::Connect(_) | ClientMessage::Reconnect(..)) => {
+                    let (request, token) = match msg {
+                        ClientMessage::Reconnect(request, token) => (request, Some(token)),
+                        ClientMessage::Connect(request) => (request, None),
+                        _ => unreachable!(),
+                    };
+                    if records.kicks.contains(client_id) {
+                        continue
+                    }
+                    if let Err(reason) = request.check() {
+                        reject(endpoint, &mut records.kicks, client_id, reason);
+                        continue
+                    }
+                    if player.is_some() {
+                        warn!("client {client_id} already joined");
+                        continue
+                    }
+                    let now = super::clock::now();
+                    if let Some(ban) = rules.bans.check(Some(&request.name), client_ip(endpoint, client_id), now) {
+                        reject(endpoint, &mut records.kicks, client_id, ban.message(now));
+                        continue
+                    }
+                    let name = request.name.trim().to_string();
+
+                    let resumed = token.and_then(|token| records.sessions.resume(client_id, token));
+                    let (player, token, info) = match resumed {
+                        Some((player, old_client)) => {
+                            println!("Player {player:?} reconnected with name {name}");
+                            if let Some(old_client) = old_client {
+                                //the Server did not notice yet that the old connection is gone
+                                let _ = endpoint.disconnect_client(old_client);
+                                records.baselines.0.remove(&old_client);
+                                records.interest.0.remove(&old_client);
+                                records.replicated.0.remove(&old_client);
+                            }
+                            let info = records.roster.0.get(&player).cloned().map(|mut info| {
+                                info.name = name.clone();
+                                info.state = ConnectionState::Connected;
+                                info
+                            }).unwrap_or_else(|| PlayerInfo::new(player, name, super::clock::now()));
+                            (player, token.unwrap(), info)
+                        },
+                        None => {
+                            if token.is_some() {
+                                println!("client {client_id} tried to resume unknown or expired session, joining as new player");
+                            }
+                            let (player, token) = records.sessions.join(client_id);
+                            println!("Player {player:?} connected with name {name}");
+                            let mut info = PlayerInfo::new(player, name, super::clock::now());
+                            if rules.limit.is_full(&records.roster) {
+                                info.spectator = true;
+                                endpoint.send(client_id, ServerMessage::Chat(None, "the server is full, you are spectating".to_string()));
+                            }
+                            (player, token, info)
+                        },
+                    };
+                    records.baselines.0.remove(&client_id);
+                    records.interest.0.remove(&client_id);
+                    records.replicated.0.remove(&client_id);
+                    records.received_inputs.0.remove(&player);
+                    records.roster.0.insert(player, info.clone());
+
+                    //RollbackIDs of the Client entities have the Player id in the upper bits,
+                    //continue after the ones which already exist so they do not collide
+                    let first_id = rollback_map.0.keys()
+                        .filter(|id| id.0 >> 32 == player.0)
+                        .map(|id| id.0 + 1)
+                        .max()
+                        .unwrap_or(player.0 << 32);
+
+                    endpoint.send(client_id, ServerMessage::ConnectionGranted(
                         player,
+                        token,
+                        RollbackID(first_id),
                         map.clone(),
+                        wire::position_bounds(),
                         States {
                             last_frame: *last_frame,
                             frame_0_time: update_timer.frame_0_time,
                         },
                     ));
-                    endpoint.try_broadcast_message(ServerMessage::Connected(player));
+                    endpoint.send(client_id, ServerMessage::Roster(records.roster.clone()));
+                    endpoint.broadcast(ServerMessage::Connected(player, info));
                 },
-                ClientMessage::Input(frame, input) => {
-                    println!("received input {frame:?}");
-
-                    input_event.send(UpdateInputEvent {
-                        frame,
-                        player,
-                        input,
-                    });
-
-                    /*
-                    match SnapshotRef::new(now, frame, &mut snapshots, &mut inputs) {
-                        SnapshotType::Past(snapshot) | SnapshotType::Now(snapshot) => {
-                            match snapshot.inputs.0.entry(player) {
-                                Entry::Occupied(e) => {
-                                    //when the server already has the players input it will
-                                    //reject it and send correction back to the client
-                                    print!("occupied");
-                                    if *e.get()!=input {
-                                        print!(" different");
-                                        endpoint.try_send_message_on(
-                                            client_id,
-                                            ChannelId::UnorderedReliable,
-                                            ServerMessage::Input(frame, player, e.get().clone())
-                                        );
-                                    }
-                                    println!();
-                                },
-                                Entry::Vacant(e) => {
-                                    println!("vacant");
-                                    e.insert(input.clone());
-                                    *snapshot.modified = true;
-                                    let mut clients = endpoint.clients();
-                                    clients.retain(|&x| x!=player.0);
-                                    endpoint.try_send_group_message_on(
-                                        clients.iter(),
-                                        ChannelId::UnorderedReliable,
-                                        ServerMessage::Input(frame, player, input)
-                                    );
-                                },
-                            }
-                        },
-                        SnapshotType::Future { now: _ } => {
-                            println!("received Input from the future! Asking to slow down. now {now} frame {frame}");
-                            endpoint.try_send_message(
-                                client_id,
-                                ServerMessage::SlowDown(now)
-                            );
-                        },
-                        SnapshotType::SuperPast => ()
+                ClientMessage::Inputs(first, inputs) => {
+                    let Some(player) = player else{continue};
+                    //spectators can not spawn
+                    let spectator = records.roster.get(player).map_or(true, |info| info.spectator);
+                    //the same Inputs arrive multiple times, only the new ones are used
+                    let received = records.received_inputs.0.entry(player).or_default();
+                    for (i, mut input) in inputs.into_iter().enumerate() {
+                        let frame = Frame(first.0 + i as u64);
+                        if spectator {
+                            input.signals.spawn = None;
+                        }
+                        if received.insert(frame.0) {
+                            input_event.send(UpdateInputEvent {
+                                frame,
+                                player,
+                                input,
+                            });
+                        }
+                    }
+                    //bundles can get lost or reordered on the unreliable channel, only frames without a gap before them are acknowledged
+                    if let Some(frame) = received.contiguous() {
+                        endpoint.send_on(client_id, 2, ServerMessage::InputAck(Frame(frame)));    //Unreliable
+                    }
+                },
+                ClientMessage::SummaryAck(frame) => {
+                    records.baselines.0.entry(client_id).or_default().ack(frame.0);
+                },
+                ClientMessage::Ping(client_time, rtt_ms) => {
+                    if let Some(info) = player.and_then(|player| records.roster.0.get_mut(&player)) {
+                        info.ping_ms = rtt_ms;
+                    }
+                    endpoint.send(client_id, ServerMessage::Pong(client_time, super::clock::now()));
+                },
+                ClientMessage::Chat(text) => {
+                    let Some(player) = player else{continue};
+                    let Some(text) = super::chat::sanitize(&text) else{continue};
+                    if !records.chat.allow(player, super::clock::now()) {
+                        endpoint.send(client_id, ServerMessage::Chat(None, "you are sending messages too fast".to_string()));
+                        continue
+                    }
+                    println!("[{}] {text}", records.roster.name(player));
+                    endpoint.broadcast(ServerMessage::Chat(Some(player), text.clone()));
+                    records.chat.push(Some(player), text);
+                },
+                ClientMessage::Spectate(spectate) => {
+                    let Some(player) = player else{continue};
+                    if let Err(reason) = set_spectating(endpoint, &mut commands, &mut records.roster, &rules.limit, player, spectate) {
+                        endpoint.send(client_id, ServerMessage::Chat(None, reason));
                     }
-                    */
                 },
-                ClientMessage::Correction(frame, state) => {
-                    //TODO: we should have some policy for rejecting too big changes
+                ClientMessage::Checksums(first, checksums) => {
+                    records.checksums.received(client_id, first, checksums);
+                },
+                ClientMessage::DesyncStates(frame, states) => {
+                    records.checksums.client_states(client_id, frame, states);
+                },
+                ClientMessage::Correction(frame, Wire(state)) => {
+                    let Some(player) = player else{continue};
+                    let Some((_, &id, physics_bundle, head_data, health, exists)) = players.iter().find(|(p,..)| **p==player) else{continue};
 
-                    if let Some((_,&id)) = players.iter().find(|(p,_)| **p==player) {
-                        state_event.send(UpdateStateEvent {frame, id, state});
+                    if frame.0 > last_frame.0 {
+                        warn!("correction of player {player:?} from the future {frame:?} {last_frame:?}");
+                        continue
+                    }
+                    let index = index::<LEN>(frame.0);
+                    if frames[index].0 != frame.0 {
+                        //too old frame, it can not be checked
+                        continue
                     }
 
-                    /*
-                    match SnapshotRef::new(now, frame, &mut snapshots, &mut inputs) {
-                        SnapshotType::Past(snapshot) | SnapshotType::Now(snapshot) => {
-                            if let Some(rollback) = players.0.get(&player) {
-                                if let Some(state) = snapshot.states.get_mut(rollback) {
-                                    let s = state.state;
-                                    let c = state_cor.state;
-                                    if !state.fixed && !state_cor.fixed && s.entity==c.entity {
-                                        let condition = 
-                                            //TODO: move magic values into constants
-                                            s.transform.translation.distance(c.transform.translation) < 0.1
-                                            && s.transform.rotation.angle_between(c.transform.rotation) < 0.1
-                                            && s.transform.scale == c.transform.scale
-                                            && s.velocity.linvel.distance(c.velocity.linvel) < 0.1  
-                                            && s.velocity.angvel.distance(c.velocity.angvel) < 0.1;
-                                        if condition {
-                                            *state = state_cor;
-                                            *snapshot.modified = true;  //TODO: resend to others
-                                        }else{
-                                            //send correction
-                                            state.fixed = true;
-                                            endpoint.try_send_message(
-                                                client_id,
-                                                ServerMessage::StateSummary(frame, snapshot.clone(), players.clone())
-                                            );
-                                        }
-                                        continue    //skip the disconnection
-                                    }
-                                }
-                            }
-                            //disconnect the player - player is probably cheating
-                            let _ = endpoint.disconnect_client(client_id);
+                    let stored = State(
+                        physics_bundle.0[index].clone(),
+                        Some((head_data.0[index].clone(), health.0[index])),
+                        Some(player),
+                        super::EntityType::Player,
+                        exists.0[index],
+                    );
+                    match rules.policy.check(&stored, &state) {
+                        Ok(()) => {
+                            state_event.send(UpdateStateEvent {frame, id, state, spawn: false});
                         },
-                        SnapshotType::Future { now: _ } => {
-                            println!("received State from the future! Asking to slow down. now {now} frame {frame}");
-                            endpoint.try_send_message(
-                                client_id,
-                                ServerMessage::SlowDown(now)
-                            );
+                        Err(reason) => {
+                            warn!("rejected correction of player {player:?} in {frame:?}: {reason}");
+                            endpoint.send(client_id, ServerMessage::CorrectionRejected(frame, id, Wire(stored)));
+                            if records.violations.add(player, last_frame.0, &rules.policy) {
+                                warn!("player {player:?} sent too many invalid corrections, disconnecting");
+                                disconnect(endpoint, &mut commands, &mut records, player, "too many invalid corrections");
+                                break
+                            }
                         },
-                        SnapshotType::SuperPast => (),
                     }
-                    */
                 }
             }
         }
     }
 }
 
+/// Removes players whose Clients did not reconnect in time
+pub fn expire_sessions(
+    mut server: ResMut<QuinnetServer>,
+    mut commands: Commands,
+    mut records: ClientRecords,
+    config: Res<SessionConfig>,
+) {
+    for player in records.sessions.expired(super::clock::now(), &config) {
+        println!("Player {} did not reconnect in time",player.0);
+        disconnect(server.endpoint_mut(), &mut commands, &mut records, player, "");
+    }
+}
+
+/// Disconnects the Client, ends its session and removes their player from the game.
+/// The Client is told the reason and the connection gets closed after [`KICK_DELAY`](super::admin::KICK_DELAY).
+pub fn disconnect(endpoint: &mut Endpoint, commands: &mut Commands, records: &mut ClientRecords, player: Player, reason: &str) {
+    if let Some(client_id) = records.sessions.client(player) {
+        endpoint.send(client_id, ServerMessage::Kicked(reason.to_string()));
+        records.kicks.add(client_id);
+    }
+    records.remove(player);
+    endpoint.broadcast(ServerMessage::Disconnected(player));
+    commands.queue(despawn_player(player));
+}
+
+/// Despawns the body and the gun of the player in the last frame through the rollback,
+/// the Clients which know them get [`ServerMessage::Despawn`] of the same frame
+pub fn despawn_player(player: Player) -> impl Fn(&mut World) {
+    move |world: &mut World| {
+        let frame = Frame(world.resource::<LastFrame>().0);
+        let ids = crate::player::despawn_player(world, player, frame);
+        //replicate sends Despawn again when the frame settles, it does not change anything then
+        let Some(replicated) = world.get_resource::<Replicated>() else{return};
+        let clients: Vec<_> = replicated.0.iter()
+            .map(|(&client_id, known)| (client_id, ids.iter().copied().filter(|id| known.contains(id)).collect::<Vec<_>>()))
+            .collect();
+        let mut server = world.resource_mut::<QuinnetServer>();
+        let endpoint = server.endpoint_mut();
+        for (client_id, ids) in clients {
+            for id in ids {
+                endpoint.send(client_id, ServerMessage::Despawn { frame, id });
+            }
+        }
+    }
+}
+
 pub fn send_state_summary(
     mut server: ResMut<QuinnetServer>,
-    query: Query<(&RollbackID, &Rollback<Exists>, &Rollback<PhysicsBundle>, Option<(&Rollback<crate::player::HeadData>, &Rollback<crate::player::Health>)>, Option<&crate::player::Player>, &super::EntityType)>,
+    query: StateQuery,
     inputs: Res<Rollback<Inputs>>,
     last_frame: Res<LastFrame>,
     time: Res<Time>,
     mut timer: Local<SummaryTimer>,
+    mut baselines: ResMut<SummaryBaselines>,
+    mut interest: InterestFilter,
+    replicated: Res<Replicated>,
 ) {
     if timer.0.tick(time.delta()).just_finished() {
         //println!("sending summary");
 
         //do not send the latest snapshot, instead send old, that way it is likely not going to change anymore
-        let offset = LEN as u64 / 4;
-        let frame = if last_frame.0 >= offset {
-            last_frame.0 - offset
+        let frame = if last_frame.0 >= SETTLED_FRAMES {
+            last_frame.0 - SETTLED_FRAMES
         }else{return};
         
         let index = index::<LEN>(frame);
+        let states = collect_states(&query, index);
 
-        let mut states = HashMap::new();
-        for (&id, exists, physics_bundle, player_data, player, &entity_type) in &query {
-            //TODO: this also sends entities present in current frame that were not yet spawned in the past frame
-            //this will then cause a crash when the Client receives it, it will spawn the entity before it should be spawned
-            //and then later it will receive the spawn signal that will try to spawn the entity second time -> crash
-
-            //this should fix it:
-            let exists = exists.0[index];
-
-            let player_data = player_data.map(|x| (x.0.0[index].clone(),x.1.0[index].clone()));
-            let player = player.map(|x| x.clone());
-            states.insert(id, State(physics_bundle.0[index].clone(), player_data, player, entity_type, exists));
+        //each Client gets only the relevant entities, as the difference against the last summary it acknowledged
+        let endpoint = server.endpoint_mut();
+        for client_id in endpoint.clients() {
+            let summaries = baselines.0.entry(client_id).or_default();
+            let baseline = summaries.baseline.as_ref();
+            let mut filtered = interest.filter(client_id, &states, &inputs.0[index], baseline.map(|(_, states)| states));
+            //only the entities the Client was told to spawn, see replicate
+            filtered.states.retain(|id, _| replicated.knows(client_id, id));
+            filtered.stale.retain(|id| filtered.states.contains_key(id));
+            let mut delta = SnapshotDelta::diff(baseline.map(|(_, states)| states), &filtered.states, filtered.inputs);
+            delta.stale = filtered.stale;
+            endpoint.send(client_id, ServerMessage::StateSummary(Frame(frame), baseline.map(|(f, _)| Frame(*f)), delta));
+            summaries.sent(frame, filtered.states);
         }
-
-        let snapshot = Snapshot {
-            states,
-            inputs: inputs.0[index].clone(),
-        };
-        server.endpoint_mut().try_broadcast_message(ServerMessage::StateSummary(Frame(frame), snapshot))
     }
 }
 
 pub fn start(
     mut server: ResMut<QuinnetServer>,
     config: Res<NetConfig>,
+    cert: Res<CertificateConfig>,
+    mut exit: EventWriter<AppExit>,
 ) {
-    let addr = config.ip_port.to_socket_addrs().unwrap().next().unwrap();
+    let Some(addr) = config.ip_port.to_socket_addrs().ok().and_then(|mut addrs| addrs.next()) else{
+        eprintln!("unknown server address {}", config.ip_port);
+        exit.send(AppExit::error());
+        return
+    };
 
     println!("socket: {addr}");
+
+    for file in [&cert.cert_file, &cert.key_file] {
+        if let Some(dir) = file.parent() {
+            let _ = std::fs::create_dir_all(dir);
+        }
+    }
     
     use bevy_quinnet::shared::channels::{ChannelType, ChannelsConfiguration};
-    server.start_endpoint(
+    let cert_info = server.start_endpoint(
         bevy_quinnet::server::ServerEndpointConfiguration::from_addr(addr),
-        bevy_quinnet::server::certificate::CertificateRetrievalMode::GenerateSelfSigned {
-            server_hostname: "GraviShot server".to_string(),    //TODO: allow manually setting this
+        bevy_quinnet::server::certificate::CertificateRetrievalMode::LoadFromFileOrGenerateSelfSigned {
+            cert_file: cert.cert_file.to_string_lossy().into_owned(),
+            key_file: cert.key_file.to_string_lossy().into_owned(),
+            save_on_disk: true,
+            server_hostname: cert.hostname.clone(),
+        },
+        ChannelsConfiguration::from_types(vec![ChannelType::OrderedReliable, ChannelType::UnorderedReliable, ChannelType::Unreliable]).unwrap()
+    );
+    let cert_info = match cert_info {
+        Ok(cert_info) => cert_info,
+        Err(e) => {
+            eprintln!("could not start the server on {addr}: {e}");
+            exit.send(AppExit::error());
+            return
         },
-        ChannelsConfiguration::from_types(vec![ChannelType::OrderedReliable, ChannelType::UnorderedReliable]).unwrap()
-    ).unwrap();
+    };
+    println!("certificate fingerprint: {}", cert_info.fingerprint);
 }
\ No newline at end of file
@@ -0,0 +1,153 @@
+// Gravishot
+// Copyright (C) 2024 Tomáš Pecl
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+// Sessions allow players to come back after their connection drops.
+//
+// Server: Connect -> new Player, ConnectionGranted contains the SessionToken of the Player
+// Server: connection lost -> the Player stays in the game for SessionConfig::grace_period
+// Client: connection lost -> opens a new connection, sends Reconnect(token)
+// Server: Reconnect(token) -> the same Player, its entities were never despawned so their RollbackIDs stay the same
+// Server: grace period is over -> the body and the gun of the Player are despawned through the rollback (Despawn of that frame)
+//         and Disconnected is broadcast so the Clients remove it from the roster
+//
+// Player ids are not the quinnet client ids, the client id changes with every connection.
+
+use crate::player::Player;
+
+use bevy::prelude::*;
+use bevy::utils::HashMap;
+
+use serde::{Serialize, Deserialize};
+
+use std::time::Duration;
+
+/// Secret which proves that the Client owns the Player
+#[derive(Reflect, Serialize, Deserialize, PartialEq, Eq, Hash, Clone, Copy, Debug)]
+pub struct SessionToken(pub u128);
+
+impl SessionToken {
+    pub fn new() -> Self {
+        Self(rand::random())
+    }
+}
+
+#[derive(Resource, Reflect, Clone, Debug)]
+#[reflect(Resource)]
+pub struct SessionConfig {
+    /// Seconds a disconnected player stays in the game waiting for the Client to reconnect
+    pub grace_period: f32,
+}
+
+impl Default for SessionConfig {
+    fn default() -> Self {
+        Self {
+            grace_period: 30.0,
+        }
+    }
+}
+
+pub struct Session {
+    pub token: SessionToken,
+    /// Current connection of the Client, None while it is disconnected
+    pub client_id: Option<u64>,
+    /// Server time when the connection dropped
+    pub disconnected: Option<Duration>,
+}
+
+/// Server side, sessions of all players which joined the game
+#[derive(Resource)]
+pub struct Sessions {
+    next_player: u64,
+    clients: HashMap<u64, Player>,
+    sessions: HashMap<Player, Session>,
+}
+
+impl Default for Sessions {
+    fn default() -> Self {
+        Self {
+            //Player(0) is the local player of the Server
+            next_player: 1,
+            clients: HashMap::new(),
+            sessions: HashMap::new(),
+        }
+    }
+}
+
+impl Sessions {
+    /// Player of the connected Client, None when it has not joined yet
+    pub fn player(&self, client_id: u64) -> Option<Player> {
+        self.clients.get(&client_id).copied()
+    }
+
+    /// Connection of the Player, None for the local player or when it is disconnected
+    pub fn client(&self, player: Player) -> Option<u64> {
+        self.sessions.get(&player).and_then(|session| session.client_id)
+    }
+
+    /// Is this a remote player with a session, connected or not
+    pub fn contains(&self, player: Player) -> bool {
+        self.sessions.contains_key(&player)
+    }
+
+    /// Starts a session of a new Player
+    pub fn join(&mut self, client_id: u64) -> (Player, SessionToken) {
+        let player = Player(self.next_player);
+        self.next_player += 1;
+        let token = SessionToken::new();
+        self.clients.insert(client_id, player);
+        self.sessions.insert(player, Session {
+            token,
+            client_id: Some(client_id),
+            disconnected: None,
+        });
+        (player, token)
+    }
+
+    /// Moves the session with this token to the new connection.
+    /// Returns the Player and its old connection when the Server did not notice yet that it was lost.
+    pub fn resume(&mut self, client_id: u64, token: SessionToken) -> Option<(Player, Option<u64>)> {
+        let (&player, session) = self.sessions.iter_mut().find(|(_, session)| session.token == token)?;
+        let old = session.client_id.replace(client_id);
+        session.disconnected = None;
+        if let Some(old) = old {
+            self.clients.remove(&old);
+        }
+        self.clients.insert(client_id, player);
+        Some((player, old))
+    }
+
+    /// The connection was lost, the session waits for the Client to come back.
+    /// Returns the Player of the connection if it had joined.
+    pub fn lost(&mut self, client_id: u64, now: Duration) -> Option<Player> {
+        let player = self.clients.remove(&client_id)?;
+        if let Some(session) = self.sessions.get_mut(&player) {
+            session.client_id = None;
+            session.disconnected = Some(now);
+        }
+        Some(player)
+    }
+
+    /// Players which have been disconnected for longer than the grace period
+    pub fn expired(&self, now: Duration, config: &SessionConfig) -> Vec<Player> {
+        let grace = Duration::from_secs_f32(config.grace_period.max(0.0));
+        self.sessions.iter()
+            .filter(|(_, session)| session.disconnected.is_some_and(|t| now.saturating_sub(t) >= grace))
+            .map(|(&player, _)| player)
+            .collect()
+    }
+
+    /// Ends the session, the Player can not come back
+    pub fn remove(&mut self, player: Player) {
+        if let Some(session) = self.sessions.remove(&player) {
+            if let Some(client_id) = session.client_id {
+                self.clients.remove(&client_id);
+            }
+        }
+    }
+}
+
+/// Client side, our session in the game we are connected to
+#[derive(Resource, Clone, Copy, Debug)]
+pub struct ClientSession(pub SessionToken);
@@ -0,0 +1,65 @@
+// Gravishot
+// Copyright (C) 2024 Tomáš Pecl
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+// Spectators are players without a body, they only watch the game.
+//
+// Client: ClientMessage::Spectate(true) -> Server: marks the player in PlayerRoster, despawns its body and gun through the rollback
+//         with Despawn(frame, id) of them, everyone gets the new Roster, from now on spawn signals in its Inputs are thrown away
+// Client: ClientMessage::Spectate(false) -> Server: allowed only when PlayerLimit is not reached, then the player can spawn again
+// Server: a Client joining a full Server becomes a spectator
+//
+// Spectators do not count towards PlayerLimit.
+
+use super::ServerMessage;
+use super::roster::PlayerRoster;
+use super::traffic::ServerSend;
+use crate::player::Player;
+
+use bevy::prelude::*;
+use bevy_quinnet::server::Endpoint;
+
+/// Maximum number of players who are not spectating, None means no limit
+#[derive(Resource, Reflect, Default, Clone, Copy, Debug)]
+#[reflect(Resource)]
+pub struct PlayerLimit(pub Option<usize>);
+
+impl PlayerLimit {
+    pub fn is_full(&self, roster: &PlayerRoster) -> bool {
+        self.0.is_some_and(|max| roster.playing() >= max)
+    }
+}
+
+/// Server side, switches the player between playing and spectating and tells everyone.
+/// Returns the reason when the player can not stop spectating.
+pub fn set_spectating(
+    endpoint: &mut Endpoint,
+    commands: &mut Commands,
+    roster: &mut PlayerRoster,
+    limit: &PlayerLimit,
+    player: Player,
+    spectate: bool,
+) -> Result<(), String> {
+    let full = limit.is_full(roster);
+    let Some(info) = roster.0.get_mut(&player) else{
+        return Err(format!("there is no player {}", player.0))
+    };
+    if info.spectator == spectate {
+        return Ok(())
+    }
+    if !spectate && full {
+        return Err("the server is full, you can only spectate".to_string())
+    }
+
+    info.spectator = spectate;
+    if spectate {
+        println!("Player {} is spectating", player.0);
+        //the body goes away the same as when the player leaves, but the player stays in the Roster sent below
+        commands.queue(super::server::despawn_player(player));
+    }else{
+        println!("Player {} stopped spectating", player.0);
+    }
+    endpoint.broadcast(ServerMessage::Roster(roster.clone()));
+    Ok(())
+}
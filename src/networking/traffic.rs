@@ -0,0 +1,112 @@
+// Gravishot
+// Copyright (C) 2024 Tomáš Pecl
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+// Counting of sent and received bytes by message type, shown by the diagnostics.
+//
+// Messages are sent with the ClientSend and ServerSend traits instead of the try_send_* methods of quinnet,
+// they count the message and send it. Received messages are counted in netsim when they arrive.
+// The counters are static because messages are sent from many places, also from World closures.
+// The size is the serialized message without the overhead of QUIC.
+
+use super::{ClientMessage, ServerMessage};
+
+use bevy_quinnet::client::connection::ClientSideConnection;
+use bevy_quinnet::server::Endpoint;
+use bevy_quinnet::shared::channels::ChannelId;
+
+use serde::Serialize;
+
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+
+pub static TRAFFIC: Traffic = Traffic {
+    inbound: Mutex::new(BTreeMap::new()),
+    outbound: Mutex::new(BTreeMap::new()),
+};
+
+#[derive(Default, Clone, Copy, Debug)]
+pub struct Counter {
+    pub messages: u64,
+    pub bytes: u64,
+}
+
+/// Message type -> counter
+pub type Counters = BTreeMap<&'static str, Counter>;
+
+pub struct Traffic {
+    inbound: Mutex<Counters>,
+    outbound: Mutex<Counters>,
+}
+
+fn add(counters: &Mutex<Counters>, kind: &'static str, message: &impl Serialize, copies: usize) {
+    let bytes = bincode::serialized_size(message).unwrap_or(0);
+    let mut counters = counters.lock().unwrap();
+    let counter = counters.entry(kind).or_default();
+    counter.messages += copies as u64;
+    counter.bytes += bytes * copies as u64;
+}
+
+impl Traffic {
+    pub fn received_from_server(&self, message: &ServerMessage) {
+        add(&self.inbound, message.kind(), message, 1);
+    }
+
+    pub fn received_from_client(&self, message: &ClientMessage) {
+        add(&self.inbound, message.kind(), message, 1);
+    }
+
+    /// Takes the inbound and outbound counters collected since the last call
+    pub fn take(&self) -> (Counters, Counters) {
+        (std::mem::take(&mut *self.inbound.lock().unwrap()), std::mem::take(&mut *self.outbound.lock().unwrap()))
+    }
+}
+
+/// Sends a message to the Server and counts it
+pub trait ClientSend {
+    fn send(&mut self, message: ClientMessage);
+    fn send_on(&mut self, channel: ChannelId, message: ClientMessage);
+}
+
+impl ClientSend for ClientSideConnection {
+    fn send(&mut self, message: ClientMessage) {
+        add(&TRAFFIC.outbound, message.kind(), &message, 1);
+        self.try_send_message(message);
+    }
+
+    fn send_on(&mut self, channel: ChannelId, message: ClientMessage) {
+        add(&TRAFFIC.outbound, message.kind(), &message, 1);
+        self.try_send_message_on(channel, message);
+    }
+}
+
+/// Sends a message to Clients and counts it once for every receiving Client
+pub trait ServerSend {
+    fn send(&mut self, client_id: u64, message: ServerMessage);
+    fn send_on(&mut self, client_id: u64, channel: ChannelId, message: ServerMessage);
+    fn send_group_on(&mut self, client_ids: &[u64], channel: ChannelId, message: ServerMessage);
+    fn broadcast(&mut self, message: ServerMessage);
+}
+
+impl ServerSend for Endpoint {
+    fn send(&mut self, client_id: u64, message: ServerMessage) {
+        add(&TRAFFIC.outbound, message.kind(), &message, 1);
+        self.try_send_message(client_id, message);
+    }
+
+    fn send_on(&mut self, client_id: u64, channel: ChannelId, message: ServerMessage) {
+        add(&TRAFFIC.outbound, message.kind(), &message, 1);
+        self.try_send_message_on(client_id, channel, message);
+    }
+
+    fn send_group_on(&mut self, client_ids: &[u64], channel: ChannelId, message: ServerMessage) {
+        add(&TRAFFIC.outbound, message.kind(), &message, client_ids.len());
+        self.try_send_group_message_on(client_ids.iter(), channel, message);
+    }
+
+    fn broadcast(&mut self, message: ServerMessage) {
+        add(&TRAFFIC.outbound, message.kind(), &message, self.clients().len());
+        self.try_broadcast_message(message);
+    }
+}
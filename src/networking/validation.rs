@@ -0,0 +1,129 @@
+// Gravishot
+// Copyright (C) 2024 Tomáš Pecl
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use super::rollback::State;
+use crate::player::Player;
+
+use bevy::prelude::*;
+use bevy::utils::HashMap;
+
+/// Decides which [`ClientMessage::Correction`](super::ClientMessage::Correction)s the Server accepts.
+/// Small corrections are accepted, large ones are rejected and the Client gets the Server State back.
+/// Clients that go over the limits too often are disconnected, they are probably cheating.
+#[derive(Resource, Reflect, Clone, Debug)]
+#[reflect(Resource)]
+pub struct CorrectionPolicy {
+    /// Maximal distance between the corrected and the Server position
+    pub max_translation: f32,
+    /// Maximal angle (radians) between the corrected and the Server body rotation
+    pub max_rotation: f32,
+    /// Maximal angle (radians) between the corrected and the Server head rotation
+    pub max_head_rotation: f32,
+    pub max_linvel: f32,
+    pub max_angvel: f32,
+    /// Health can never be increased by a correction, this is the allowed numerical error
+    pub health_epsilon: f32,
+    /// Number of rejected corrections after which the Client gets disconnected
+    pub max_violations: u32,
+    /// One violation is forgotten after this many frames without another violation
+    pub forgive_frames: u64,
+}
+
+impl Default for CorrectionPolicy {
+    fn default() -> Self {
+        Self {
+            max_translation: 0.1,
+            max_rotation: 0.1,
+            max_head_rotation: 0.1,
+            max_linvel: 0.1,
+            max_angvel: 0.1,
+            health_epsilon: 0.001,
+            max_violations: 10,
+            forgive_frames: 50*10,
+        }
+    }
+}
+
+impl CorrectionPolicy {
+    /// Compares the `correction` sent by a Client with the `stored` Server State of the same frame.
+    /// Returns the reason when it should be rejected.
+    pub fn check(&self, stored: &State, correction: &State) -> Result<(), String> {
+        let State(s_physics, s_player_data, s_player, s_type, s_exists) = stored;
+        let State(c_physics, c_player_data, c_player, c_type, c_exists) = correction;
+
+        if s_type != c_type || s_player != c_player || s_exists.0 != c_exists.0 {
+            return Err("entity type, owner or existence changed".to_string());
+        }
+
+        let (s, c) = (&s_physics.transform, &c_physics.transform);
+        let translation = s.translation.distance(c.translation);
+        if !(translation <= self.max_translation) {
+            return Err(format!("translation changed by {translation}"));
+        }
+        let rotation = s.rotation.angle_between(c.rotation);
+        if !(rotation <= self.max_rotation) {
+            return Err(format!("rotation changed by {rotation}"));
+        }
+        if s.scale != c.scale {
+            return Err("scale changed".to_string());
+        }
+
+        let (s, c) = (&s_physics.velocity, &c_physics.velocity);
+        let linvel = s.linvel.distance(c.linvel);
+        if !(linvel <= self.max_linvel) {
+            return Err(format!("linear velocity changed by {linvel}"));
+        }
+        let angvel = s.angvel.distance(c.angvel);
+        if !(angvel <= self.max_angvel) {
+            return Err(format!("angular velocity changed by {angvel}"));
+        }
+
+        match (s_player_data, c_player_data) {
+            (Some((s_head, s_health)), Some((c_head, c_health))) => {
+                let head = s_head.rotation.angle_between(c_head.rotation);
+                if !(head <= self.max_head_rotation) {
+                    return Err(format!("head rotation changed by {head}"));
+                }
+                if !(c_health.0 <= s_health.0 + self.health_epsilon) {
+                    return Err(format!("health increased from {} to {}", s_health.0, c_health.0));
+                }
+            },
+            (None, None) => (),
+            _ => return Err("player data added or removed".to_string()),
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Reflect, Clone, Copy, Debug, Default)]
+pub struct Violations {
+    pub count: u32,
+    /// Frame of the last violation
+    pub last_frame: u64,
+}
+
+/// Rejected corrections of each Client
+#[derive(Resource, Reflect, Default, Debug)]
+#[reflect(Resource)]
+pub struct CorrectionViolations(pub HashMap<Player, Violations>);
+
+impl CorrectionViolations {
+    /// Records a violation in `frame`, returns true when the Client went over the limit
+    pub fn add(&mut self, player: Player, frame: u64, policy: &CorrectionPolicy) -> bool {
+        let violations = self.0.entry(player).or_default();
+        if policy.forgive_frames != 0 {
+            let forgiven = frame.saturating_sub(violations.last_frame) / policy.forgive_frames;
+            violations.count = violations.count.saturating_sub(forgiven.min(u32::MAX as u64) as u32);
+        }
+        violations.count += 1;
+        violations.last_frame = frame;
+        violations.count > policy.max_violations
+    }
+
+    pub fn remove(&mut self, player: Player) {
+        self.0.remove(&player);
+    }
+}
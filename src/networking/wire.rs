@@ -0,0 +1,374 @@
+// Gravishot
+// Copyright (C) 2024 Tomáš Pecl
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+// Compact encoding of Transform, Velocity and Quat used in network messages.
+// Wrap the values in Wire inside of ServerMessage and ClientMessage, everywhere else (replays, desync dumps, RON files)
+// they are serialized at full precision.
+//
+// | type       | encoding                                            | size | full size |
+// |------------|-----------------------------------------------------|------|-----------|
+// | position   | 3 x 24 bit fixed point inside of the map bounds     | 10 B |      12 B |
+// | rotation   | smallest three, 2 bit index + 3 x 15 bit components |  6 B |      16 B |
+// | scale      | omitted, always Vec3::ONE                           |  0 B |      12 B |
+// | velocity   | 2 x 3 x 16 bit fixed point                          | 12 B |      24 B |
+//
+// The round trip errors are bounded by the MAX_*_ERROR constants and max_position_error. They are far below
+// the limits of CorrectionPolicy (0.1 by default) so the rollback correction logic does not notice them.
+//
+// The Server derives the position bounds from the Map and sends them in ConnectionGranted. Positions outside
+// of them are sent at full precision (22 B), so entities which leave the map are still corrected exactly.
+
+use super::EntityType;
+use super::rollback::{State, PhysicsBundle};
+use crate::map::DEFAULT_HALF_EXTENT;
+use crate::player::{Player, HeadData, Health};
+
+use bevy_gravirollback::prelude::*;
+
+use bevy::prelude::*;
+use bevy_rapier3d::prelude::*;
+
+use serde::{Serialize, Deserialize, Serializer, Deserializer};
+use serde::de::DeserializeOwned;
+
+use std::f32::consts::FRAC_1_SQRT_2;
+use std::sync::atomic::{AtomicU32, Ordering};
+
+const POSITION_BITS: u32 = 24;
+const POSITION_MAX: u32 = (1 << POSITION_BITS) - 1;
+const QUAT_BITS: u32 = 15;
+const QUAT_MAX: u32 = (1 << QUAT_BITS) - 1;
+/// Linear velocity is stored in steps of 1/64 m/s, range is about ±512 m/s, faster velocities are clamped
+const LINVEL_SCALE: f32 = 64.0;
+/// Angular velocity is stored in steps of 1/256 rad/s, range is about ±128 rad/s, faster velocities are clamped
+const ANGVEL_SCALE: f32 = 256.0;
+
+/// Maximal error of the three sent quaternion components, the largest one is computed from them
+pub const MAX_QUAT_COMPONENT_ERROR: f32 = FRAC_1_SQRT_2 / QUAT_MAX as f32;
+/// Maximal angle in radians between the sent and the received rotation
+pub const MAX_QUAT_ANGLE_ERROR: f32 = 0.0002;
+pub const MAX_LINVEL_ERROR: f32 = 0.5 / LINVEL_SCALE;
+pub const MAX_ANGVEL_ERROR: f32 = 0.5 / ANGVEL_SCALE;
+
+/// Half of the size of the cube centered at the origin in which positions are quantized, in whole meters
+static POSITION_BOUNDS: AtomicU32 = AtomicU32::new(DEFAULT_HALF_EXTENT as u32);
+
+/// Sets the bounds of the position encoding, the Server and the Client have to use the same ones.
+/// See [`Map::half_extent`](crate::map::Map::half_extent).
+pub fn set_position_bounds(half_extent: f32) {
+    POSITION_BOUNDS.store(half_extent.ceil().max(1.0) as u32, Ordering::Relaxed);
+}
+
+pub fn position_bounds() -> f32 {
+    POSITION_BOUNDS.load(Ordering::Relaxed) as f32
+}
+
+/// Maximal position error per axis for positions inside of the bounds, not counting the rounding into f32.
+/// Positions outside of the bounds have no error.
+pub fn max_position_error() -> f32 {
+    position_bounds() / POSITION_MAX as f32
+}
+
+/// Fixed point position inside of the bounds, the exact position when it is outside of them
+pub type PackedPosition = ([u8; 9], Option<[f32; 3]>);
+
+pub fn pack_position(position: Vec3) -> PackedPosition {
+    let bounds = position_bounds() as f64;
+    let mut out = [0; 9];
+    let mut inside = true;
+    for (i, x) in position.to_array().into_iter().enumerate() {
+        //NaN is outside too
+        inside &= (x as f64).abs() <= bounds;
+        //f32 does not have enough precision for the normalized value
+        let normalized = ((x as f64 + bounds) / (2.0 * bounds)).clamp(0.0, 1.0);
+        let q = (normalized * POSITION_MAX as f64).round() as u32;
+        out[3*i..3*i+3].copy_from_slice(&q.to_le_bytes()[..3]);
+    }
+    if inside {
+        (out, None)
+    }else{
+        //clamping would make different positions look the same and they would never get corrected
+        ([0; 9], Some(position.to_array()))
+    }
+}
+
+pub fn unpack_position((data, exact): PackedPosition) -> Vec3 {
+    if let Some(exact) = exact {
+        return Vec3::from_array(exact)
+    }
+    let bounds = position_bounds() as f64;
+    let mut out = [0.0; 3];
+    for (i, x) in out.iter_mut().enumerate() {
+        let q = u32::from_le_bytes([data[3*i], data[3*i+1], data[3*i+2], 0]);
+        *x = (q as f64 / POSITION_MAX as f64 * 2.0 * bounds - bounds) as f32;
+    }
+    Vec3::from_array(out)
+}
+
+/// Smallest three quaternion compression. The largest component is left out, it is computed
+/// from the other three. q and -q represent the same rotation, so the largest component is made positive.
+pub fn pack_quat(rotation: Quat) -> [u8; 6] {
+    let a = rotation.normalize().to_array();
+    let largest = (0..4).max_by(|&i, &j| a[i].abs().total_cmp(&a[j].abs())).unwrap();
+    let sign = if a[largest] < 0.0 {-1.0}else{1.0};
+
+    let mut bits = largest as u64;
+    let mut shift = 2;
+    for (i, &x) in a.iter().enumerate() {
+        if i==largest {continue}
+        let normalized = ((x * sign / FRAC_1_SQRT_2 + 1.0) / 2.0).clamp(0.0, 1.0);
+        let q = (normalized * QUAT_MAX as f32).round() as u64;
+        bits |= q << shift;
+        shift += QUAT_BITS;
+    }
+
+    let mut out = [0; 6];
+    out.copy_from_slice(&bits.to_le_bytes()[..6]);
+    out
+}
+
+pub fn unpack_quat(data: [u8; 6]) -> Quat {
+    let mut bytes = [0; 8];
+    bytes[..6].copy_from_slice(&data);
+    let bits = u64::from_le_bytes(bytes);
+
+    let largest = (bits & 0b11) as usize;
+    let mut a = [0.0; 4];
+    let mut shift = 2;
+    let mut sum = 0.0;
+    for (i, x) in a.iter_mut().enumerate() {
+        if i==largest {continue}
+        let q = ((bits >> shift) & QUAT_MAX as u64) as f32;
+        *x = (q / QUAT_MAX as f32 * 2.0 - 1.0) * FRAC_1_SQRT_2;
+        sum += *x * *x;
+        shift += QUAT_BITS;
+    }
+    a[largest] = (1.0 - sum).max(0.0).sqrt();
+
+    Quat::from_array(a).normalize()
+}
+
+fn pack_fixed(v: Vec3, scale: f32) -> [i16; 3] {
+    v.to_array().map(|x| (x * scale).round().clamp(i16::MIN as f32, i16::MAX as f32) as i16)
+}
+
+fn unpack_fixed(v: [i16; 3], scale: f32) -> Vec3 {
+    Vec3::from_array(v.map(|x| x as f32 / scale))
+}
+
+pub fn pack_velocity(velocity: &Velocity) -> ([i16; 3], [i16; 3]) {
+    (pack_fixed(velocity.linvel, LINVEL_SCALE), pack_fixed(velocity.angvel, ANGVEL_SCALE))
+}
+
+pub fn unpack_velocity((linvel, angvel): ([i16; 3], [i16; 3])) -> Velocity {
+    Velocity {
+        linvel: unpack_fixed(linvel, LINVEL_SCALE),
+        angvel: unpack_fixed(angvel, ANGVEL_SCALE),
+    }
+}
+
+/// Compares values as they would be seen after sending them over the network
+pub fn transform_eq(a: &Transform, b: &Transform) -> bool {
+    pack_position(a.translation) == pack_position(b.translation) && pack_quat(a.rotation) == pack_quat(b.rotation)
+}
+
+/// Compares values as they would be seen after sending them over the network
+pub fn velocity_eq(a: &Velocity, b: &Velocity) -> bool {
+    pack_velocity(a) == pack_velocity(b)
+}
+
+/// Compares values as they would be seen after sending them over the network
+pub fn quat_eq(a: Quat, b: Quat) -> bool {
+    pack_quat(a) == pack_quat(b)
+}
+
+/// Value serialized in the compact encoding, used only inside of network messages
+#[derive(Clone, Debug)]
+pub struct Wire<T>(pub T);
+
+/// Types with a compact network encoding
+pub trait WireEncode: Sized {
+    type Encoded: Serialize + DeserializeOwned;
+    fn encode(&self) -> Self::Encoded;
+    fn decode(encoded: Self::Encoded) -> Self;
+}
+
+impl<T: WireEncode> Serialize for Wire<T> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.0.encode().serialize(serializer)
+    }
+}
+
+impl<'de, T: WireEncode> Deserialize<'de> for Wire<T> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Ok(Wire(T::decode(T::Encoded::deserialize(deserializer)?)))
+    }
+}
+
+impl WireEncode for PhysicsBundle {
+    type Encoded = (PackedPosition, [u8; 6], ([i16; 3], [i16; 3]));
+
+    fn encode(&self) -> Self::Encoded {
+        (pack_position(self.transform.translation), pack_quat(self.transform.rotation), pack_velocity(&self.velocity))
+    }
+
+    fn decode((position, rotation, velocity): Self::Encoded) -> Self {
+        PhysicsBundle {
+            transform: Transform {
+                translation: unpack_position(position),
+                rotation: unpack_quat(rotation),
+                scale: Vec3::ONE,
+            },
+            velocity: unpack_velocity(velocity),
+        }
+    }
+}
+
+impl WireEncode for HeadData {
+    type Encoded = [u8; 6];
+
+    fn encode(&self) -> Self::Encoded {
+        pack_quat(self.rotation)
+    }
+
+    fn decode(rotation: Self::Encoded) -> Self {
+        HeadData {
+            rotation: unpack_quat(rotation),
+        }
+    }
+}
+
+impl WireEncode for Option<(HeadData, Health)> {
+    type Encoded = Option<([u8; 6], Health)>;
+
+    fn encode(&self) -> Self::Encoded {
+        self.as_ref().map(|(head_data, health)| (head_data.encode(), *health))
+    }
+
+    fn decode(encoded: Self::Encoded) -> Self {
+        encoded.map(|(head_data, health)| (HeadData::decode(head_data), health))
+    }
+}
+
+impl WireEncode for State {
+    type Encoded = (<PhysicsBundle as WireEncode>::Encoded, <Option<(HeadData, Health)> as WireEncode>::Encoded, Option<Player>, EntityType, bool);
+
+    fn encode(&self) -> Self::Encoded {
+        (self.0.encode(), self.1.encode(), self.2, self.3, self.4.0)
+    }
+
+    fn decode((physics, player_data, player, entity_type, exists): Self::Encoded) -> Self {
+        State(PhysicsBundle::decode(physics), WireEncode::decode(player_data), player, entity_type, Exists(exists))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Positions, velocities and rotations spread over the whole range
+    fn samples(range: f32) -> Vec<f32> {
+        (0..=1000).map(|i| (i as f32 / 500.0 - 1.0) * range).chain([0.0, range, -range, 0.001, -0.001]).collect()
+    }
+
+    fn rotations() -> Vec<Quat> {
+        let mut rotations = vec![Quat::IDENTITY, -Quat::IDENTITY, Quat::from_rotation_x(std::f32::consts::PI), Quat::from_xyzw(0.5, 0.5, 0.5, 0.5)];
+        for yaw in (0..360).step_by(7) {
+            for pitch in (-90..=90).step_by(7) {
+                for roll in (0..360).step_by(11) {
+                    let (yaw, pitch, roll) = ((yaw as f32).to_radians(), (pitch as f32).to_radians(), (roll as f32).to_radians());
+                    let rotation = Quat::from_euler(EulerRot::YXZ, yaw, pitch, roll);
+                    rotations.push(rotation);
+                    rotations.push(-rotation);
+                }
+            }
+        }
+        rotations
+    }
+
+    #[test]
+    fn position_round_trip() {
+        for x in samples(position_bounds()) {
+            let position = Vec3::new(x, -x, x / 3.0);
+            let packed = pack_position(position);
+            assert!(packed.1.is_none(), "position {position} inside of the bounds is sent exactly");
+            let received = unpack_position(packed);
+            for (sent, received) in position.to_array().into_iter().zip(received.to_array()) {
+                let bound = max_position_error() + sent.abs() * f32::EPSILON;
+                assert!((sent - received).abs() <= bound, "position {sent} received as {received}");
+            }
+        }
+    }
+
+    #[test]
+    fn position_outside_of_bounds_is_exact() {
+        let far = position_bounds() * 3.0;
+        for position in [Vec3::new(far, -far, 1e9), Vec3::new(0.0, far + 0.001, 0.0), Vec3::new(-far, 0.0, -1e-3)] {
+            assert_eq!(unpack_position(pack_position(position)), position);
+        }
+        //they would be the same after clamping
+        let (a, b) = (Vec3::new(far, 0.0, 0.0), Vec3::new(far * 2.0, 0.0, 0.0));
+        assert!(!transform_eq(&Transform::from_translation(a), &Transform::from_translation(b)));
+    }
+
+    #[test]
+    fn out_of_bounds_entity_is_corrected() {
+        let far = position_bounds() * 2.0;
+        let server = PhysicsBundle { transform: Transform::from_xyz(far, 1.0, -far), ..default() };
+        let client = PhysicsBundle { transform: Transform::from_xyz(far + 10.0, 1.0, -far), ..default() };
+        //the Client state differs from the Server one, so it gets replaced by the received one
+        assert!(!client.wire_eq(&server));
+        let bytes = bincode::serialize(&Wire(server.clone())).unwrap();
+        let received = bincode::deserialize::<Wire<PhysicsBundle>>(&bytes).unwrap().0;
+        assert_eq!(received.transform.translation, server.transform.translation);
+        assert!(received.wire_eq(&server));
+    }
+
+    #[test]
+    fn quat_round_trip() {
+        for rotation in rotations() {
+            let mut received = unpack_quat(pack_quat(rotation));
+            //q and -q are the same rotation
+            if received.dot(rotation) < 0.0 {
+                received = -received;
+            }
+            //the same components are sent as in pack_quat
+            let (sent, got) = (rotation.normalize().to_array(), received.to_array());
+            let largest = (0..4).max_by(|&i, &j| sent[i].abs().total_cmp(&sent[j].abs())).unwrap();
+            for i in (0..4).filter(|&i| i != largest) {
+                assert!((sent[i] - got[i]).abs() <= MAX_QUAT_COMPONENT_ERROR + f32::EPSILON, "rotation {rotation:?} received as {received:?}");
+            }
+            let chord = (rotation - received).length();
+            let angle = 4.0 * (chord / 2.0).asin();
+            assert!(angle <= MAX_QUAT_ANGLE_ERROR, "rotation {rotation:?} received as {received:?}, angle {angle}");
+        }
+    }
+
+    #[test]
+    fn velocity_round_trip() {
+        let linvel_range = i16::MAX as f32 / LINVEL_SCALE;
+        let angvel_range = i16::MAX as f32 / ANGVEL_SCALE;
+        for (linvel, angvel) in samples(linvel_range).into_iter().zip(samples(angvel_range)) {
+            let velocity = Velocity {
+                linvel: Vec3::new(linvel, -linvel, linvel / 7.0),
+                angvel: Vec3::new(angvel, angvel / 3.0, -angvel),
+            };
+            let received = unpack_velocity(pack_velocity(&velocity));
+            assert!((velocity.linvel - received.linvel).abs().max_element() <= MAX_LINVEL_ERROR + linvel.abs() * f32::EPSILON, "{velocity:?} received as {received:?}");
+            assert!((velocity.angvel - received.angvel).abs().max_element() <= MAX_ANGVEL_ERROR + angvel.abs() * f32::EPSILON, "{velocity:?} received as {received:?}");
+        }
+    }
+
+    #[test]
+    fn velocity_clamped_to_range() {
+        let velocity = Velocity {
+            linvel: Vec3::new(1000.0, -1000.0, 0.0),
+            angvel: Vec3::new(0.0, 200.0, -200.0),
+        };
+        let received = unpack_velocity(pack_velocity(&velocity));
+        assert_eq!(received.linvel, Vec3::new(i16::MAX as f32, i16::MIN as f32, 0.0) / LINVEL_SCALE);
+        assert_eq!(received.angvel, Vec3::new(0.0, i16::MAX as f32, i16::MIN as f32) / ANGVEL_SCALE);
+    }
+}
@@ -93,7 +93,7 @@ pub struct Body;
 #[derive(Component)]
 pub struct Head;
 
-#[derive(Default, Reflect, Serialize, Deserialize, Clone, Debug)]
+#[derive(Default, Reflect, Serialize, Deserialize, Clone, PartialEq, Debug)]
 pub struct HeadData {
     pub rotation: Quat,
 }
@@ -115,12 +115,16 @@ impl RollbackCapable for HeadData {
 }
 
 const PLAYER_HEALTH: Health = Health(100.0);
-#[derive(Component, Reflect, Serialize, Deserialize, Clone, Copy, Debug, Default)]
+/// Height of the body, the head is on top of it
+pub const HEIGHT: f32 = 0.5;
+/// Radius of the body and of the head
+pub const RADIUS: f32 = 0.125;
+/// Damage multipliers of hits into the body and into the head
+pub const BODY_DAMAGE: f32 = 1.0;
+pub const HEAD_DAMAGE: f32 = 5.0;
+#[derive(Component, Reflect, Serialize, Deserialize, Clone, Copy, PartialEq, Debug, Default)]
 pub struct Health(pub f32);
 
-#[derive(Component, Reflect)]
-pub struct DamageCoeficient(pub f32);
-
 #[derive(Reflect, Serialize, Deserialize, Clone)]
 pub struct SpawnPlayer {
     pub player: Player,
@@ -175,8 +179,8 @@ pub fn spawn_player_system(
 }
 
 pub fn make_player(event: SpawnPlayer) -> impl Fn(Option<Res<crate::networking::LocalPlayer>>, ResMut<Assets<Mesh>>, ResMut<Assets<StandardMaterial>>, Commands) -> Entity {
-    let height = 0.5;
-    let radius = 0.125;
+    let height = HEIGHT;
+    let radius = RADIUS;
 
     let player_id = event.player;
     let rollback_body = event.rollback_body;
@@ -232,8 +236,7 @@ pub fn make_player(event: SpawnPlayer) -> impl Fn(Option<Res<crate::networking::
             ),
             Standing(false),
             (health,
-            health_rb,
-            DamageCoeficient(1.0),),
+            health_rb,),
             rollback_body,
             (Body,
             Name::new("Player Body"),),
@@ -275,7 +278,6 @@ pub fn make_player(event: SpawnPlayer) -> impl Fn(Option<Res<crate::networking::
             let mut head = parent.spawn((
                 Head,
                 Name::new("Player Head"),
-                DamageCoeficient(5.0),  //TODO: head damage does not work without a Collider
                 player_id,
                 Transform::from_xyz(0.0, height, 0.0),
                 Mesh3d(head_mesh),
@@ -330,8 +332,7 @@ pub fn make_player(event: SpawnPlayer) -> impl Fn(Option<Res<crate::networking::
     }
 }
 
-//TODO: this does not always work, when the client is not fully synced it will miss a hit and then not remove the Gun's Player
-//this will cause a crash
+/// Health changes only by the hits which the Server registered, see [`crate::networking::hits`]
 pub fn health_system(
     mut players: Query<(&PlayerParts, &mut Exists, &Health)>,
     mut commands: Commands,
@@ -346,16 +347,31 @@ pub fn health_system(
     }
 }
 
-pub fn despawn_player(player_to_despawn: Player) -> impl Fn(&mut World) {
-    move |world: &mut World| {
-        //TODO: this does not work, this is not used when rollback systems despawn the player, so the gun is not despawned
-        //rollback despawn system need some callback that despawns even the gun
-        //or just put RollbackID onto the gun
-        let entities = world.query_filtered::<(Entity, &Player), With<Body>>().iter(world).filter_map(|(entity,&player)| if player==player_to_despawn {Some(entity)}else{None}).collect::<Vec<_>>();
-        for e in entities {
-            let _ = world.get_entity_mut(e).map(|e| e.despawn_recursive()); //TODO: maybe instead set Exists to false
+/// Despawns the bodies of the player and their guns through the rollback, they do not exist from this frame on.
+/// Returns their RollbackIDs, the Server sends them to the Clients in [`ServerMessage::Despawn`](crate::networking::ServerMessage::Despawn).
+pub fn despawn_player(world: &mut World, player_to_despawn: Player, frame: Frame) -> Vec<RollbackID> {
+    let bodies = world.query_filtered::<(&Player, &RollbackID, &PlayerParts), With<Body>>().iter(world)
+        .filter(|(&player, _, _)| player == player_to_despawn)
+        .map(|(_, &id, parts)| (id, parts.gun))
+        .collect::<Vec<_>>();
+    let mut despawned = Vec::new();
+    for (body, gun) in bodies {
+        despawned.push(body);
+        //the gun is a separate Rollback entity, it would stay behind
+        if let Some(&gun) = world.get::<RollbackID>(gun) {
+            despawned.push(gun);
         }
     }
+    if despawned.is_empty() {
+        return despawned
+    }
+    world.send_event_batch(despawned.iter().map(|&id| crate::networking::rollback::DespawnEvent { frame, id }));
+
+    //it does not come from Inputs, so replays have to remember it
+    if let Some(mut recorder) = world.get_resource_mut::<crate::replay::ReplayRecorder>() {
+        recorder.event(frame.0, crate::replay::ReplayEvent::Despawn(player_to_despawn));
+    }
+    despawned
 }
 
 /*pub fn display_events(
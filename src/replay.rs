@@ -0,0 +1,450 @@
+// Gravishot
+// Copyright (C) 2024 Tomáš Pecl
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+// Recording of matches and their deterministic replay.
+//
+// The simulation is driven only by the Map, the States of the Rollback entities and the Inputs and Hits of every frame,
+// so a replay stores just these. Both the Server and the Client can record, they write frames once they are
+// SETTLED_FRAMES old and not likely to change anymore.
+//
+// The file is line based so it can be written while the match goes on:
+//   first line:  ReplayHeader in RON format - map, roster and States of the first recorded frame
+//   next lines:  ReplayFrame in RON format - Inputs and Hits of one frame and events which are not driven by them, consecutive frames
+//
+// Playback spawns the recorded States and runs the normal RollbackUpdate schedule, the Inputs and Hits of each frame are
+// stored into Rollback<Inputs> and Rollback<Hits> as soon as the frame is created, the same as if they arrived over the network in time.
+// Seeking back starts again from the first frame because older frames are not kept.
+
+use crate::networking::PROTOCOL_VERSION;
+use crate::networking::rollback::{State, StateQuery, UpdateStateEvent, Rollback, LEN, SETTLED_FRAMES, collect_states};
+use crate::networking::delta::EntityStates;
+use crate::networking::roster::PlayerRoster;
+use crate::networking::hits::Hits;
+use crate::input::Inputs;
+use crate::map::Map;
+use crate::player::Player;
+
+use bevy_gravirollback::prelude::*;
+
+use bevy::prelude::*;
+use bevy::ecs::system::SystemParam;
+
+use serde::{Serialize, Deserialize};
+
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// The recording is written to the disk at least this often, seconds
+const FLUSH_INTERVAL: f32 = 1.0;
+/// Fast forwarding and seeking simulates at most this many frames in one update
+const MAX_FRAMES_PER_UPDATE: u64 = 16;
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ReplayHeader {
+    /// [`PROTOCOL_VERSION`] of the game which recorded it, Inputs and States change together with the protocol
+    pub version: u32,
+    pub map: Map,
+    pub roster: PlayerRoster,
+    /// Server time of frame 0, tells when the match was played
+    pub frame_0_time: Duration,
+    /// The first recorded frame
+    pub start_frame: u64,
+    /// States of existing Rollback entities in the first recorded frame
+    pub states: EntityStates,
+}
+
+/// Changes of the game which do not come from Inputs
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub enum ReplayEvent {
+    /// The body of the player was removed, the player left or started spectating
+    Despawn(Player),
+    Map(Map),
+    Roster(PlayerRoster),
+}
+
+#[derive(Serialize, Deserialize, Default, Clone)]
+pub struct ReplayFrame {
+    pub inputs: Inputs,
+    pub hits: Hits,
+    /// Events which happened in this frame
+    pub events: Vec<ReplayEvent>,
+}
+
+pub struct Replay {
+    pub header: ReplayHeader,
+    /// Frames following `header.start_frame`, the first one is the start frame
+    pub frames: Vec<ReplayFrame>,
+}
+
+impl Replay {
+    pub fn load(path: &Path) -> Result<Replay, String> {
+        let file = File::open(path).map_err(|e| format!("could not read replay file {}: {e}", path.display()))?;
+        let mut lines = BufReader::new(file).lines();
+        let parse_error = |e: &dyn std::fmt::Display| format!("could not parse replay file {}: {e}", path.display());
+
+        let header = lines.next().ok_or_else(|| parse_error(&"the file is empty"))?.map_err(|e| parse_error(&e))?;
+        let header: ReplayHeader = ron::from_str(&header).map_err(|e| parse_error(&e))?;
+        if header.version != PROTOCOL_VERSION {
+            return Err(format!("replay file {} was recorded by an incompatible version {}, this version is {PROTOCOL_VERSION}", path.display(), header.version))
+        }
+
+        let mut frames = Vec::new();
+        for line in lines {
+            let line = line.map_err(|e| parse_error(&e))?;
+            match ron::from_str(&line) {
+                Ok(frame) => frames.push(frame),
+                Err(e) => {
+                    //the game probably did not finish writing the last line, keep what was read
+                    warn!("{}, the replay ends at frame {}", parse_error(&e), header.start_frame + frames.len() as u64);
+                    break
+                },
+            }
+        }
+
+        Ok(Replay { header, frames })
+    }
+
+    /// Frames up to this one can be simulated
+    pub fn end_frame(&self) -> u64 {
+        self.header.start_frame + self.frames.len() as u64
+    }
+
+    pub fn frame(&self, frame: u64) -> Option<&ReplayFrame> {
+        let i = frame.checked_sub(self.header.start_frame)?;
+        self.frames.get(i as usize)
+    }
+}
+
+/// Records the match into a file while it is present
+#[derive(Resource)]
+pub struct ReplayRecorder {
+    path: PathBuf,
+    file: BufWriter<File>,
+    /// The next frame to write, None until the header is written
+    next_frame: Option<u64>,
+    /// Events waiting until their frame is written
+    events: Vec<(u64, ReplayEvent)>,
+    flush_timer: Timer,
+}
+
+impl ReplayRecorder {
+    pub fn create(path: &Path) -> Result<ReplayRecorder, String> {
+        if let Some(dir) = path.parent() {
+            let _ = std::fs::create_dir_all(dir);
+        }
+        let file = File::create(path).map_err(|e| format!("could not create replay file {}: {e}", path.display()))?;
+        Ok(ReplayRecorder {
+            path: path.to_path_buf(),
+            file: BufWriter::new(file),
+            next_frame: None,
+            events: Vec::new(),
+            flush_timer: Timer::from_seconds(FLUSH_INTERVAL, TimerMode::Repeating),
+        })
+    }
+
+    /// Remembers a change which is not driven by Inputs, it happened in this frame
+    pub fn event(&mut self, frame: u64, event: ReplayEvent) {
+        self.events.push((frame, event));
+    }
+
+    fn write_line(&mut self, value: &impl Serialize) {
+        let result = ron::to_string(value).map_err(|e| e.to_string())
+            .and_then(|line| writeln!(self.file, "{line}").map_err(|e| e.to_string()));
+        if let Err(e) = result {
+            warn!("could not write replay file {}: {e}", self.path.display());
+        }
+    }
+}
+
+/// Players and their names, the roster is recorded only when these change and not because of new pings
+fn roster_key(roster: &PlayerRoster) -> Vec<(u64, String, bool)> {
+    let mut key: Vec<_> = roster.0.iter().map(|(player, info)| (player.0, info.name.clone(), info.spectator)).collect();
+    key.sort();
+    key
+}
+
+/// Writes frames which became settled
+pub fn record(
+    mut recorder: ResMut<ReplayRecorder>,
+    query: StateQuery,
+    inputs: Res<Rollback<Inputs>>,
+    hits: Res<Rollback<Hits>>,
+    frames: Res<Rollback<Frame>>,
+    last_frame: Res<LastFrame>,
+    map: Res<Map>,
+    roster: Res<PlayerRoster>,
+    update_timer: Res<crate::gamestate::UpdateTimer>,
+    time: Res<Time<Real>>,
+    mut last_roster: Local<Vec<(u64, String, bool)>>,
+) {
+    let recorder = recorder.as_mut();
+    let Some(settled) = last_frame.0.checked_sub(SETTLED_FRAMES) else{return};
+
+    let next_frame = match recorder.next_frame {
+        Some(next_frame) => {
+            let key = roster_key(&roster);
+            if key != *last_roster {
+                *last_roster = key;
+                recorder.event(last_frame.0, ReplayEvent::Roster(roster.clone()));
+            }
+            if map.is_changed() {
+                recorder.event(last_frame.0, ReplayEvent::Map(map.clone()));
+            }
+            next_frame
+        },
+        None => {
+            //a Client which just connected does not have the older frames
+            let index = index::<LEN>(settled);
+            if frames[index].0 != settled {
+                return
+            }
+            let mut states = collect_states(&query, index);
+            states.retain(|_, state| state.4.0);
+            let header = ReplayHeader {
+                version: PROTOCOL_VERSION,
+                map: map.clone(),
+                roster: roster.clone(),
+                frame_0_time: update_timer.frame_0_time,
+                start_frame: settled,
+                states,
+            };
+            recorder.write_line(&header);
+            *last_roster = roster_key(&roster);
+            println!("recording replay into {} from frame {settled}", recorder.path.display());
+            settled
+        },
+    };
+
+    for frame in next_frame..=settled {
+        let index = index::<LEN>(frame);
+        let (inputs, hits) = if frames[index].0 == frame {
+            (inputs.0[index].clone(), hits.0[index].clone())
+        }else{
+            warn!("replay frame {frame} is not stored anymore, recording it without Inputs");
+            (Inputs::default(), Hits::default())
+        };
+        //events from before the recording started belong to the first frame
+        let (events, later) = std::mem::take(&mut recorder.events).into_iter().partition(|(f, _)| *f <= frame);
+        recorder.events = later;
+        let events = events.into_iter().map(|(_, event)| event).collect();
+        recorder.write_line(&ReplayFrame { inputs, hits, events });
+    }
+    recorder.next_frame = Some(settled + 1);
+
+    if recorder.flush_timer.tick(time.delta()).just_finished() {
+        if let Err(e) = recorder.file.flush() {
+            warn!("could not write replay file {}: {e}", recorder.path.display());
+        }
+    }
+}
+
+/// Stops recording when the game ends, the rest of the file gets written
+pub fn stop_recording(mut commands: Commands) {
+    commands.remove_resource::<ReplayRecorder>();
+}
+
+/// Path to a replay file which should be played
+#[derive(Resource, Clone, Debug)]
+pub struct ReplayFile(pub PathBuf);
+
+/// Loads the [`ReplayFile`] and starts playing it, goes back to the main menu when the file is broken.
+/// The States in the header are stored at full precision, so the first frame is the same as when it was recorded.
+pub fn start_playback(
+    mut commands: Commands,
+    file: Res<ReplayFile>,
+    assets: Res<crate::map::asteroid::AsteroidAssets>,
+    mut state: ResMut<NextState<crate::gamestate::GameState>>,
+) {
+    let replay = Replay::load(&file.0).and_then(|replay| match replay.header.map.validate(&assets) {
+        Ok(_) => Ok(replay),
+        Err(e) => Err(format!("replay file {} contains {e}", file.0.display())),
+    });
+    match replay {
+        Ok(replay) => {
+            println!("loaded replay {} with frames {}..{}", file.0.display(), replay.header.start_frame, replay.end_frame());
+            commands.insert_resource(ReplayPlayback::new(replay));
+            state.set(crate::gamestate::GameState::Running);
+        },
+        Err(e) => {
+            error!("{e}");
+            //shown in the main menu
+            commands.insert_resource(crate::networking::ConnectionError(e));
+            state.set(crate::gamestate::GameState::MainMenu);
+        },
+    }
+}
+
+/// Playback of a loaded [`Replay`]
+#[derive(Resource)]
+pub struct ReplayPlayback {
+    pub replay: Replay,
+    pub paused: bool,
+    /// 1 is real time
+    pub speed: f32,
+    /// Frame the playback should jump to
+    pub seek: Option<u64>,
+    /// The frame which should be displayed now, fractional so slow speeds work
+    playhead: f64,
+    /// Everything has to be reset to the first frame
+    rewind: bool,
+    /// The States of the first frame are waiting to be spawned
+    spawn: bool,
+    /// Events of this and later frames were not yet applied
+    next_events: u64,
+}
+
+impl ReplayPlayback {
+    pub fn new(replay: Replay) -> Self {
+        let start = replay.header.start_frame;
+        Self {
+            replay,
+            paused: false,
+            speed: 1.0,
+            seek: None,
+            playhead: start as f64,
+            rewind: true,
+            spawn: false,
+            next_events: start,
+        }
+    }
+
+    pub fn playhead(&self) -> u64 {
+        self.playhead as u64
+    }
+
+    /// Seeking or fast forwarding did not yet catch up
+    pub fn is_catching_up(&self, last_frame: LastFrame) -> bool {
+        last_frame.0 + 1 < self.playhead()
+    }
+}
+
+pub fn is_replaying(playback: Option<Res<ReplayPlayback>>) -> bool {
+    playback.is_some()
+}
+
+/// Everything the playback needs to move the game back to the first frame
+#[derive(SystemParam)]
+pub struct Rewind<'w, 's> {
+    commands: Commands<'w, 's>,
+    rollback_map: Res<'w, RollbackMap>,
+    current_frame: ResMut<'w, Frame>,
+    last_frame: ResMut<'w, LastFrame>,
+    wanted: ResMut<'w, WantedFrame>,
+    frames: ResMut<'w, Rollback<Frame>>,
+    inputs: ResMut<'w, Rollback<Inputs>>,
+    hits: ResMut<'w, Rollback<Hits>>,
+    state_events: EventWriter<'w, UpdateStateEvent<State>>,
+}
+
+impl Rewind<'_, '_> {
+    fn rewind(&mut self, playback: &mut ReplayPlayback) {
+        let header = &playback.replay.header;
+        let start = header.start_frame;
+
+        for &entity in self.rollback_map.0.values() {
+            self.commands.entity(entity).despawn_recursive();
+        }
+        self.commands.insert_resource(header.map.clone());
+        self.commands.insert_resource(header.roster.clone());
+
+        self.current_frame.0 = start;
+        self.last_frame.0 = start;
+        self.wanted.0 = start;
+        let index = index::<LEN>(start);
+        self.frames[index].0 = start;
+        self.inputs.0[index] = playback.replay.frame(start).map(|frame| frame.inputs.clone()).unwrap_or_default();
+        self.hits.0[index] = playback.replay.frame(start).map(|frame| frame.hits.clone()).unwrap_or_default();
+
+        playback.next_events = start;
+        playback.spawn = true;
+    }
+
+    fn spawn(&mut self, playback: &ReplayPlayback) {
+        let frame = Frame(playback.replay.header.start_frame);
+        self.state_events.send_batch(playback.replay.header.states.iter().map(|(&id, state)| UpdateStateEvent { frame, id, state: state.clone(), spawn: true }));
+    }
+}
+
+/// Moves the playback, replaces `update_frame` which follows the Server clock
+pub fn advance(
+    mut playback: ResMut<ReplayPlayback>,
+    mut rewind: Rewind,
+    time: Res<Time<Real>>,
+) {
+    let playback = playback.as_mut();
+    let start = playback.replay.header.start_frame;
+    let end = playback.replay.end_frame();
+
+    if let Some(seek) = playback.seek.take() {
+        let seek = seek.clamp(start, end);
+        //older frames are not stored, simulate again from the start
+        playback.rewind |= seek < rewind.last_frame.0;
+        playback.playhead = seek as f64;
+    }
+    if playback.rewind {
+        playback.rewind = false;
+        rewind.rewind(playback);
+        return
+    }
+    //the old entities are despawned now
+    if playback.spawn {
+        playback.spawn = false;
+        rewind.spawn(playback);
+        return
+    }
+
+    if !playback.paused {
+        playback.playhead += time.delta_secs_f64() * playback.speed as f64 * 1000.0 / crate::gravity::PHYSICS_TIMESTEP_MS as f64;
+    }
+    if playback.playhead >= end as f64 {
+        playback.playhead = end as f64;
+        playback.paused = true;
+    }
+
+    let wanted = playback.playhead().min(rewind.last_frame.0 + MAX_FRAMES_PER_UPDATE);
+    if wanted > rewind.wanted.0 {
+        rewind.wanted.0 = wanted;
+    }
+}
+
+/// Puts the recorded Inputs and Hits into a newly created frame, runs in [`RollbackSave`]
+pub fn store_inputs(
+    playback: Res<ReplayPlayback>,
+    frame: Res<Frame>,
+    mut inputs: ResMut<Rollback<Inputs>>,
+    mut hits: ResMut<Rollback<Hits>>,
+) {
+    if let Some(recorded) = playback.replay.frame(frame.0) {
+        inputs.0[index::<LEN>(frame.0)] = recorded.inputs.clone();
+        hits.0[index::<LEN>(frame.0)] = recorded.hits.clone();
+    }
+}
+
+/// Applies the recorded events of frames which were simulated
+pub fn apply_events(
+    mut commands: Commands,
+    mut playback: ResMut<ReplayPlayback>,
+    last_frame: Res<LastFrame>,
+) {
+    if playback.spawn {
+        return
+    }
+    for frame in playback.next_events..=last_frame.0 {
+        let Some(recorded) = playback.replay.frame(frame) else{break};
+        for event in &recorded.events {
+            match event {
+                ReplayEvent::Despawn(player) => {
+                    let (player, frame) = (*player, Frame(frame));
+                    commands.queue(move |world: &mut World| { crate::player::despawn_player(world, player, frame); });
+                },
+                ReplayEvent::Map(map) => commands.insert_resource(map.clone()),
+                ReplayEvent::Roster(roster) => commands.insert_resource(roster.clone()),
+            }
+        }
+    }
+    playback.next_events = playback.next_events.max(last_frame.0 + 1);
+}